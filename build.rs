@@ -0,0 +1,9 @@
+// Генерация gRPC сервиса из proto/orders.proto, protoc берется из protoc-bin-vendored
+// чтобы не требовать его установку в системе
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/orders.proto"], &["proto"])?;
+    Ok(())
+}
@@ -1,31 +1,40 @@
+-- tenant_id - арендатор (маркетплейс), у каждого свои заказы и покупатели (src/tenant.rs)
 CREATE TABLE customers (
-    customer_id VARCHAR PRIMARY KEY,
+    tenant_id VARCHAR NOT NULL,
+    customer_id VARCHAR NOT NULL,
     name VARCHAR,
     phone VARCHAR,
     zip VARCHAR,
     city VARCHAR,
     address VARCHAR,
     region VARCHAR,
-    email VARCHAR
+    email VARCHAR,
+    PRIMARY KEY (tenant_id, customer_id)
 );
 
+-- order_uid, transaction и chrt_id уникальны в пределах арендатора: у другого арендатора могут быть такие же,
+-- и конфликт с его строкой не должен выдавать что она есть
 CREATE TABLE orders (
-    order_uid VARCHAR PRIMARY KEY,
+    order_uid VARCHAR NOT NULL,
+    tenant_id VARCHAR NOT NULL,
     track_number VARCHAR,
     entry VARCHAR,
     locale VARCHAR,
     internal_signature VARCHAR,
-    customer_id VARCHAR REFERENCES customers(customer_id),
+    customer_id VARCHAR,
     delivery_service VARCHAR,
     shardkey VARCHAR,
     sm_id INT,
     date_created TIMESTAMP,
-    oof_shard VARCHAR
+    oof_shard VARCHAR,
+    PRIMARY KEY (tenant_id, order_uid),
+    FOREIGN KEY (tenant_id, customer_id) REFERENCES customers (tenant_id, customer_id)
 );
 
 CREATE TABLE payment (
-    transaction VARCHAR PRIMARY KEY,
-    order_uid VARCHAR UNIQUE REFERENCES orders(order_uid),
+    transaction VARCHAR NOT NULL,
+    tenant_id VARCHAR NOT NULL,
+    order_uid VARCHAR,
     request_id VARCHAR,
     currency VARCHAR,
     provider VARCHAR,
@@ -34,12 +43,16 @@ CREATE TABLE payment (
     bank VARCHAR,
     delivery_cost INT,
     goods_total INT,
-    custom_fee INT
+    custom_fee INT,
+    PRIMARY KEY (tenant_id, transaction),
+    UNIQUE (tenant_id, order_uid),
+    FOREIGN KEY (tenant_id, order_uid) REFERENCES orders (tenant_id, order_uid)
 );
 
 CREATE TABLE items (
-    chrt_id BIGINT PRIMARY KEY,
-    order_uid VARCHAR REFERENCES orders(order_uid),
+    chrt_id BIGINT NOT NULL,
+    tenant_id VARCHAR NOT NULL,
+    order_uid VARCHAR,
     track_number VARCHAR,
     price INT,
     rid VARCHAR,
@@ -49,5 +62,169 @@ CREATE TABLE items (
     total_price INT,
     nm_id BIGINT,
     brand VARCHAR,
-    status INT
+    status INT,
+    PRIMARY KEY (tenant_id, chrt_id),
+    FOREIGN KEY (tenant_id, order_uid) REFERENCES orders (tenant_id, order_uid)
+);
+
+-- снимок доставки на момент заказа, заполняется при CUSTOMER_UPSERT_POLICY=snapshot
+CREATE TABLE order_delivery (
+    order_uid VARCHAR NOT NULL,
+    tenant_id VARCHAR NOT NULL,
+    name VARCHAR,
+    phone VARCHAR,
+    zip VARCHAR,
+    city VARCHAR,
+    address VARCHAR,
+    region VARCHAR,
+    email VARCHAR,
+    PRIMARY KEY (tenant_id, order_uid),
+    FOREIGN KEY (tenant_id, order_uid) REFERENCES orders (tenant_id, order_uid)
+);
+
+-- поиск заказов по трек номеру заказа и товара
+CREATE INDEX idx_orders_track_number ON orders (tenant_id, track_number);
+CREATE INDEX idx_items_track_number ON items (tenant_id, track_number);
+CREATE INDEX idx_items_order_uid ON items (tenant_id, order_uid);
+
+-- подписки партнеров на события по заказам, пустой event_types - все события
+CREATE TABLE webhooks (
+    id BIGSERIAL PRIMARY KEY,
+    -- события только по заказам этого арендатора
+    tenant_id VARCHAR NOT NULL,
+    url VARCHAR NOT NULL,
+    event_types VARCHAR[] NOT NULL DEFAULT '{}',
+    secret VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+-- журнал доставок, по строке на каждое событие для каждой подписки
+CREATE TABLE webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id BIGINT NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    event_id BIGINT NOT NULL,
+    event_type VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    -- pending | delivered | failed
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_status_code INT,
+    last_error VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMP
+);
+
+CREATE INDEX idx_webhook_deliveries_pending ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX idx_webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);
+-- одно событие попадает в журнал подписки один раз, даже если relay отправит его повторно
+CREATE UNIQUE INDEX idx_webhook_deliveries_event ON webhook_deliveries (webhook_id, event_id);
+
+-- события по заказам, пишутся в той же транзакции что и изменение заказа,
+-- relay (src/outbox.rs) отправляет их дальше и проставляет published_at
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id VARCHAR NOT NULL,
+    order_uid VARCHAR NOT NULL,
+    -- created | item_added | item_status_changed
+    event_type VARCHAR NOT NULL,
+    chrt_id BIGINT,
+    status INT,
+    -- заказ целиком на момент события, relay отправляет его как есть
+    payload JSONB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    published_at TIMESTAMP
+);
+
+CREATE INDEX idx_outbox_unpublished ON outbox (id) WHERE published_at IS NULL;
+-- relay проверяет нет ли у заказа более раннего неотправленного события
+CREATE INDEX idx_outbox_unpublished_order ON outbox (tenant_id, order_uid, id) WHERE published_at IS NULL;
+
+-- будим relay сразу после комита, без этого он найдет новые события только при следующем опросе
+CREATE FUNCTION notify_outbox() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('outbox', '');
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER outbox_notify AFTER INSERT ON outbox
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_outbox();
+
+-- изменения заказов для всех экземпляров сервиса (src/order_changes.rs): по ним сбрасывается кэш заказов.
+-- Триггеры на весь запрос, а не на строку: вставка пачки с тысячами товаров дает по одному уведомлению
+-- на заказ, а не на каждую строку. Транзакционная таблица у всех триггеров называется changed_rows,
+-- у одного триггера в postgres она может быть только для одного вида изменений, поэтому их по три на таблицу
+CREATE FUNCTION notify_order_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_TABLE_NAME = 'customers' THEN
+        PERFORM pg_notify('order_changes', json_build_object(
+            'table', TG_TABLE_NAME, 'op', TG_OP, 'customer_id', changed.customer_id)::text)
+        FROM (SELECT DISTINCT customer_id FROM changed_rows) AS changed;
+    ELSE
+        PERFORM pg_notify('order_changes', json_build_object(
+            'table', TG_TABLE_NAME, 'op', TG_OP, 'order_uid', changed.order_uid)::text)
+        FROM (SELECT DISTINCT order_uid FROM changed_rows) AS changed;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER orders_notify_insert AFTER INSERT ON orders REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER orders_notify_update AFTER UPDATE ON orders REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER orders_notify_delete AFTER DELETE ON orders REFERENCING OLD TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER payment_notify_insert AFTER INSERT ON payment REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER payment_notify_update AFTER UPDATE ON payment REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER payment_notify_delete AFTER DELETE ON payment REFERENCING OLD TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER items_notify_insert AFTER INSERT ON items REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER items_notify_update AFTER UPDATE ON items REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER items_notify_delete AFTER DELETE ON items REFERENCING OLD TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER order_delivery_notify_insert AFTER INSERT ON order_delivery REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER order_delivery_notify_update AFTER UPDATE ON order_delivery REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER order_delivery_notify_delete AFTER DELETE ON order_delivery REFERENCING OLD TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+-- доставка покупателя показывается во всех его заказах
+CREATE TRIGGER customers_notify_update AFTER UPDATE ON customers REFERENCING NEW TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+CREATE TRIGGER customers_notify_delete AFTER DELETE ON customers REFERENCING OLD TABLE AS changed_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_order_change();
+
+-- ответы на POST /order с заголовком Idempotency-Key (src/idempotency.rs), хранятся сутки
+CREATE TABLE idempotency_keys (
+    tenant_id VARCHAR NOT NULL,
+    key VARCHAR NOT NULL,
+    -- sha256 от Content-Type и тела запроса
+    request_hash VARCHAR NOT NULL,
+    -- NULL пока первый запрос еще выполняется
+    status_code INT,
+    content_type VARCHAR,
+    response_body BYTEA,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (tenant_id, key)
+);
+
+CREATE INDEX idx_idempotency_keys_created ON idempotency_keys (created_at);
+
+-- API ключи (src/api_keys.rs), хранится только sha256 от ключа
+CREATE TABLE api_keys (
+    id BIGSERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    key_hash VARCHAR NOT NULL UNIQUE,
+    -- orders:read | orders:write | admin
+    scopes VARCHAR[] NOT NULL,
+    -- NULL - ключ платформы, арендатор берется из X-Tenant-ID
+    tenant_id VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMP
 );
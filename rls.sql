@@ -0,0 +1,25 @@
+-- Row level security для таблиц заказов, дополнительная защита поверх фильтров по tenant_id в запросах.
+-- Применяется после init.sql вместе с TENANT_RLS=on у сервиса: перед запросами сервис ставит app.tenant_id,
+-- без него строки не видны вовсе. '*' - все арендаторы, так читает outbox relay.
+-- Суперпользователь и роли с BYPASSRLS политики не проверяют, сервис должен ходить в базу обычной ролью
+CREATE FUNCTION current_tenant_allows(tenant VARCHAR) RETURNS boolean AS $$
+    SELECT tenant = current_setting('app.tenant_id', true) OR current_setting('app.tenant_id', true) = '*'
+$$ LANGUAGE sql STABLE;
+
+CREATE POLICY tenant_isolation ON customers USING (current_tenant_allows(tenant_id));
+CREATE POLICY tenant_isolation ON orders USING (current_tenant_allows(tenant_id));
+CREATE POLICY tenant_isolation ON payment USING (current_tenant_allows(tenant_id));
+CREATE POLICY tenant_isolation ON items USING (current_tenant_allows(tenant_id));
+CREATE POLICY tenant_isolation ON order_delivery USING (current_tenant_allows(tenant_id));
+
+-- FORCE - политики действуют и на владельца таблиц
+ALTER TABLE customers ENABLE ROW LEVEL SECURITY;
+ALTER TABLE customers FORCE ROW LEVEL SECURITY;
+ALTER TABLE orders ENABLE ROW LEVEL SECURITY;
+ALTER TABLE orders FORCE ROW LEVEL SECURITY;
+ALTER TABLE payment ENABLE ROW LEVEL SECURITY;
+ALTER TABLE payment FORCE ROW LEVEL SECURITY;
+ALTER TABLE items ENABLE ROW LEVEL SECURITY;
+ALTER TABLE items FORCE ROW LEVEL SECURITY;
+ALTER TABLE order_delivery ENABLE ROW LEVEL SECURITY;
+ALTER TABLE order_delivery FORCE ROW LEVEL SECURITY;
@@ -0,0 +1,202 @@
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+use tokio_postgres::{Client, Row};
+
+use crate::{
+    cli::{KeysArgs, KeysCommand},
+    get_db,
+    order_errors::OrderError,
+    order_impl::db_timeout,
+    tenant::Tenant,
+};
+
+// По префиксу ключ легко узнать в логах и конфигах, сам ключ в базе не хранится
+const KEY_PREFIX: &str = "ak_";
+
+// Что разрешено ключу. admin разрешает все
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    OrdersRead,
+    OrdersWrite,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::OrdersRead => "orders:read",
+            Scope::OrdersWrite => "orders:write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "orders:read" => Ok(Scope::OrdersRead),
+            "orders:write" => Ok(Scope::OrdersWrite),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("Unknown scope: {other}, expected orders:read, orders:write or admin")),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Ключ из таблицы api_keys, без самого секрета
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // None - ключ платформы, работает с любым арендатором
+    pub tenant_id: Option<String>,
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+}
+
+impl ApiKey {
+    fn from_row(row: &Row) -> Self {
+        let scopes: Vec<String> = row.get("scopes");
+        ApiKey {
+            id: row.get("id"),
+            name: row.get("name"),
+            // неизвестный scope в базе (например после отката версии) просто ничего не разрешает
+            scopes: scopes.iter().filter_map(|scope| scope.parse().ok()).collect(),
+            tenant_id: row.get("tenant_id"),
+            created_at: row.get("created_at"),
+            revoked_at: row.get("revoked_at"),
+        }
+    }
+
+    // Возвращает ключ целиком, он показывается только один раз
+    pub async fn create(
+        client: &Client,
+        name: &str,
+        scopes: &[Scope],
+        tenant: Option<&Tenant>,
+    ) -> Result<(ApiKey, String), OrderError> {
+        let secret: String = rand::thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+        let key = format!("{KEY_PREFIX}{secret}");
+        let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+        let row = db_timeout(
+            "create api key",
+            client.query_one(
+                "INSERT INTO api_keys (name, key_hash, scopes, tenant_id) VALUES ($1, $2, $3, $4)
+                RETURNING id, name, scopes, tenant_id, TO_CHAR(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                    TO_CHAR(revoked_at, 'YYYY-MM-DD HH24:MI:SS') AS revoked_at",
+                &[&name, &hash_key(&key), &scopes, &tenant.map(Tenant::as_str)],
+            ),
+        )
+        .await?;
+        Ok((ApiKey::from_row(&row), key))
+    }
+
+    // Действующий ключ по его значению из запроса
+    pub async fn find_active(client: &Client, key: &str) -> Result<Option<ApiKey>, OrderError> {
+        let row = db_timeout(
+            "query api key",
+            client.query_opt(
+                "SELECT id, name, scopes, tenant_id, TO_CHAR(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                    TO_CHAR(revoked_at, 'YYYY-MM-DD HH24:MI:SS') AS revoked_at
+                FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL",
+                &[&hash_key(key)],
+            ),
+        )
+        .await?;
+        Ok(row.as_ref().map(ApiKey::from_row))
+    }
+
+    pub async fn list(client: &Client) -> Result<Vec<ApiKey>, OrderError> {
+        let rows = db_timeout(
+            "list api keys",
+            client.query(
+                "SELECT id, name, scopes, tenant_id, TO_CHAR(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                    TO_CHAR(revoked_at, 'YYYY-MM-DD HH24:MI:SS') AS revoked_at
+                FROM api_keys ORDER BY id",
+                &[],
+            ),
+        )
+        .await?;
+        Ok(rows.iter().map(ApiKey::from_row).collect())
+    }
+
+    pub async fn revoke(client: &Client, id: i64) -> Result<(), OrderError> {
+        let revoked = db_timeout(
+            "revoke api key",
+            client.execute(
+                "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+                &[&id],
+            ),
+        )
+        .await?;
+        if revoked == 0 {
+            return Err(OrderError::NotFound {
+                msg: format!("Active api key {id} not found"),
+                field: "id".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+// Ключи случайные и длинные, поэтому хватает sha256 без соли: по хешу из базы ключ не подобрать
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// Подкоманда keys: create, revoke, list
+pub async fn run(args: KeysArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let client = get_db().await?;
+    match args.command {
+        KeysCommand::Create { name, scopes, tenant } => {
+            if scopes.is_empty() {
+                return Err("At least one scope is required".into());
+            }
+            let tenant = tenant.as_deref().map(Tenant::new).transpose()?;
+            let (api_key, key) = ApiKey::create(&client, &name, &scopes, tenant.as_ref()).await?;
+            println!(
+                "Created api key {} ({}) with scopes {} for tenant {}",
+                api_key.id,
+                api_key.name,
+                scopes_list(&api_key),
+                api_key.tenant_id.as_deref().unwrap_or("any")
+            );
+            println!("{key}");
+            eprintln!("Save the key now, it is stored hashed and can't be shown again");
+        }
+        KeysCommand::Revoke { id } => {
+            ApiKey::revoke(&client, id).await?;
+            println!("Revoked api key {id}");
+        }
+        KeysCommand::List => {
+            for api_key in ApiKey::list(&client).await? {
+                let state = api_key
+                    .revoked_at
+                    .as_ref()
+                    .map_or_else(|| "active".to_string(), |at| format!("revoked {at}"));
+                println!(
+                    "{}\t{}\t{}\ttenant {}\tcreated {}\t{state}",
+                    api_key.id,
+                    api_key.name,
+                    scopes_list(&api_key),
+                    api_key.tenant_id.as_deref().unwrap_or("any"),
+                    api_key.created_at
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scopes_list(api_key: &ApiKey) -> String {
+    api_key.scopes.iter().map(|scope| scope.as_str()).collect::<Vec<_>>().join(",")
+}
@@ -0,0 +1,288 @@
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use log::debug;
+use std::sync::Arc;
+
+use crate::{
+    api_keys::{ApiKey, Scope},
+    config,
+    db::Db,
+    jwt::{Claims, JwtKeys},
+    models::Order,
+    order_errors::OrderError,
+};
+
+const API_KEY_HEADER: &str = "x-api-key";
+// Браузер не может выставить заголовки для WebSocket, учетные данные для /ws можно передать подпротоколом:
+// new WebSocket(url, ["orders", "bearer." + token]) или ["orders", "apikey." + key]
+pub const BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+const API_KEY_PROTOCOL_PREFIX: &str = "apikey.";
+
+// Все что нужно для проверки учетных данных, часть AppState
+#[derive(Clone)]
+pub struct Auth {
+    db: Db,
+    // None - bearer токены не настроены
+    jwt: Option<Arc<JwtKeys>>,
+}
+
+impl Auth {
+    pub fn new(db: Db, jwt: Option<JwtKeys>) -> Self {
+        Self { db, jwt: jwt.map(Arc::new) }
+    }
+
+    // API ключ из X-API-Key или JWT из Authorization: Bearer (HTTP заголовки и метаданные gRPC)
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, OrderError> {
+        let key = headers.get(API_KEY_HEADER).and_then(|value| value.to_str().ok());
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        self.check_credentials(key, token).await
+    }
+
+    // То же для апгрейда /ws, без заголовков учетные данные берутся из подпротоколов
+    pub async fn authenticate_ws(&self, headers: &HeaderMap) -> Result<Principal, OrderError> {
+        if headers.contains_key(API_KEY_HEADER) || headers.contains_key(header::AUTHORIZATION) {
+            return self.authenticate(headers).await;
+        }
+        let key = protocol_value(headers, API_KEY_PROTOCOL_PREFIX);
+        let token = protocol_value(headers, BEARER_PROTOCOL_PREFIX);
+        self.check_credentials(key, token).await
+    }
+
+    async fn check_credentials(&self, key: Option<&str>, token: Option<&str>) -> Result<Principal, OrderError> {
+        if !config::api_auth() {
+            return Ok(Principal::anonymous());
+        }
+        if let Some(key) = key {
+            let api_key = {
+                let client = self.db.lock().await;
+                ApiKey::find_active(&client, key.trim()).await?
+            };
+            return match api_key {
+                Some(api_key) => Ok(Principal::from_api_key(api_key)),
+                None => Err(OrderError::Unauthorized("Invalid or revoked API key".to_string())),
+            };
+        }
+        match (token, &self.jwt) {
+            (Some(token), Some(jwt)) => Ok(Principal::from_claims(jwt.verify(token.trim())?)),
+            (Some(_), None) => Err(OrderError::Unauthorized("Bearer tokens are not accepted".to_string())),
+            (None, _) => Err(OrderError::Unauthorized("Missing API key or bearer token".to_string())),
+        }
+    }
+}
+
+// Кто делает запрос: владелец API ключа или пользователь из JWT
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    // Неизменный id клиента для лимитов запросов: id ключа (имена ключей могут повторяться) или iss и sub токена
+    pub client_key: String,
+    pub scopes: Vec<Scope>,
+    // Some - это покупатель, ему видны только его ордера
+    pub customer_id: Option<String>,
+    pub tenant_id: Option<String>,
+}
+
+impl Principal {
+    // API_AUTH=off: можно все
+    fn anonymous() -> Self {
+        Principal {
+            subject: "anonymous".to_string(),
+            client_key: "anonymous".to_string(),
+            scopes: vec![Scope::Admin],
+            customer_id: None,
+            tenant_id: None,
+        }
+    }
+
+    fn from_api_key(api_key: ApiKey) -> Self {
+        Principal {
+            subject: format!("api-key:{}", api_key.name),
+            client_key: format!("api-key-id:{}", api_key.id),
+            scopes: api_key.scopes,
+            customer_id: None,
+            tenant_id: api_key.tenant_id,
+        }
+    }
+
+    // Роли из токена: admin - все, operator - чтение и запись ордеров,
+    // customer - чтение только своих ордеров (нужен клейм customer_id). Неизвестные роли ничего не дают
+    fn from_claims(claims: Claims) -> Self {
+        let mut scopes = Vec::new();
+        let mut customer_id = None;
+        for role in &claims.roles {
+            match role.as_str() {
+                "admin" => scopes.push(Scope::Admin),
+                "operator" => scopes.extend([Scope::OrdersRead, Scope::OrdersWrite]),
+                _ => {}
+            }
+        }
+        // сотрудник с ролью customer все равно видит все
+        if scopes.is_empty() && claims.roles.iter().any(|role| role == "customer") {
+            if let Some(id) = claims.customer_id {
+                scopes.push(Scope::OrdersRead);
+                customer_id = Some(id);
+            }
+        }
+        Principal {
+            client_key: format!("jwt:{}:{}", claims.iss.as_deref().unwrap_or_default(), claims.sub),
+            subject: claims.sub,
+            scopes,
+            customer_id,
+            tenant_id: claims.tenant_id,
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| *granted == scope || *granted == Scope::Admin)
+    }
+
+    // Покупатель видит только свои ордера, остальные (с правом чтения) - любые
+    pub fn can_read(&self, order: &Order) -> bool {
+        self.customer_id.as_ref().is_none_or(|customer_id| *customer_id == order.customer_id)
+    }
+}
+
+// Значение подпротокола с префиксом из Sec-WebSocket-Protocol
+pub fn protocol_value<'a>(headers: &'a HeaderMap, prefix: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(prefix))
+}
+
+// Хендлер объявляет Principal аргументом. За middleware берется уже проверенный,
+// на маршруте без middleware учетные данные проверяются здесь же
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+where
+    Auth: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = OrderError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(principal) = parts.extensions.get::<Principal>() {
+            return Ok(principal.clone());
+        }
+        let principal = Auth::from_ref(state).authenticate(&parts.headers).await?;
+        parts.extensions.insert(principal.clone());
+        Ok(principal)
+    }
+}
+
+// Middleware для групп маршрутов в main.rs
+// Чтение ордеров, покупателю тоже можно: хендлеры сами оставляют ему только его ордера через Principal
+pub async fn orders_read(State(auth): State<Auth>, req: Request, next: Next) -> Result<Response, OrderError> {
+    require(&auth, Scope::OrdersRead, false, req, next).await
+}
+
+// Чтение ордеров без разбора по покупателю (выгрузка, поток событий, поиск, GraphQL) - покупателю нельзя
+pub async fn orders_read_all(State(auth): State<Auth>, req: Request, next: Next) -> Result<Response, OrderError> {
+    require(&auth, Scope::OrdersRead, true, req, next).await
+}
+
+pub async fn orders_write(State(auth): State<Auth>, req: Request, next: Next) -> Result<Response, OrderError> {
+    require(&auth, Scope::OrdersWrite, true, req, next).await
+}
+
+pub async fn admin(State(auth): State<Auth>, req: Request, next: Next) -> Result<Response, OrderError> {
+    require(&auth, Scope::Admin, true, req, next).await
+}
+
+// Нет учетных данных или они неверные - 401, не хватает прав - 403.
+// Проверенный Principal кладется в extensions запроса для хендлеров
+async fn require(
+    auth: &Auth,
+    scope: Scope,
+    staff_only: bool,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, OrderError> {
+    let principal = auth.authenticate(req.headers()).await?;
+    check(&principal, scope, staff_only)?;
+    req.extensions_mut().insert(principal);
+    Ok(next.run(req).await)
+}
+
+// Хватает ли прав, общая для HTTP маршрутов, gRPC и /ws
+pub fn check(principal: &Principal, scope: Scope, staff_only: bool) -> Result<(), OrderError> {
+    debug!(
+        "Request from {} (tenant {}) with scopes {:?}",
+        principal.subject,
+        principal.tenant_id.as_deref().unwrap_or("-"),
+        principal.scopes
+    );
+    if !principal.allows(scope) {
+        return Err(OrderError::Forbidden(format!("{} has no {scope} scope", principal.subject)));
+    }
+    if staff_only && principal.customer_id.is_some() {
+        return Err(OrderError::Forbidden("Customers can only read their own orders".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(roles: &[&str], customer_id: Option<&str>) -> Claims {
+        Claims {
+            sub: "u1".to_string(),
+            iss: None,
+            roles: roles.iter().map(ToString::to_string).collect(),
+            tenant_id: None,
+            customer_id: customer_id.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn roles_map_to_scopes() {
+        let operator = Principal::from_claims(claims(&["operator"], None));
+        assert!(operator.allows(Scope::OrdersWrite) && !operator.allows(Scope::Admin));
+        assert!(Principal::from_claims(claims(&["admin"], None)).allows(Scope::OrdersWrite));
+        assert!(Principal::from_claims(claims(&["unknown"], None)).scopes.is_empty());
+    }
+
+    #[test]
+    fn customer_reads_only_own_orders() {
+        let customer = Principal::from_claims(claims(&["customer"], Some("c1")));
+        assert_eq!(customer.customer_id.as_deref(), Some("c1"));
+        assert!(check(&customer, Scope::OrdersRead, false).is_ok());
+        assert!(matches!(check(&customer, Scope::OrdersRead, true), Err(OrderError::Forbidden(_))));
+        assert!(matches!(check(&customer, Scope::OrdersWrite, false), Err(OrderError::Forbidden(_))));
+        // без customer_id в токене роль customer ничего не дает
+        assert!(Principal::from_claims(claims(&["customer"], None)).scopes.is_empty());
+        // сотрудник с ролью customer видит все
+        assert!(Principal::from_claims(claims(&["operator", "customer"], Some("c1"))).customer_id.is_none());
+    }
+
+    #[test]
+    fn client_key_is_stable_per_key_and_token() {
+        let api_key = |id| ApiKey {
+            id,
+            name: "partner".to_string(),
+            scopes: vec![Scope::OrdersRead],
+            tenant_id: None,
+            created_at: String::new(),
+            revoked_at: None,
+        };
+        // ключи с одним именем - разные клиенты
+        assert_eq!(Principal::from_api_key(api_key(1)).client_key, "api-key-id:1");
+        assert_ne!(Principal::from_api_key(api_key(1)).client_key, Principal::from_api_key(api_key(2)).client_key);
+
+        let token = |iss: Option<&str>| Claims { iss: iss.map(str::to_string), ..claims(&["operator"], None) };
+        assert_eq!(Principal::from_claims(token(Some("gateway"))).client_key, "jwt:gateway:u1");
+        assert_ne!(Principal::from_claims(token(Some("gateway"))).client_key, Principal::from_claims(token(Some("other"))).client_key);
+        assert_ne!(Principal::from_claims(token(None)).client_key, Principal::from_api_key(api_key(1)).client_key);
+    }
+}
@@ -0,0 +1,289 @@
+use log::info;
+use std::{
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio_postgres::{Client, Transaction};
+
+use crate::{
+    cli::BenchArgs,
+    config::CustomerPolicy,
+    get_db,
+    models::{Delivery, Item, Order, Payment},
+    order_errors::OrderError,
+    statements::StatementCache,
+    tenant::{self, Tenant},
+};
+
+// Способы вставки которые сравниваю
+#[derive(Debug, Clone, Copy)]
+enum Strategy {
+    // как было раньше: транзакция на заказ, INSERT на каждую строку без подготовленных запросов
+    Legacy,
+    // как сейчас работает POST /order: транзакция на заказ, UNNEST и кэш запросов
+    PerOrder,
+    // как сейчас работает POST /orders/bulk: batch_size заказов в одной транзакции через save_many
+    Batched,
+}
+
+const STRATEGIES: [Strategy; 3] = [Strategy::Legacy, Strategy::PerOrder, Strategy::Batched];
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // pad, а не write_str: в отчете имя выравнивается по ширине
+        f.pad(match self {
+            Strategy::Legacy => "legacy",
+            Strategy::PerOrder => "per_order",
+            Strategy::Batched => "batched",
+        })
+    }
+}
+
+pub async fn run(args: BenchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let tenant = Tenant::from_arg(args.tenant.as_deref())?;
+    let mut client = get_db().await?;
+    tenant::bind(&client, &tenant).await?;
+    let statements = StatementCache::default();
+    let policy = CustomerPolicy::from_env();
+    let run_id = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros();
+
+    info!(
+        "Benchmark: {} orders x {} items per strategy, batch size {}, policy {}, tenant {}",
+        args.orders, args.items, args.batch_size, policy, tenant
+    );
+
+    let mut chrt_offset = 0;
+    for strategy in STRATEGIES {
+        let prefix = format!("bench-{run_id}-{strategy}");
+        let orders = generate_orders(&prefix, args.orders, args.items, run_id, &mut chrt_offset);
+
+        let started = Instant::now();
+        match strategy {
+            Strategy::Legacy => {
+                for order in &orders {
+                    let tx = client.transaction().await?;
+                    legacy_save(&tx, &tenant, order, policy).await?;
+                    tx.commit().await?;
+                }
+            }
+            Strategy::PerOrder => {
+                for order in &orders {
+                    let tx = client.transaction().await?;
+                    order.save(&tx, &statements, &tenant, policy).await?;
+                    tx.commit().await?;
+                }
+            }
+            Strategy::Batched => {
+                for chunk in orders.chunks(args.batch_size.max(1)) {
+                    let tx = client.transaction().await?;
+                    Order::save_many(&tx, &statements, &tenant, chunk, policy).await?;
+                    tx.commit().await?;
+                }
+            }
+        }
+        report(strategy, orders.len(), started.elapsed());
+
+        if !args.keep {
+            cleanup(&client, &tenant, &prefix).await?;
+        }
+    }
+    Ok(())
+}
+
+fn report(strategy: Strategy, orders: usize, elapsed: Duration) {
+    #[allow(clippy::cast_precision_loss)]
+    let per_sec = orders as f64 / elapsed.as_secs_f64();
+    println!("{strategy:<10} {orders:>8} orders in {:>8.3}s  {per_sec:>10.1} orders/sec", elapsed.as_secs_f64());
+}
+
+fn generate_orders(prefix: &str, count: usize, items: usize, run_id: u128, chrt_offset: &mut i64) -> Vec<Order> {
+    // chrt_id уникален по всей таблице, поэтому собираю его из времени запуска и счетчика
+    let chrt_base = i64::try_from(run_id % 1_000_000_000_000).unwrap_or_default() * 10_000;
+    (0..count)
+        .map(|n| {
+            let order_uid = format!("{prefix}-{n}");
+            let items = (0..items)
+                .map(|_| {
+                    *chrt_offset += 1;
+                    Item {
+                        chrt_id: chrt_base + *chrt_offset,
+                        track_number: "WBILMTESTTRACK".to_string(),
+                        price: 453,
+                        rid: "ab4219087a764ae0btest".to_string(),
+                        name: "Mascaras".to_string(),
+                        sale: 30,
+                        size: "0".to_string(),
+                        total_price: 317,
+                        nm_id: 2_389_212,
+                        brand: "Vivienne Sabo".to_string(),
+                        status: 202,
+                    }
+                })
+                .collect();
+            Order {
+                order_uid: order_uid.clone(),
+                track_number: "WBILMTESTTRACK".to_string(),
+                entry: "WBIL".to_string(),
+                delivery: Delivery {
+                    name: "Test Testov".to_string(),
+                    phone: "+9720000000".to_string(),
+                    zip: "2639809".to_string(),
+                    city: "Kiryat Mozkin".to_string(),
+                    address: "Ploshad Mira 15".to_string(),
+                    region: "Kraiot".to_string(),
+                    email: "test@gmail.com".to_string(),
+                },
+                payment: Payment {
+                    transaction: order_uid,
+                    request_id: String::new(),
+                    currency: "USD".to_string(),
+                    provider: "wbpay".to_string(),
+                    amount: 1817,
+                    payment_dt: 1_637_907_727,
+                    bank: "alpha".to_string(),
+                    delivery_cost: 1500,
+                    goods_total: 317,
+                    custom_fee: 0,
+                },
+                items,
+                delivery_service: "meest".to_string(),
+                // покупатели повторяются чтобы upsert тоже попадал в замер
+                customer_id: format!("{prefix}-customer-{}", n % 100),
+                shardkey: "9".to_string(),
+                sm_id: 99,
+                date_created: "2021-11-26T06:22:19Z".to_string(),
+                oof_shard: "1".to_string(),
+            }
+        })
+        .collect()
+}
+
+// Старый способ вставки, оставлен только для сравнения в замере.
+// Покупатель пишется по той же политике что и в остальных способах, иначе замер сравнивал бы разную работу
+async fn legacy_save(tx: &Transaction<'_>, tenant: &Tenant, order: &Order, policy: CustomerPolicy) -> Result<(), OrderError> {
+    let tenant = tenant.as_str();
+    tx.execute(
+        legacy_customers_query(policy),
+        &[
+            &order.customer_id,
+            &order.delivery.name,
+            &order.delivery.phone,
+            &order.delivery.zip,
+            &order.delivery.city,
+            &order.delivery.address,
+            &order.delivery.region,
+            &order.delivery.email,
+            &tenant,
+        ],
+    ).await?;
+    tx.execute(
+        "INSERT INTO orders (tenant_id, order_uid, track_number, entry, customer_id, delivery_service, shardkey, sm_id, date_created, oof_shard)
+        VALUES ($10, $1, $2, $3, $4, $5, $6, $7, to_timestamp($8, 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'), $9)",
+        &[
+            &order.order_uid,
+            &order.track_number,
+            &order.entry,
+            &order.customer_id,
+            &order.delivery_service,
+            &order.shardkey,
+            &order.sm_id,
+            &order.date_created,
+            &order.oof_shard,
+            &tenant,
+        ],
+    ).await?;
+    if policy == CustomerPolicy::Snapshot {
+        tx.execute(
+            "INSERT INTO order_delivery (tenant_id, order_uid, name, phone, zip, city, address, region, email)
+            VALUES ($9, $1, $2, $3, $4, $5, $6, $7, $8)",
+            &[
+                &order.order_uid,
+                &order.delivery.name,
+                &order.delivery.phone,
+                &order.delivery.zip,
+                &order.delivery.city,
+                &order.delivery.address,
+                &order.delivery.region,
+                &order.delivery.email,
+                &tenant,
+            ],
+        ).await?;
+    }
+    tx.execute(
+        "INSERT INTO payment (tenant_id, transaction, order_uid, request_id, currency, provider, amount, payment_dt, bank, delivery_cost, goods_total, custom_fee)
+        VALUES ($12, $1, $2, $3, $4, $5, $6, to_timestamp($7::bigint), $8, $9, $10, $11)",
+        &[
+            &order.payment.transaction,
+            &order.order_uid,
+            &order.payment.request_id,
+            &order.payment.currency,
+            &order.payment.provider,
+            &order.payment.amount,
+            &order.payment.payment_dt,
+            &order.payment.bank,
+            &order.payment.delivery_cost,
+            &order.payment.goods_total,
+            &order.payment.custom_fee,
+            &tenant,
+        ],
+    ).await?;
+    for item in &order.items {
+        tx.execute(
+            "INSERT INTO items (tenant_id, chrt_id, order_uid, track_number, price, rid, name, sale, size, total_price, nm_id, brand, status)
+            VALUES ($13, $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            &[
+                &item.chrt_id,
+                &order.order_uid,
+                &item.track_number,
+                &item.price,
+                &item.rid,
+                &item.name,
+                &item.sale,
+                &item.size,
+                &item.total_price,
+                &item.nm_id,
+                &item.brand,
+                &item.status,
+                &tenant,
+            ],
+        ).await?;
+    }
+    Ok(())
+}
+
+fn legacy_customers_query(policy: CustomerPolicy) -> &'static str {
+    match policy {
+        CustomerPolicy::KeepFirst => {
+            "INSERT INTO customers (tenant_id, customer_id, name, phone, zip, city, address, region, email)
+            VALUES ($9, $1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (tenant_id, customer_id) DO NOTHING"
+        }
+        CustomerPolicy::OverwriteLatest | CustomerPolicy::Snapshot => {
+            "INSERT INTO customers (tenant_id, customer_id, name, phone, zip, city, address, region, email)
+            VALUES ($9, $1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (tenant_id, customer_id) DO UPDATE SET
+                name = EXCLUDED.name,
+                phone = EXCLUDED.phone,
+                zip = EXCLUDED.zip,
+                city = EXCLUDED.city,
+                address = EXCLUDED.address,
+                region = EXCLUDED.region,
+                email = EXCLUDED.email"
+        }
+    }
+}
+
+async fn cleanup(client: &Client, tenant: &Tenant, prefix: &str) -> Result<(), OrderError> {
+    let pattern = format!("{prefix}-%");
+    for query in [
+        "DELETE FROM outbox WHERE tenant_id = $2 AND order_uid LIKE $1",
+        "DELETE FROM items WHERE tenant_id = $2 AND order_uid LIKE $1",
+        "DELETE FROM payment WHERE tenant_id = $2 AND order_uid LIKE $1",
+        "DELETE FROM order_delivery WHERE tenant_id = $2 AND order_uid LIKE $1",
+        "DELETE FROM orders WHERE tenant_id = $2 AND order_uid LIKE $1",
+        "DELETE FROM customers WHERE tenant_id = $2 AND customer_id LIKE $1",
+    ] {
+        client.execute(query, &[&pattern, &tenant.as_str()]).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::{api_keys::Scope, order_export::ExportFormat};
+
+// Аргументы командной строки, без подкоманды просто запускается сервер
+#[derive(Debug, Parser)]
+#[command(version, about = "Orders service")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Запустить HTTP сервер (по умолчанию)
+    Serve,
+    /// Замерить скорость вставки заказов (orders/sec) разными способами
+    Bench(BenchArgs),
+    /// Загрузить заказы из NDJSON, JSON массива или директории с такими файлами
+    Import(ImportArgs),
+    /// Выгрузить заказы в NDJSON или CSV
+    Export(ExportArgs),
+    /// Запустить локальный приемник вебхуков для проверки доставки и подписи
+    WebhookReceiver(WebhookReceiverArgs),
+    /// Управление API ключами
+    Keys(KeysArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Сколько заказов вставлять на каждый способ
+    #[arg(long, default_value_t = 2000)]
+    pub orders: usize,
+    /// Сколько товаров в каждом заказе
+    #[arg(long, default_value_t = 3)]
+    pub items: usize,
+    /// Сколько заказов в одной транзакции для способа batched
+    #[arg(long, default_value_t = 500)]
+    pub batch_size: usize,
+    /// Не удалять вставленные заказы после замера
+    #[arg(long)]
+    pub keep: bool,
+    /// Арендатор, по умолчанию из переменной `DEFAULT_TENANT`
+    #[arg(long)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Файл, директория (.json, .ndjson, .jsonl) или "-" для stdin
+    pub path: PathBuf,
+    /// Сколько пачек вставлять параллельно, на каждую свое соединение с базой
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// Сколько заказов в одной транзакции
+    #[arg(long, default_value_t = 500)]
+    pub batch_size: usize,
+    /// Куда записать отклоненные заказы (NDJSON с причиной и исходным заказом)
+    #[arg(long)]
+    pub rejects: Option<PathBuf>,
+    /// Арендатор, по умолчанию из переменной `DEFAULT_TENANT`
+    #[arg(long)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Формат выгрузки
+    #[arg(long, value_enum, default_value_t = ExportFormat::Ndjson)]
+    pub format: ExportFormat,
+    /// Куда писать, без этого аргумента или с "-" пишется в stdout
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+    /// Сколько заказов выгрузить, по умолчанию все
+    #[arg(long)]
+    pub limit: Option<i64>,
+    /// Сколько заказов пропустить
+    #[arg(long)]
+    pub offset: Option<i64>,
+    /// Арендатор, по умолчанию из переменной `DEFAULT_TENANT`
+    #[arg(long)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct WebhookReceiverArgs {
+    /// Адрес на котором слушать
+    #[arg(long, default_value = "127.0.0.1:9090")]
+    pub addr: String,
+    /// Секрет подписки, без него подпись не проверяется
+    #[arg(long)]
+    pub secret: Option<String>,
+    /// Сколько первых запросов отклонить с 500, чтобы проверить повторы
+    #[arg(long, default_value_t = 0)]
+    pub fail_first: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct KeysArgs {
+    #[command(subcommand)]
+    pub command: KeysCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// Создать ключ, он печатается один раз
+    Create {
+        /// Кому выдан ключ
+        #[arg(long)]
+        name: String,
+        /// orders:read, orders:write, admin через запятую
+        #[arg(long, value_delimiter = ',', required = true)]
+        scopes: Vec<Scope>,
+        /// Привязать ключ к арендатору, без этого ключ работает с любым через X-Tenant-ID
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Отозвать ключ по id
+    Revoke {
+        id: i64,
+    },
+    /// Показать все ключи
+    List,
+}
@@ -0,0 +1,262 @@
+use std::{env, fmt, str::FromStr};
+use log::warn;
+
+// Политика обработки покупателя, если он уже есть в таблице customers,
+// а в новом заказе пришли другие данные доставки
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CustomerPolicy {
+    // оставляем первую запись, новые данные доставки игнорируются (старое поведение)
+    #[default]
+    KeepFirst,
+    // перезаписываем покупателя данными из последнего заказа
+    OverwriteLatest,
+    // перезаписываем покупателя и дополнительно сохраняем доставку как снимок для каждого заказа
+    Snapshot,
+}
+
+impl CustomerPolicy {
+    // Читаем политику из CUSTOMER_UPSERT_POLICY, если переменная не задана или кривая - берем KeepFirst
+    pub fn from_env() -> Self {
+        match env::var("CUSTOMER_UPSERT_POLICY") {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!("{e}, falling back to {}", CustomerPolicy::default());
+                CustomerPolicy::default()
+            }),
+            Err(_) => CustomerPolicy::default(),
+        }
+    }
+}
+
+impl FromStr for CustomerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "keep_first" => Ok(CustomerPolicy::KeepFirst),
+            "overwrite_latest" => Ok(CustomerPolicy::OverwriteLatest),
+            "snapshot" => Ok(CustomerPolicy::Snapshot),
+            other => Err(format!("Unknown customer upsert policy: {other}")),
+        }
+    }
+}
+
+impl fmt::Display for CustomerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomerPolicy::KeepFirst => write!(f, "keep_first"),
+            CustomerPolicy::OverwriteLatest => write!(f, "overwrite_latest"),
+            CustomerPolicy::Snapshot => write!(f, "snapshot"),
+        }
+    }
+}
+
+// Лимит размера тела для POST /orders/bulk в мегабайтах, по умолчанию у axum всего 2MB
+pub fn bulk_body_limit() -> usize {
+    env::var("BULK_MAX_BODY_MB")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(32)
+        * 1024
+        * 1024
+}
+
+// Сколько последних событий по заказам держать в памяти для переподключения SSE клиентов (Last-Event-ID)
+pub fn event_log_size() -> usize {
+    env::var("EVENT_LOG_SIZE")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(1000)
+}
+
+// Сколько заказов держать в кэше для чтения по order_uid, 0 выключает кэш
+pub fn order_cache_size() -> usize {
+    env::var("ORDER_CACHE_SIZE")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(1000)
+}
+
+// Проверка API ключей на маршрутах, API_AUTH=off выключает ее (например для локальной разработки)
+pub fn api_auth() -> bool {
+    !env::var("API_AUTH").is_ok_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+// Общий с gateway секрет для JWT с алгоритмом HS256
+pub fn jwt_secret() -> Option<String> {
+    env::var("JWT_SECRET").ok().filter(|secret| !secret.is_empty())
+}
+
+// JWKS файл с публичными ключами для JWT с алгоритмом RS256, читается при запуске
+pub fn jwt_jwks_file() -> Option<String> {
+    env::var("JWT_JWKS_FILE").ok().filter(|path| !path.is_empty())
+}
+
+// Если заданы, токен должен быть выдан этим iss и для этого aud
+pub fn jwt_issuer() -> Option<String> {
+    env::var("JWT_ISSUER").ok().filter(|issuer| !issuer.is_empty())
+}
+
+pub fn jwt_audience() -> Option<String> {
+    env::var("JWT_AUDIENCE").ok().filter(|audience| !audience.is_empty())
+}
+
+// Арендатор для запросов без X-Tenant-ID и без арендатора в ключе или токене, например когда маркетплейс один
+pub fn default_tenant() -> String {
+    env::var("DEFAULT_TENANT")
+        .ok()
+        .map(|tenant| tenant.trim().to_string())
+        .filter(|tenant| !tenant.is_empty())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+// TENANT_RLS=on: перед запросами ставится app.tenant_id для политик row level security из rls.sql
+pub fn tenant_rls() -> bool {
+    env::var("TENANT_RLS").is_ok_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+// Лимит запросов на клиента (API ключ, пользователь из JWT или IP) к маршрутам чтения:
+// сколько запросов в секунду и сколько можно сделать разом после простоя. 0 в секунду выключает лимит
+pub fn rate_limit_read() -> (u32, u32) {
+    let rate = env_number("RATE_LIMIT_READ", 100);
+    (rate, env_number("RATE_LIMIT_READ_BURST", rate.saturating_mul(2)))
+}
+
+// Лимит на IP клиента для всех запросов HTTP и gRPC, проверяется до API ключа и JWT.
+// Должен быть выше лимитов на клиента: за одним IP (NAT, прокси) бывает несколько клиентов
+pub fn rate_limit_ip() -> (u32, u32) {
+    let rate = env_number("RATE_LIMIT_IP", 200);
+    (rate, env_number("RATE_LIMIT_IP_BURST", rate.saturating_mul(2)))
+}
+
+// То же для записи и управления вебхуками, запись дороже и идет через один клиент базы
+pub fn rate_limit_write() -> (u32, u32) {
+    let rate = env_number("RATE_LIMIT_WRITE", 20);
+    (rate, env_number("RATE_LIMIT_WRITE_BURST", rate.saturating_mul(2)))
+}
+
+// Сколько HTTP запросов сервис обрабатывает одновременно, остальные сразу получают 429. 0 - без ограничения
+pub fn max_in_flight() -> u32 {
+    env_number("MAX_IN_FLIGHT", 512)
+}
+
+// Сколько выгрузок GET /orders/export идет одновременно, у каждой свое соединение с базой
+// на все время выгрузки, поэтому без лимита их можно открыть сколько угодно. Лишние получают 429
+pub fn export_max_concurrent() -> usize {
+    env_number("EXPORT_MAX_CONCURRENT", 4).max(1) as usize
+}
+
+// Общий уровень логов: off, error, warn, info, debug, trace. Меняется на ходу через PUT /admin/log-level
+pub fn log_level() -> String {
+    env::var("LOG_LEVEL").unwrap_or_else(|_| "debug".to_string())
+}
+
+// Уровни для отдельных модулей через запятую: tokio_postgres=info,axum_study_project_1::outbox=trace
+pub fn log_modules() -> String {
+    env::var("LOG_MODULES").unwrap_or_default()
+}
+
+// text (по умолчанию) или json
+pub fn log_format() -> String {
+    env::var("LOG_FORMAT").unwrap_or_else(|_| "text".to_string())
+}
+
+// Файл куда сервис пишет логи кроме stdout
+pub fn log_file() -> Option<String> {
+    env::var("LOG_FILE").ok().filter(|path| !path.is_empty())
+}
+
+// Размер файла лога в мегабайтах после которого начинается новый, 0 - без ограничения
+pub fn log_rotate_size_mb() -> u32 {
+    env_number("LOG_ROTATE_SIZE_MB", 100)
+}
+
+// never, hourly или daily (по умолчанию)
+pub fn log_rotate() -> String {
+    env::var("LOG_ROTATE").unwrap_or_else(|_| "daily".to_string())
+}
+
+// Сколько старых файлов лога хранить
+pub fn log_keep_files() -> u32 {
+    env_number("LOG_KEEP_FILES", 7)
+}
+
+// Как скрывать поля доставки и оплаты в логах поверх правил по умолчанию: delivery.city=full,payment.bank=hash
+pub fn log_redact() -> String {
+    env::var("LOG_REDACT").unwrap_or_default()
+}
+
+// LOG_UNSAFE_DEBUG=on пишет персональные данные в логи как есть, действует только вместе с APP_ENV=dev
+pub fn log_unsafe_debug() -> bool {
+    env::var("LOG_UNSAFE_DEBUG").is_ok_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+// none (по умолчанию), otlp или stdout
+pub fn traces_exporter() -> String {
+    env::var("OTEL_TRACES_EXPORTER").unwrap_or_else(|_| "none".to_string())
+}
+
+// Имя сервиса в трейсах
+pub fn service_name() -> String {
+    env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "axum_study_project".to_string())
+}
+
+fn env_number(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or(default)
+}
+
+// Токен для подключения к /ws, без него подключиться может кто угодно
+pub fn ws_auth_token() -> Option<String> {
+    env::var("WS_AUTH_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+// dev режим (APP_ENV=dev) включает инструменты которые не нужны в проде, например песочницу GraphiQL
+pub fn dev_mode() -> bool {
+    env::var("APP_ENV").is_ok_and(|value| value.trim().eq_ignore_ascii_case("dev"))
+}
+
+// Куда outbox relay отправляет события кроме подписчиков внутри процесса: log, webhook, kafka (через запятую)
+pub fn outbox_sinks() -> Vec<String> {
+    env::var("OUTBOX_SINKS")
+        .unwrap_or_else(|_| "webhook".to_string())
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// Ключ AES-256 для секретов вебхуков в базе, 32 байта в hex (openssl rand -hex 32)
+pub fn webhook_secret_key() -> Result<Option<[u8; 32]>, String> {
+    let Some(value) = env::var("WEBHOOK_SECRET_KEY").ok().filter(|value| !value.trim().is_empty()) else {
+        return Ok(None);
+    };
+    hex::decode(value.trim())
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .map(Some)
+        .ok_or_else(|| "WEBHOOK_SECRET_KEY must be 32 bytes in hex (64 characters)".to_string())
+}
+
+// on - вебхуки можно слать на localhost и адреса внутренней сети, только для локальной проверки
+pub fn webhook_allow_private() -> bool {
+    env::var("WEBHOOK_ALLOW_PRIVATE").is_ok_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+// Брокеры Kafka через запятую (bootstrap.servers) для OUTBOX_SINKS=kafka
+#[cfg(feature = "kafka")]
+pub fn kafka_brokers() -> Option<String> {
+    env::var("KAFKA_BROKERS").ok().filter(|brokers| !brokers.is_empty())
+}
+
+#[cfg(feature = "kafka")]
+pub fn kafka_topic() -> String {
+    env::var("KAFKA_TOPIC").unwrap_or_else(|_| "orders".to_string())
+}
+
+// Формат значения записи: json (по умолчанию) или avro
+#[cfg(feature = "kafka")]
+pub fn kafka_format() -> String {
+    env::var("KAFKA_FORMAT").unwrap_or_else(|_| "json".to_string())
+}
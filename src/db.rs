@@ -0,0 +1,52 @@
+use log::{error, warn};
+use metrics::counter;
+use std::{sync::Arc, time::Instant};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio_postgres::Client;
+
+use crate::{monitoring, statements::StatementCache};
+
+// Клиент базы один на весь сервис, рядом с ним кэш запросов подготовленных на его соединении.
+// Хранятся вместе: при переподключении сбрасывается кэш только этого клиента,
+// у воркеров import и bench соединения и кэши свои и их это не касается
+#[derive(Clone)]
+pub struct Db {
+    inner: Arc<DbInner>,
+}
+
+struct DbInner {
+    client: Mutex<Client>,
+    statements: StatementCache,
+}
+
+impl Db {
+    pub fn new(client: Client) -> Self {
+        Db { inner: Arc::new(DbInner { client: Mutex::new(client), statements: StatementCache::default() }) }
+    }
+
+    // Клиент в очереди с остальными запросами. Если соединение оборвалось, клиент подменяется новым
+    // и кэш подготовленных запросов сбрасывается. Не вышло подключиться - отдаем старый,
+    // запрос упадет с ошибкой базы, следующий попробует снова
+    pub async fn lock(&self) -> MutexGuard<'_, Client> {
+        let started = Instant::now();
+        let mut client = self.inner.client.lock().await;
+        monitoring::client_waited(started);
+        if client.is_closed() {
+            match crate::get_db().await {
+                Ok(reconnected) => {
+                    warn!("Database connection was closed, reconnected");
+                    *client = reconnected;
+                    self.inner.statements.clear();
+                    counter!("db_reconnects_total").increment(1);
+                }
+                Err(e) => error!("Database connection is closed, failed to reconnect: {e}"),
+            }
+        }
+        client
+    }
+
+    // Подготовленные запросы для клиента из lock
+    pub fn statements(&self) -> &StatementCache {
+        &self.inner.statements
+    }
+}
@@ -0,0 +1,49 @@
+use log::{error, info};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    cli::ExportArgs,
+    get_db,
+    order_export::{stream_orders, ExportParams},
+    tenant::Tenant,
+};
+
+// Выгрузка в файл через тот же курсор что и GET /orders/export
+pub async fn run(args: ExportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) if path != Path::new("-") => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+        _ => Box::new(io::BufWriter::new(io::stdout())),
+    };
+
+    let tenant = Tenant::from_arg(args.tenant.as_deref())?;
+    let client = get_db().await?;
+    let params = ExportParams {
+        format: args.format,
+        limit: args.limit,
+        offset: args.offset,
+    };
+
+    let (chunks, mut receiver) = mpsc::channel(16);
+    let export = tokio::spawn(async move { stream_orders(client, &tenant, &params, &chunks).await });
+
+    while let Some(chunk) = receiver.recv().await {
+        output.write_all(&chunk?)?;
+    }
+    output.flush()?;
+
+    match export.await? {
+        Ok(orders) => {
+            info!("Exported {orders} orders");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Orders export failed: {e}");
+            Err(e.into())
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use async_graphql::{
+    dataloader::{DataLoader, Loader},
+    http::GraphiQLSource,
+    ComplexObject, Context, EmptyMutation, EmptySubscription, ErrorExtensions, InputObject, Object, Schema,
+    SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse},
+};
+use chrono::NaiveDateTime;
+use std::{collections::HashMap, sync::Arc};
+use tokio_postgres::{types::ToSql, Row};
+
+use crate::{
+    db::Db,
+    models::{Delivery, Item, Payment},
+    order_errors::OrderError,
+    order_impl::db_timeout,
+    request_id,
+    tenant::{self, Tenant},
+};
+
+// Сколько записей можно запросить за раз в orders/customers
+const MAX_LIMIT: i64 = 100;
+const MAX_DEPTH: usize = 10;
+// формат created_from/created_to, такой же как у date_created в ответе
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+const ORDER_COLUMNS: &str = "
+    SELECT
+        o.order_uid,
+        o.track_number,
+        o.entry,
+        o.delivery_service,
+        o.customer_id,
+        o.shardkey,
+        o.sm_id,
+        TO_CHAR(o.date_created, 'YYYY-MM-DD HH24:MI:SS') AS date_created,
+        o.oof_shard
+    FROM orders o
+";
+
+const CUSTOMER_COLUMNS: &str = "
+    SELECT customer_id, name, phone, zip, city, address, region, email
+    FROM customers
+";
+
+pub type OrdersSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> OrdersSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+// Загрузчики создаются на каждый запрос, так кэш DataLoader не живет дольше одного запроса.
+// Арендатор запроса кладется в данные запроса и в загрузчик, по нему фильтруются все запросы
+pub async fn graphql_handler(
+    State(schema): State<OrdersSchema>,
+    State(db): State<Db>,
+    tenant: Tenant,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let loader = DataLoader::new(
+        DbLoader {
+            db: db.clone(),
+            tenant: tenant.clone(),
+        },
+        // загрузки идут в отдельных задачах, id запроса нужен их логам ошибок базы
+        request_id::spawn,
+    );
+    schema
+        .execute(request.into_inner().data(db).data(tenant).data(loader))
+        .await
+        .into()
+}
+
+// Песочница GraphiQL, подключается только в dev режиме (APP_ENV=dev)
+pub async fn graphiql() -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+// Ошибка в ответе GraphQL: то же сообщение что и в HTTP, статус и поле в extensions
+fn gql_error(err: &OrderError) -> async_graphql::Error {
+    let (status, message, field) = err.parts();
+    async_graphql::Error::new(message).extend_with(|_, e| {
+        e.set("status", status.as_u16());
+        if !field.is_empty() {
+            e.set("field", field);
+        }
+    })
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(complex, name = "Order")]
+pub struct OrderNode {
+    pub order_uid: String,
+    pub track_number: String,
+    pub entry: String,
+    pub delivery_service: String,
+    pub customer_id: String,
+    pub shardkey: String,
+    pub sm_id: i32,
+    pub date_created: String,
+    pub oof_shard: String,
+}
+
+impl OrderNode {
+    fn from_row(row: &Row) -> Self {
+        OrderNode {
+            order_uid: row.get("order_uid"),
+            track_number: row.get("track_number"),
+            entry: row.get("entry"),
+            delivery_service: row.get("delivery_service"),
+            customer_id: row.get("customer_id"),
+            shardkey: row.get("shardkey"),
+            sm_id: row.get("sm_id"),
+            date_created: row.get("date_created"),
+            oof_shard: row.get("oof_shard"),
+        }
+    }
+}
+
+// Вложенные поля заказа грузятся через DataLoader: на список из N заказов
+// уходит по одному запросу на доставку, оплату, товары и покупателей, а не N
+#[ComplexObject]
+impl OrderNode {
+    // Доставка на момент заказа если есть снимок (политика snapshot), иначе текущая у покупателя
+    async fn delivery(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Delivery>> {
+        load(ctx, DeliveryOf(self.order_uid.clone())).await
+    }
+
+    async fn payment(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Payment>> {
+        load(ctx, PaymentOf(self.order_uid.clone())).await
+    }
+
+    async fn items(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Item>> {
+        Ok(load(ctx, ItemsOf(self.order_uid.clone())).await?.unwrap_or_default())
+    }
+
+    async fn customer(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<CustomerNode>> {
+        load(ctx, CustomerById(self.customer_id.clone())).await
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(complex, name = "Customer")]
+pub struct CustomerNode {
+    pub customer_id: String,
+    pub name: String,
+    pub phone: String,
+    pub zip: String,
+    pub city: String,
+    pub address: String,
+    pub region: String,
+    pub email: String,
+}
+
+impl CustomerNode {
+    fn from_row(row: &Row) -> Self {
+        CustomerNode {
+            customer_id: row.get("customer_id"),
+            name: row.get("name"),
+            phone: row.get("phone"),
+            zip: row.get("zip"),
+            city: row.get("city"),
+            address: row.get("address"),
+            region: row.get("region"),
+            email: row.get("email"),
+        }
+    }
+}
+
+#[ComplexObject]
+impl CustomerNode {
+    // Заказы покупателя грузятся пачкой на всех покупателей, limit/offset уходят в запрос для каждого
+    async fn orders(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 10)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<Vec<OrderNode>> {
+        let (limit, offset) = page(limit, offset)?;
+        let key = OrdersOf {
+            customer_id: self.customer_id.clone(),
+            limit,
+            offset,
+        };
+        Ok(load(ctx, key).await?.unwrap_or_default())
+    }
+}
+
+// Фильтры для orders, пустые поля не фильтруют
+#[derive(Debug, Default, InputObject)]
+pub struct OrderFilter {
+    pub delivery_service: Option<String>,
+    pub customer_id: Option<String>,
+    // трек номер заказа или любого из его товаров
+    pub track_number: Option<String>,
+    // date_created в формате YYYY-MM-DD HH24:MI:SS, включительно
+    pub created_from: Option<String>,
+    pub created_to: Option<String>,
+}
+
+#[derive(Debug, Default, InputObject)]
+pub struct CustomerFilter {
+    pub city: Option<String>,
+    pub region: Option<String>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn order(&self, ctx: &Context<'_>, order_uid: String) -> async_graphql::Result<Option<OrderNode>> {
+        let tenant = ctx.data::<Tenant>()?;
+        let client = ctx.data::<Db>()?.lock().await;
+        tenant::bind(&*client, tenant).await.map_err(|e| gql_error(&e))?;
+        let query = format!("{ORDER_COLUMNS} WHERE o.tenant_id = $1 AND o.order_uid = $2");
+        let row = db_timeout("query order", client.query_opt(&query, &[&tenant.as_str(), &order_uid]))
+            .await
+            .map_err(|e| gql_error(&e))?;
+        Ok(row.as_ref().map(OrderNode::from_row))
+    }
+
+    async fn orders(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<OrderFilter>,
+        #[graphql(default = 10)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<Vec<OrderNode>> {
+        let filter = filter.unwrap_or_default();
+        let (limit, offset) = page(limit, offset)?;
+        let created_from = date(filter.created_from.as_deref(), "createdFrom")?;
+        let created_to = date(filter.created_to.as_deref(), "createdTo")?;
+        let tenant = ctx.data::<Tenant>()?;
+        let client = ctx.data::<Db>()?.lock().await;
+        tenant::bind(&*client, tenant).await.map_err(|e| gql_error(&e))?;
+        // NULL в параметре выключает соответствующий фильтр, так запрос остается одним и тем же
+        let query = format!(
+            "{ORDER_COLUMNS}
+            WHERE o.tenant_id = $8
+                AND ($1::varchar IS NULL OR o.delivery_service = $1)
+                AND ($2::varchar IS NULL OR o.customer_id = $2)
+                AND ($3::varchar IS NULL OR o.track_number = $3
+                    OR EXISTS (SELECT 1 FROM items i
+                        WHERE i.tenant_id = o.tenant_id AND i.order_uid = o.order_uid AND i.track_number = $3))
+                AND ($4::varchar IS NULL OR o.date_created >= $4::timestamp)
+                AND ($5::varchar IS NULL OR o.date_created <= $5::timestamp)
+            ORDER BY o.order_uid
+            LIMIT $6 OFFSET $7"
+        );
+        let tenant_id = tenant.as_str();
+        let params: [&(dyn ToSql + Sync); 8] = [
+            &filter.delivery_service,
+            &filter.customer_id,
+            &filter.track_number,
+            &created_from,
+            &created_to,
+            &limit,
+            &offset,
+            &tenant_id,
+        ];
+        let rows = db_timeout("query orders", client.query(&query, &params))
+            .await
+            .map_err(|e| gql_error(&e))?;
+        Ok(rows.iter().map(OrderNode::from_row).collect())
+    }
+
+    async fn customer(&self, ctx: &Context<'_>, customer_id: String) -> async_graphql::Result<Option<CustomerNode>> {
+        load(ctx, CustomerById(customer_id)).await
+    }
+
+    async fn customers(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<CustomerFilter>,
+        #[graphql(default = 10)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<Vec<CustomerNode>> {
+        let filter = filter.unwrap_or_default();
+        let (limit, offset) = page(limit, offset)?;
+        let tenant = ctx.data::<Tenant>()?;
+        let client = ctx.data::<Db>()?.lock().await;
+        tenant::bind(&*client, tenant).await.map_err(|e| gql_error(&e))?;
+        let query = format!(
+            "{CUSTOMER_COLUMNS}
+            WHERE tenant_id = $5
+                AND ($1::varchar IS NULL OR city = $1)
+                AND ($2::varchar IS NULL OR region = $2)
+            ORDER BY customer_id
+            LIMIT $3 OFFSET $4"
+        );
+        let rows = db_timeout(
+            "query customers",
+            client.query(&query, &[&filter.city, &filter.region, &limit, &offset, &tenant.as_str()]),
+        )
+        .await
+        .map_err(|e| gql_error(&e))?;
+        Ok(rows.iter().map(CustomerNode::from_row).collect())
+    }
+}
+
+fn page(limit: i64, offset: i64) -> async_graphql::Result<(i64, i64)> {
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(gql_error(&OrderError::Validation {
+            msg: format!("limit must be between 1 and {MAX_LIMIT}"),
+            field: "limit".to_string(),
+        }));
+    }
+    if offset < 0 {
+        return Err(gql_error(&OrderError::Validation {
+            msg: "offset must not be negative".to_string(),
+            field: "offset".to_string(),
+        }));
+    }
+    Ok((limit, offset))
+}
+
+// Кривая дата иначе падала бы в postgres на ::timestamp и приходила как ошибка базы
+fn date(value: Option<&str>, field: &str) -> async_graphql::Result<Option<String>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let parsed = NaiveDateTime::parse_from_str(value.trim(), DATE_FORMAT).map_err(|_| {
+        gql_error(&OrderError::Validation {
+            msg: format!("{field} must be in format YYYY-MM-DD HH:MM:SS"),
+            field: field.to_string(),
+        })
+    })?;
+    Ok(Some(parsed.format(DATE_FORMAT).to_string()))
+}
+
+async fn load<K>(ctx: &Context<'_>, key: K) -> async_graphql::Result<Option<<DbLoader as Loader<K>>::Value>>
+where
+    K: Send + Sync + std::hash::Hash + Eq + Clone + 'static,
+    DbLoader: Loader<K, Error = Arc<OrderError>>,
+{
+    ctx.data::<DataLoader<DbLoader>>()?
+        .load_one(key)
+        .await
+        .map_err(|e| gql_error(&e))
+}
+
+// Ключи загрузчика, по одному типу на каждое вложенное поле
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeliveryOf(String);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PaymentOf(String);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ItemsOf(String);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CustomerById(String);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OrdersOf {
+    customer_id: String,
+    limit: i64,
+    offset: i64,
+}
+
+pub struct DbLoader {
+    db: Db,
+    tenant: Tenant,
+}
+
+impl DbLoader {
+    // Один запрос на все ключи через = ANY($1), арендатор всегда $2
+    async fn query(&self, action: &str, query: &str, keys: Vec<String>) -> Result<Vec<Row>, Arc<OrderError>> {
+        self.query_with(action, query, &[&keys, &self.tenant.as_str()]).await
+    }
+
+    async fn query_with(
+        &self,
+        action: &str,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, Arc<OrderError>> {
+        let client = self.db.lock().await;
+        tenant::bind(&*client, &self.tenant).await.map_err(Arc::new)?;
+        db_timeout(action, client.query(query, params)).await.map_err(Arc::new)
+    }
+}
+
+impl Loader<DeliveryOf> for DbLoader {
+    type Value = Delivery;
+    type Error = Arc<OrderError>;
+
+    async fn load(&self, keys: &[DeliveryOf]) -> Result<HashMap<DeliveryOf, Delivery>, Self::Error> {
+        let query = "
+            SELECT
+                o.order_uid,
+                COALESCE(od.name, d.name) AS name,
+                COALESCE(od.phone, d.phone) AS phone,
+                COALESCE(od.zip, d.zip) AS zip,
+                COALESCE(od.city, d.city) AS city,
+                COALESCE(od.address, d.address) AS address,
+                COALESCE(od.region, d.region) AS region,
+                COALESCE(od.email, d.email) AS email
+            FROM orders o
+            JOIN customers d ON o.tenant_id = d.tenant_id AND o.customer_id = d.customer_id
+            LEFT JOIN order_delivery od ON o.tenant_id = od.tenant_id AND o.order_uid = od.order_uid
+            WHERE o.order_uid = ANY($1::varchar[]) AND o.tenant_id = $2";
+        let keys = keys.iter().map(|key| key.0.clone()).collect();
+        let rows = self.query("load deliveries", query, keys).await?;
+        Ok(rows
+            .iter()
+            .map(|row| (DeliveryOf(row.get("order_uid")), Delivery::from_row(row)))
+            .collect())
+    }
+}
+
+impl Loader<PaymentOf> for DbLoader {
+    type Value = Payment;
+    type Error = Arc<OrderError>;
+
+    async fn load(&self, keys: &[PaymentOf]) -> Result<HashMap<PaymentOf, Payment>, Self::Error> {
+        let query = "
+            SELECT
+                order_uid,
+                transaction,
+                request_id,
+                currency,
+                provider,
+                amount,
+                CAST(EXTRACT(EPOCH FROM payment_dt) AS bigint) AS payment_unix_timestamp,
+                bank,
+                delivery_cost,
+                goods_total,
+                custom_fee
+            FROM payment
+            WHERE order_uid = ANY($1::varchar[]) AND tenant_id = $2";
+        let keys = keys.iter().map(|key| key.0.clone()).collect();
+        let rows = self.query("load payments", query, keys).await?;
+        Ok(rows
+            .iter()
+            .map(|row| (PaymentOf(row.get("order_uid")), Payment::from_row(row)))
+            .collect())
+    }
+}
+
+impl Loader<ItemsOf> for DbLoader {
+    type Value = Vec<Item>;
+    type Error = Arc<OrderError>;
+
+    async fn load(&self, keys: &[ItemsOf]) -> Result<HashMap<ItemsOf, Vec<Item>>, Self::Error> {
+        let query = "
+            SELECT
+                order_uid,
+                chrt_id,
+                track_number AS item_track_number,
+                price,
+                rid,
+                name AS item_name,
+                sale,
+                size,
+                total_price,
+                nm_id,
+                brand,
+                status
+            FROM items
+            WHERE order_uid = ANY($1::varchar[]) AND tenant_id = $2
+            ORDER BY chrt_id";
+        let keys = keys.iter().map(|key| key.0.clone()).collect();
+        let rows = self.query("load items", query, keys).await?;
+        let mut items: HashMap<ItemsOf, Vec<Item>> = HashMap::new();
+        for row in &rows {
+            items.entry(ItemsOf(row.get("order_uid"))).or_default().push(Item::from_row(row));
+        }
+        Ok(items)
+    }
+}
+
+impl Loader<CustomerById> for DbLoader {
+    type Value = CustomerNode;
+    type Error = Arc<OrderError>;
+
+    async fn load(&self, keys: &[CustomerById]) -> Result<HashMap<CustomerById, CustomerNode>, Self::Error> {
+        let query = format!("{CUSTOMER_COLUMNS} WHERE customer_id = ANY($1::varchar[]) AND tenant_id = $2");
+        let keys = keys.iter().map(|key| key.0.clone()).collect();
+        let rows = self.query("load customers", &query, keys).await?;
+        Ok(rows
+            .iter()
+            .map(|row| (CustomerById(row.get("customer_id")), CustomerNode::from_row(row)))
+            .collect())
+    }
+}
+
+impl Loader<OrdersOf> for DbLoader {
+    type Value = Vec<OrderNode>;
+    type Error = Arc<OrderError>;
+
+    // Страница считается в базе для каждого покупателя отдельно (LATERAL с LIMIT/OFFSET).
+    // Покупатели с одинаковыми limit/offset грузятся одним запросом, обычно он и один на весь список
+    async fn load(&self, keys: &[OrdersOf]) -> Result<HashMap<OrdersOf, Vec<OrderNode>>, Self::Error> {
+        let query = format!(
+            "SELECT page.* FROM UNNEST($1::varchar[]) AS c(customer_id)
+            CROSS JOIN LATERAL (
+                {ORDER_COLUMNS}
+                WHERE o.tenant_id = $2 AND o.customer_id = c.customer_id
+                ORDER BY o.order_uid
+                LIMIT $3 OFFSET $4
+            ) page"
+        );
+        let mut pages: HashMap<(i64, i64), Vec<String>> = HashMap::new();
+        for key in keys {
+            pages.entry((key.limit, key.offset)).or_default().push(key.customer_id.clone());
+        }
+
+        let mut orders: HashMap<OrdersOf, Vec<OrderNode>> = HashMap::new();
+        for ((limit, offset), customer_ids) in pages {
+            let params: [&(dyn ToSql + Sync); 4] = [&customer_ids, &self.tenant.as_str(), &limit, &offset];
+            let rows = self.query_with("load customer orders", &query, &params).await?;
+            for row in &rows {
+                let order = OrderNode::from_row(row);
+                let key = OrdersOf {
+                    customer_id: order.customer_id.clone(),
+                    limit,
+                    offset,
+                };
+                orders.entry(key).or_default().push(order);
+            }
+        }
+        Ok(orders)
+    }
+}
@@ -0,0 +1,563 @@
+use log::{debug, info, warn};
+use std::{convert::Infallible, net::IpAddr};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    body::BoxBody,
+    codegen::{
+        http::{self, HeaderMap},
+        BoxFuture, Context, Poll, Service,
+    },
+    metadata::{MetadataMap, MetadataValue},
+    server::NamedService,
+    transport::server::TcpConnectInfo,
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    api_keys::Scope,
+    auth::{self, Auth, Principal},
+    rate_limit::RateLimits,
+    models::{Delivery, Item, Order, Payment},
+    order_errors::OrderError,
+    order_events::{OrderEventFilter, OrderEvents},
+    state::AppState,
+    tenant::{Tenant, TENANT_HEADER},
+};
+
+// Код сгенерированный из proto/orders.proto в build.rs
+#[allow(clippy::all, clippy::pedantic)]
+pub mod proto {
+    tonic::include_proto!("orders");
+}
+
+use proto::order_service_server::{OrderService, OrderServiceServer};
+
+// gRPC сервис поверх того же состояния что и HTTP роутер: общий клиент базы,
+// кэш подготовленных запросов, политика покупателей и шина событий
+pub struct OrderGrpc {
+    state: AppState,
+}
+
+impl OrderGrpc {
+    pub fn server(state: AppState) -> Authenticated<OrderServiceServer<Self>> {
+        Authenticated {
+            auth: state.auth.clone(),
+            limits: state.rate_limits.clone(),
+            inner: OrderServiceServer::new(OrderGrpc { state }),
+        }
+    }
+}
+
+// Проверка учетных данных для всех методов сервиса, те же API ключи и JWT что и у HTTP маршрутов
+// (x-api-key или authorization: Bearer в метаданных). Interceptor из tonic синхронный, а ключ ищется в базе,
+// поэтому проверка - обертка над сервисом. Проверенный Principal кладется в extensions запроса.
+// Лимиты запросов тоже как у HTTP: на IP до учетных данных, на клиента после
+#[derive(Clone)]
+pub struct Authenticated<S> {
+    auth: Auth,
+    limits: RateLimits,
+    inner: S,
+}
+
+impl<S> Service<http::Request<BoxBody>> for Authenticated<S>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<BoxBody>) -> Self::Future {
+        // в future уходит сервис который уже прошел poll_ready, на его место встает клон
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let auth = self.auth.clone();
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            let ip = req
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(TcpConnectInfo::remote_addr)
+                .map(|addr| addr.ip());
+            match admit(&auth, &limits, req.uri().path(), req.headers(), ip).await {
+                Ok(principal) => {
+                    req.extensions_mut().insert(principal);
+                    inner.call(req).await
+                }
+                Err(e) => Ok(Status::from(e).into_http()),
+            }
+        })
+    }
+}
+
+// Лимит на IP, учетные данные, права на метод и лимит клиента
+async fn admit(
+    auth: &Auth,
+    limits: &RateLimits,
+    path: &str,
+    headers: &HeaderMap,
+    ip: Option<IpAddr>,
+) -> Result<Principal, OrderError> {
+    let (scope, staff_only) = method_scope(path);
+    limits.check_ip(ip)?;
+    let principal = auth.authenticate(headers).await?;
+    auth::check(&principal, scope, staff_only)?;
+    limits.check_client(scope == Scope::OrdersWrite, Some(&principal), ip)?;
+    Ok(principal)
+}
+
+impl<S: NamedService> NamedService for Authenticated<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+// Права на методы как у HTTP маршрутов: CreateOrder как POST /order, GetOrder как GET /order/:order_uid
+// (покупателю можно, но только свой), ListOrders и WatchOrders как выгрузка и поток событий - только сотрудникам
+fn method_scope(path: &str) -> (Scope, bool) {
+    match path.rsplit('/').next().unwrap_or_default() {
+        "CreateOrder" => (Scope::OrdersWrite, true),
+        "GetOrder" => (Scope::OrdersRead, false),
+        _ => (Scope::OrdersRead, true),
+    }
+}
+
+#[tonic::async_trait]
+impl OrderService for OrderGrpc {
+    async fn create_order(
+        &self,
+        request: Request<proto::CreateOrderRequest>,
+    ) -> Result<Response<proto::CreateOrderResponse>, Status> {
+        let tenant = tenant(&principal(&request)?, request.metadata())?;
+        let Some(order) = request.into_inner().order else {
+            return Err(missing_field("order").into());
+        };
+        let order = Order::try_from(order)?;
+        info!("Received gRPC order creation request for tenant {}: {:?}", tenant, order);
+
+        let mut client = self.state.db.lock().await;
+        order
+            .create(&mut client, self.state.db.statements(), &tenant, self.state.customer_policy)
+            .await?;
+        drop(client);
+
+        Ok(Response::new(proto::CreateOrderResponse { order_uid: order.order_uid }))
+    }
+
+    async fn get_order(
+        &self,
+        request: Request<proto::GetOrderRequest>,
+    ) -> Result<Response<proto::Order>, Status> {
+        let principal = principal(&request)?;
+        let tenant = tenant(&principal, request.metadata())?;
+        let order_uid = request.into_inner().order_uid;
+        let client = self.state.db.lock().await;
+        // чужой ордер для покупателя выглядит так же как несуществующий
+        let Some(order) = self
+            .state
+            .orders_cache
+            .find_by_id(&client, &tenant, &order_uid)
+            .await?
+            .filter(|order| principal.can_read(order))
+        else {
+            return Err(OrderError::order_not_found().into());
+        };
+        Ok(Response::new(proto::Order::from(&order)))
+    }
+
+    async fn list_orders(
+        &self,
+        request: Request<proto::ListOrdersRequest>,
+    ) -> Result<Response<proto::ListOrdersResponse>, Status> {
+        let tenant = tenant(&principal(&request)?, request.metadata())?;
+        let request = request.into_inner();
+        let limit = request.limit.unwrap_or(10);
+        let offset = request.offset.unwrap_or(0);
+
+        let client = self.state.db.lock().await;
+        let orders = Order::list(&client, &tenant, limit, offset, None).await?;
+        Ok(Response::new(proto::ListOrdersResponse {
+            orders: orders.iter().map(proto::Order::from).collect(),
+        }))
+    }
+
+    type WatchOrdersStream = ReceiverStream<Result<proto::OrderEvent, Status>>;
+
+    async fn watch_orders(
+        &self,
+        request: Request<proto::WatchOrdersRequest>,
+    ) -> Result<Response<Self::WatchOrdersStream>, Status> {
+        let tenant = tenant(&principal(&request)?, request.metadata())?;
+        let filter = request.into_inner();
+        info!("New gRPC order watcher for tenant {}: {:?}", tenant, filter);
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(watch(self.state.events.clone(), tenant, filter, sender));
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+// Пересылает события из шины подписчику пока он не отключится
+async fn watch(
+    events: OrderEvents,
+    tenant: Tenant,
+    filter: proto::WatchOrdersRequest,
+    sender: mpsc::Sender<Result<proto::OrderEvent, Status>>,
+) {
+    let filter = OrderEventFilter {
+        delivery_service: filter.delivery_service,
+        customer_id: filter.customer_id,
+        tenant: Some(tenant),
+    };
+    let mut receiver = events.subscribe(None).receiver;
+    loop {
+        // клиент может отключиться пока событий нет, тогда задача заканчивается сразу, а не на следующем send
+        let next = tokio::select! {
+            next = receiver.recv() => next,
+            () = sender.closed() => {
+                debug!("gRPC order watcher disconnected");
+                return;
+            }
+        };
+        let event = match next {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("gRPC order watcher lagged behind, {skipped} events skipped");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !filter.matches(&event) {
+            continue;
+        }
+        let message = proto::OrderEvent {
+            kind: event.kind.as_str().to_string(),
+            order: Some(proto::Order::from(event.order.as_ref())),
+            chrt_id: event.kind.chrt_id(),
+        };
+        if sender.send(Ok(message)).await.is_err() {
+            debug!("gRPC order watcher disconnected");
+            return;
+        }
+    }
+}
+
+// Арендатор как у HTTP маршрутов: из ключа или токена, метаданные x-tenant-id (как заголовок X-Tenant-ID)
+// учитываются только если учетные данные ни к кому не привязаны, чужой арендатор - PERMISSION_DENIED
+fn tenant(principal: &Principal, metadata: &MetadataMap) -> Result<Tenant, OrderError> {
+    let requested = metadata
+        .get(TENANT_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().trim())
+        .filter(|value| !value.is_empty());
+    Tenant::resolve_requested(Some(principal), requested)
+}
+
+// Principal который положил Authenticated
+fn principal<T>(request: &Request<T>) -> Result<Principal, OrderError> {
+    request
+        .extensions()
+        .get::<Principal>()
+        .cloned()
+        .ok_or_else(|| OrderError::Unauthorized("Missing API key or bearer token".to_string()))
+}
+
+fn missing_field(field: &str) -> OrderError {
+    OrderError::Validation {
+        msg: format!("{field} is required"),
+        field: field.to_string(),
+    }
+}
+
+// Ошибки раскладываются по каноническим кодам gRPC, сообщение то же что и в HTTP ответе,
+// поле с ошибкой передается в метаданных x-error-field
+impl From<OrderError> for Status {
+    fn from(err: OrderError) -> Self {
+        let (status, message, field) = err.parts();
+        metrics::counter!("order_errors_total", "kind" => err.kind()).increment(1);
+        let code = match err {
+            OrderError::Deserialization(_)
+            | OrderError::Decode(_)
+            | OrderError::UnsupportedMediaType(_)
+            | OrderError::Validation { .. } => Code::InvalidArgument,
+            OrderError::Conflict { .. } => Code::Aborted,
+            OrderError::Unprocessable { .. } => Code::FailedPrecondition,
+            OrderError::NotFound { .. } => Code::NotFound,
+            OrderError::Unauthorized(_) => Code::Unauthenticated,
+            OrderError::Forbidden(_) => Code::PermissionDenied,
+            OrderError::TooManyRequests { .. } | OrderError::PayloadTooLarge(_) => Code::ResourceExhausted,
+            OrderError::Timeout => Code::DeadlineExceeded,
+            OrderError::Database(_) if status == axum::http::StatusCode::CONFLICT => Code::AlreadyExists,
+            OrderError::Database(_) | OrderError::Internal(_) => Code::Internal,
+        };
+        let mut status = Status::new(code, message);
+        if let Ok(field) = MetadataValue::try_from(field.as_str()) {
+            if !field.is_empty() {
+                status.metadata_mut().insert("x-error-field", field);
+            }
+        }
+        status
+    }
+}
+
+impl TryFrom<proto::Order> for Order {
+    type Error = OrderError;
+
+    fn try_from(order: proto::Order) -> Result<Self, Self::Error> {
+        let delivery = order.delivery.ok_or_else(|| missing_field("delivery"))?;
+        let payment = order.payment.ok_or_else(|| missing_field("payment"))?;
+        Ok(Order {
+            order_uid: order.order_uid,
+            track_number: order.track_number,
+            entry: order.entry,
+            delivery: Delivery {
+                name: delivery.name,
+                phone: delivery.phone,
+                zip: delivery.zip,
+                city: delivery.city,
+                address: delivery.address,
+                region: delivery.region,
+                email: delivery.email,
+            },
+            payment: Payment {
+                transaction: payment.transaction,
+                request_id: payment.request_id,
+                currency: payment.currency,
+                provider: payment.provider,
+                amount: payment.amount,
+                payment_dt: payment.payment_dt,
+                bank: payment.bank,
+                delivery_cost: payment.delivery_cost,
+                goods_total: payment.goods_total,
+                custom_fee: payment.custom_fee,
+            },
+            items: order
+                .items
+                .into_iter()
+                .map(|item| Item {
+                    chrt_id: item.chrt_id,
+                    track_number: item.track_number,
+                    price: item.price,
+                    rid: item.rid,
+                    name: item.name,
+                    sale: item.sale,
+                    size: item.size,
+                    total_price: item.total_price,
+                    nm_id: item.nm_id,
+                    brand: item.brand,
+                    status: item.status,
+                })
+                .collect(),
+            delivery_service: order.delivery_service,
+            customer_id: order.customer_id,
+            shardkey: order.shardkey,
+            sm_id: order.sm_id,
+            date_created: order.date_created,
+            oof_shard: order.oof_shard,
+        })
+    }
+}
+
+impl From<&Order> for proto::Order {
+    fn from(order: &Order) -> Self {
+        let delivery = &order.delivery;
+        let payment = &order.payment;
+        proto::Order {
+            order_uid: order.order_uid.clone(),
+            track_number: order.track_number.clone(),
+            entry: order.entry.clone(),
+            delivery: Some(proto::Delivery {
+                name: delivery.name.clone(),
+                phone: delivery.phone.clone(),
+                zip: delivery.zip.clone(),
+                city: delivery.city.clone(),
+                address: delivery.address.clone(),
+                region: delivery.region.clone(),
+                email: delivery.email.clone(),
+            }),
+            payment: Some(proto::Payment {
+                transaction: payment.transaction.clone(),
+                request_id: payment.request_id.clone(),
+                currency: payment.currency.clone(),
+                provider: payment.provider.clone(),
+                amount: payment.amount,
+                payment_dt: payment.payment_dt,
+                bank: payment.bank.clone(),
+                delivery_cost: payment.delivery_cost,
+                goods_total: payment.goods_total,
+                custom_fee: payment.custom_fee,
+            }),
+            items: order
+                .items
+                .iter()
+                .map(|item| proto::Item {
+                    chrt_id: item.chrt_id,
+                    track_number: item.track_number.clone(),
+                    price: item.price,
+                    rid: item.rid.clone(),
+                    name: item.name.clone(),
+                    sale: item.sale,
+                    size: item.size.clone(),
+                    total_price: item.total_price,
+                    nm_id: item.nm_id,
+                    brand: item.brand.clone(),
+                    status: item.status,
+                })
+                .collect(),
+            delivery_service: order.delivery_service.clone(),
+            customer_id: order.customer_id.clone(),
+            shardkey: order.shardkey.clone(),
+            sm_id: order.sm_id,
+            date_created: order.date_created.clone(),
+            oof_shard: order.oof_shard.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::Db, jwt::JwtKeys};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const SECRET: &str = "test-secret-test-secret-test-secret";
+
+    // Клиент базы после рукопожатия с фейковым сервером: запросы он не выполнит,
+    // но без ключа и с JWT до базы дело не доходит
+    async fn offline_db() -> Db {
+        let (client_side, mut server_side) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            // startup сообщение: длина вместе с самим полем длины и параметры
+            let len = server_side.read_i32().await.unwrap();
+            let mut startup = vec![0; usize::try_from(len).unwrap() - 4];
+            server_side.read_exact(&mut startup).await.unwrap();
+            // AuthenticationOk и ReadyForQuery
+            server_side.write_all(b"R\0\0\0\x08\0\0\0\0Z\0\0\0\x05I").await.unwrap();
+            // держим соединение пока клиент жив
+            let _ = server_side.read_to_end(&mut Vec::new()).await;
+        });
+        let (client, connection) = tokio_postgres::Config::new()
+            .user("test")
+            .connect_raw(client_side, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        Db::new(client)
+    }
+
+    // Внутренний сервис отвечает 200 и отдает subject пропущенного Principal в заголовке
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<http::Request<BoxBody>> for Echo {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+            let subject = req.extensions().get::<Principal>().map(|p| p.subject.clone()).unwrap_or_default();
+            Box::pin(async move {
+                let mut response = http::Response::new(tonic::body::empty_body());
+                response.headers_mut().insert("x-subject", subject.parse().unwrap());
+                Ok(response)
+            })
+        }
+    }
+
+    async fn call(method: &str, token: Option<&str>) -> http::Response<BoxBody> {
+        let mut service = Authenticated {
+            auth: Auth::new(offline_db().await, Some(JwtKeys::hs256(SECRET))),
+            limits: RateLimits::from_env(),
+            inner: Echo,
+        };
+        let mut request = http::Request::new(tonic::body::empty_body());
+        *request.uri_mut() = format!("/orders.OrderService/{method}").parse().unwrap();
+        if let Some(token) = token {
+            request
+                .headers_mut()
+                .insert("authorization", format!("Bearer {token}").parse().unwrap());
+        }
+        service.call(request).await.unwrap()
+    }
+
+    fn token(claims: &serde_json::Value) -> String {
+        let mut claims = claims.clone();
+        claims["exp"] = json!(chrono::Utc::now().timestamp() + 3600);
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    fn code(response: &http::Response<BoxBody>) -> Option<Code> {
+        Status::from_header_map(response.headers()).map(|status| status.code())
+    }
+
+    #[test]
+    fn methods_need_scopes_like_http_routes() {
+        assert_eq!(method_scope("/orders.OrderService/CreateOrder"), (Scope::OrdersWrite, true));
+        assert_eq!(method_scope("/orders.OrderService/GetOrder"), (Scope::OrdersRead, false));
+        assert_eq!(method_scope("/orders.OrderService/ListOrders"), (Scope::OrdersRead, true));
+        assert_eq!(method_scope("/orders.OrderService/WatchOrders"), (Scope::OrdersRead, true));
+        // неизвестный метод - самые строгие права на чтение
+        assert_eq!(method_scope("/orders.OrderService/Unknown"), (Scope::OrdersRead, true));
+    }
+
+    #[tokio::test]
+    async fn missing_credentials_are_unauthenticated() {
+        let response = call("GetOrder", None).await;
+        assert_eq!(code(&response), Some(Code::Unauthenticated));
+        assert!(response.headers().get("x-subject").is_none());
+        let response = call("GetOrder", Some("not-a-token")).await;
+        assert_eq!(code(&response), Some(Code::Unauthenticated));
+    }
+
+    #[tokio::test]
+    async fn insufficient_credentials_are_denied() {
+        let customer = token(&json!({"sub": "u1", "roles": ["customer"], "customer_id": "c1"}));
+        assert_eq!(code(&call("ListOrders", Some(&customer)).await), Some(Code::PermissionDenied));
+        assert_eq!(code(&call("CreateOrder", Some(&customer)).await), Some(Code::PermissionDenied));
+        let nobody = token(&json!({"sub": "u2", "roles": ["unknown"]}));
+        assert_eq!(code(&call("GetOrder", Some(&nobody)).await), Some(Code::PermissionDenied));
+
+        // свой GetOrder покупателю можно, сотруднику можно все
+        let response = call("GetOrder", Some(&customer)).await;
+        assert_eq!(code(&response), None);
+        assert_eq!(response.headers()["x-subject"], "u1");
+        let operator = token(&json!({"sub": "u3", "roles": ["operator"]}));
+        assert_eq!(code(&call("CreateOrder", Some(&operator)).await), None);
+    }
+
+    #[test]
+    fn order_errors_map_to_grpc_codes() {
+        let field = || "order_uid".to_string();
+        let cases = [
+            (OrderError::Validation { msg: "order_uid is empty".to_string(), field: field() }, Code::InvalidArgument),
+            (OrderError::Decode("bad cbor".to_string()), Code::InvalidArgument),
+            (OrderError::UnsupportedMediaType("text/plain".to_string()), Code::InvalidArgument),
+            (OrderError::order_not_found(), Code::NotFound),
+            (OrderError::Unauthorized("no key".to_string()), Code::Unauthenticated),
+            (OrderError::Forbidden("no scope".to_string()), Code::PermissionDenied),
+            (OrderError::Conflict { msg: "in progress".to_string(), field: field() }, Code::Aborted),
+            (OrderError::Unprocessable { msg: "other body".to_string(), field: field() }, Code::FailedPrecondition),
+            (OrderError::TooManyRequests { msg: "slow down".to_string(), retry_after: 1 }, Code::ResourceExhausted),
+            (OrderError::PayloadTooLarge("too big".to_string()), Code::ResourceExhausted),
+            (OrderError::Timeout, Code::DeadlineExceeded),
+            (OrderError::Internal("boom".to_string()), Code::Internal),
+        ];
+        for (err, expected) in cases {
+            let kind = err.kind();
+            assert_eq!(Status::from(err).code(), expected, "{kind}");
+        }
+
+        let status = Status::from(missing_field("payment"));
+        assert_eq!(status.message(), "payment is required");
+        assert_eq!(status.metadata().get("x-error-field").unwrap(), "payment");
+        assert!(Status::from(OrderError::Timeout).metadata().get("x-error-field").is_none());
+    }
+}
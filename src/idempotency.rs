@@ -0,0 +1,299 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::time::{interval, Duration};
+
+use crate::{
+    auth::Principal, db::Db, negotiation::Format, order_errors::OrderError, order_impl::db_timeout, request_id,
+    tenant::Tenant,
+};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+// ответ который отдан из сохраненного, а не выполнен заново
+const REPLAYED: &str = "idempotent-replayed";
+// Ключи хранятся сутки, повтор позже выполнится как новый запрос
+const KEY_TTL_HOURS: i32 = 24;
+// Просроченные ключи удаляет фоновая задача, запросы на это не тратятся
+const CLEANUP_INTERVAL: Duration = Duration::from_mins(10);
+// Если первый запрос так и не записал ответ за это время (процесс упал), ключ можно занять заново
+const STALE_SECS: f64 = 60.0;
+const MAX_KEY_LEN: usize = 255;
+// как у axum по умолчанию для остальных маршрутов
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+// Повтор запроса с тем же Idempotency-Key и тем же телом получает сохраненный первый ответ,
+// с тем же ключом но другим телом - 422, пока первый еще выполняется - 409.
+// Ответы 5xx и таймауты не сохраняются, такой запрос можно повторить с тем же ключом.
+// Ключи у каждого арендатора свои
+pub async fn idempotent(
+    State(db): State<Db>,
+    req: Request,
+    next: Next,
+) -> Result<Response, OrderError> {
+    let Some(key) = idempotency_key(req.headers())? else {
+        return Ok(next.run(req).await);
+    };
+    let tenant = Tenant::resolve(req.extensions().get::<Principal>(), req.headers())?;
+
+    let (parts, body) = req.into_parts();
+    let body = to_bytes(body, MAX_BODY)
+        .await
+        .map_err(|e| OrderError::Decode(e.to_string()))?;
+    let request_hash = request_hash(&parts.headers, &body);
+
+    if let Some(response) = begin(&db, &tenant, &key, &request_hash).await? {
+        info!("Replaying stored response for Idempotency-Key {key}");
+        return Ok(response);
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            forget(&db, &tenant, &key).await;
+            return Err(OrderError::Internal(format!("Failed to read response body: {e}")));
+        }
+    };
+
+    if parts.status.is_server_error() || parts.status == StatusCode::REQUEST_TIMEOUT {
+        forget(&db, &tenant, &key).await;
+    } else {
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let client = db.lock().await;
+        let stored = db_timeout(
+            "store idempotent response",
+            client.execute(
+                "UPDATE idempotency_keys SET status_code = $2, content_type = $3, response_body = $4
+                WHERE tenant_id = $5 AND key = $1",
+                &[&key, &i32::from(parts.status.as_u16()), &content_type, &body.as_ref(), &tenant.as_str()],
+            ),
+        )
+        .await;
+        // запрос уже выполнен, клиенту отдаем его ответ даже если сохранить не вышло
+        if let Err(e) = stored {
+            error!("Failed to store response for Idempotency-Key {key}: {e}");
+        }
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, OrderError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    let key = value.to_str().unwrap_or_default().trim();
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(OrderError::Validation {
+            msg: format!("Idempotency-Key must be 1 to {MAX_KEY_LEN} visible ASCII characters"),
+            field: "Idempotency-Key".to_string(),
+        });
+    }
+    Ok(Some(key.to_string()))
+}
+
+// Одно и то же тело в разных форматах - разные запросы
+fn request_hash(headers: &HeaderMap, body: &[u8]) -> String {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .map(HeaderValue::as_bytes)
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(content_type);
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+// Занимает ключ. None - ключ наш и запрос нужно выполнить, Some - готовый ответ для повтора
+async fn begin(
+    db: &Db,
+    tenant: &Tenant,
+    key: &str,
+    request_hash: &str,
+) -> Result<Option<Response>, OrderError> {
+    let client = db.lock().await;
+    // ключ старше суток, который фоновая чистка еще не удалила, занимается заново как свободный
+    let inserted = db_timeout(
+        "insert idempotency key",
+        client.execute(
+            "INSERT INTO idempotency_keys (tenant_id, key, request_hash) VALUES ($3, $1, $2)
+            ON CONFLICT (tenant_id, key) DO UPDATE
+                SET request_hash = EXCLUDED.request_hash, status_code = NULL, content_type = NULL,
+                    response_body = NULL, created_at = now()
+                WHERE idempotency_keys.created_at < now() - make_interval(hours => $4)",
+            &[&key, &request_hash, &tenant.as_str(), &KEY_TTL_HOURS],
+        ),
+    )
+    .await?;
+    if inserted == 1 {
+        return Ok(None);
+    }
+
+    let row = db_timeout(
+        "query idempotency key",
+        client.query_opt(
+            "SELECT request_hash, status_code, content_type, response_body,
+                created_at < now() - make_interval(secs => $2) AS stale
+            FROM idempotency_keys WHERE tenant_id = $3 AND key = $1",
+            &[&key, &STALE_SECS, &tenant.as_str()],
+        ),
+    )
+    .await?;
+    // ключ только что удалили (первый запрос упал с 5xx), пусть клиент повторит
+    let Some(row) = row else {
+        return Err(in_progress());
+    };
+
+    if row.get::<_, String>("request_hash") != request_hash {
+        return Err(OrderError::Unprocessable {
+            msg: "Idempotency-Key was already used with a different request".to_string(),
+            field: "Idempotency-Key".to_string(),
+        });
+    }
+
+    let Some(status_code) = row.get::<_, Option<i32>>("status_code") else {
+        if !row.get::<_, bool>("stale") {
+            return Err(in_progress());
+        }
+        // первый запрос так и не закончился, занимаем ключ заново если никто не успел раньше
+        let taken = db_timeout(
+            "take over idempotency key",
+            client.execute(
+                "UPDATE idempotency_keys SET created_at = now()
+                WHERE tenant_id = $3 AND key = $1 AND status_code IS NULL
+                    AND created_at < now() - make_interval(secs => $2)",
+                &[&key, &STALE_SECS, &tenant.as_str()],
+            ),
+        )
+        .await?;
+        return if taken == 1 { Ok(None) } else { Err(in_progress()) };
+    };
+
+    let status = u16::try_from(status_code)
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let content_type: Option<String> = row.get("content_type");
+    let body: Vec<u8> = row.get::<_, Option<Vec<u8>>>("response_body").unwrap_or_default();
+    let body = with_current_request_id(content_type.as_deref(), body);
+    let mut response = (status, body).into_response();
+    if let Some(content_type) = content_type.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+    }
+    response.headers_mut().insert(REPLAYED, HeaderValue::from_static("true"));
+    Ok(Some(response))
+}
+
+// В сохраненном ответе с ошибкой лежит request_id первого запроса, повтор получает в нем свой,
+// иначе по id из ответа в логах нашелся бы чужой запрос. Остальные ответы отдаются как есть
+fn with_current_request_id(content_type: Option<&str>, body: Vec<u8>) -> Vec<u8> {
+    let Some(request_id) = request_id::current() else {
+        return body;
+    };
+    let media_type = content_type.and_then(|value| value.split(';').next());
+    let Some(format) = media_type.and_then(Format::from_media_type) else {
+        return body;
+    };
+    let Ok(mut value) = format.decode::<Value>(&body) else {
+        return body;
+    };
+    match value.get_mut("request_id") {
+        Some(stored) => *stored = json!(request_id),
+        None => return body,
+    }
+    format.encode(&value).unwrap_or(body)
+}
+
+// Фоновая чистка просроченных ключей раз в CLEANUP_INTERVAL
+pub fn spawn_cleanup(db: Db) {
+    tokio::spawn(async move {
+        let mut tick = interval(CLEANUP_INTERVAL);
+        loop {
+            tick.tick().await;
+            let client = db.lock().await;
+            let deleted = db_timeout(
+                "remove expired idempotency keys",
+                client.execute(
+                    "DELETE FROM idempotency_keys WHERE created_at < now() - make_interval(hours => $1)",
+                    &[&KEY_TTL_HOURS],
+                ),
+            )
+            .await;
+            match deleted {
+                Ok(0) => {}
+                Ok(deleted) => debug!("Removed {deleted} expired idempotency keys"),
+                Err(e) => error!("Failed to remove expired idempotency keys: {e}"),
+            }
+        }
+    });
+}
+
+// Освобождает ключ, следующий запрос с ним выполнится заново
+async fn forget(db: &Db, tenant: &Tenant, key: &str) {
+    let client = db.lock().await;
+    if let Err(e) = db_timeout(
+        "remove idempotency key",
+        client.execute(
+            "DELETE FROM idempotency_keys WHERE tenant_id = $2 AND key = $1",
+            &[&key, &tenant.as_str()],
+        ),
+    )
+    .await
+    {
+        error!("Failed to release Idempotency-Key {key}: {e}");
+    }
+}
+
+fn in_progress() -> OrderError {
+    OrderError::Conflict {
+        msg: "A request with this Idempotency-Key is still in progress".to_string(),
+        field: "Idempotency-Key".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id::REQUEST_ID;
+
+    #[tokio::test]
+    async fn replayed_error_gets_the_current_request_id() {
+        let stored = Format::Json.encode(&json!({"error": "x", "request_id": "first"})).unwrap();
+        let body = REQUEST_ID
+            .scope("second".to_string(), async { with_current_request_id(Some("application/json"), stored) })
+            .await;
+        let body: Value = Format::Json.decode(&body).unwrap();
+        assert_eq!(body["request_id"], "second");
+        assert_eq!(body["error"], "x");
+
+        let stored = Format::MsgPack.encode(&json!({"request_id": "first"})).unwrap();
+        let body = REQUEST_ID
+            .scope("second".to_string(), async {
+                with_current_request_id(Some("application/msgpack; charset=utf-8"), stored)
+            })
+            .await;
+        assert_eq!(Format::MsgPack.decode::<Value>(&body).unwrap()["request_id"], "second");
+    }
+
+    #[tokio::test]
+    async fn other_bodies_are_replayed_as_is() {
+        let order = br#"{"order_uid":"b563"}"#.to_vec();
+        let body = REQUEST_ID
+            .scope("second".to_string(), async { with_current_request_id(Some("application/json"), order.clone()) })
+            .await;
+        assert_eq!(body, order);
+        assert_eq!(with_current_request_id(Some("text/plain"), b"ok".to_vec()), b"ok");
+    }
+}
@@ -0,0 +1,280 @@
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    cli::ImportArgs,
+    config::CustomerPolicy,
+    get_db,
+    models::{BulkOrderResult, BulkStatus},
+    order_bulk::{insert_batch, parse_values, ParsedOrder},
+    order_errors::OrderError,
+    statements::StatementCache,
+    tenant::Tenant,
+};
+
+// Заказ из файла вместе с тем откуда он взят, исходный JSON нужен чтобы записать его в rejects
+struct Entry {
+    source: String,
+    value: Result<Value, String>,
+}
+
+// Что приходит в сборщик итогов: результат по заказу или файл который не удалось прочитать
+enum Outcome {
+    Order(Entry, BulkOrderResult),
+    Unreadable { source: String, error: String },
+}
+
+#[derive(Default)]
+struct Summary {
+    created: usize,
+    duplicate: usize,
+    invalid: usize,
+    failed: usize,
+    unreadable: usize,
+}
+
+impl Summary {
+    // Считает результат, все кроме созданных заказов пишется в rejects если он задан
+    fn add(&mut self, outcome: Outcome, rejects: Option<&mut impl Write>) -> io::Result<()> {
+        let line = match outcome {
+            Outcome::Unreadable { source, error } => {
+                self.unreadable += 1;
+                json!({
+                    "source": source,
+                    "status": "unreadable",
+                    "message": format!("Failed to read file: {error}"),
+                })
+            }
+            Outcome::Order(entry, result) => {
+                match result.status {
+                    BulkStatus::Created => {
+                        self.created += 1;
+                        return Ok(());
+                    }
+                    BulkStatus::Conflict => self.duplicate += 1,
+                    BulkStatus::Invalid => self.invalid += 1,
+                    BulkStatus::Failed | BulkStatus::RolledBack | BulkStatus::Skipped => self.failed += 1,
+                }
+                json!({
+                    "source": entry.source,
+                    "order_uid": result.order_uid,
+                    "status": result.status,
+                    "message": result.message,
+                    "field": result.field,
+                    "order": entry.value.ok(),
+                })
+            }
+        };
+        match rejects {
+            Some(rejects) => writeln!(rejects, "{line}"),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn run(args: ImportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let files = collect_files(&args.path)?;
+    let tenant = Tenant::from_arg(args.tenant.as_deref())?;
+    let policy = CustomerPolicy::from_env();
+    let concurrency = args.concurrency.max(1);
+    let batch_size = args.batch_size.max(1);
+    info!(
+        "Importing orders from {} files for tenant {tenant}, concurrency {concurrency}, batch size {batch_size}",
+        files.len()
+    );
+
+    // каждый воркер держит свое соединение и свой кэш запросов, пачки разбирают из общей очереди
+    let (batch_tx, batch_rx) = mpsc::channel::<Vec<Entry>>(concurrency * 2);
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    let (result_tx, mut result_rx) = mpsc::channel::<Outcome>(batch_size * concurrency);
+
+    let mut workers = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        let client = get_db().await?;
+        workers.push(tokio::spawn(worker(client, batch_rx.clone(), result_tx.clone(), tenant.clone(), policy)));
+    }
+
+    // непрочитанный файл попадает в итоги и в rejects, остальные файлы импортируются дальше
+    let unreadable = result_tx;
+    let producer = tokio::spawn(async move {
+        for file in files {
+            let entries = match read_entries(&file).map_err(|e| e.to_string()) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    error!("Failed to read {}: {}", file.display(), error);
+                    let outcome = Outcome::Unreadable {
+                        source: file.display().to_string(),
+                        error,
+                    };
+                    if unreadable.send(outcome).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            info!("{}: {} orders", file.display(), entries.len());
+            let mut entries = entries.into_iter().peekable();
+            while entries.peek().is_some() {
+                let batch: Vec<Entry> = entries.by_ref().take(batch_size).collect();
+                if batch_tx.send(batch).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut rejects = match &args.rejects {
+        Some(path) => Some(io::BufWriter::new(fs::File::create(path)?)),
+        None => None,
+    };
+
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    while let Some(outcome) = result_rx.recv().await {
+        summary.add(outcome, rejects.as_mut())?;
+    }
+
+    producer.await?;
+    for worker in workers {
+        worker.await?;
+    }
+    if let Some(mut rejects) = rejects {
+        rejects.flush()?;
+    }
+
+    let total = summary.created + summary.duplicate + summary.invalid + summary.failed;
+    let elapsed = started.elapsed().as_secs_f64();
+    println!("Processed: {total} orders in {elapsed:.3}s");
+    println!("Created:   {}", summary.created);
+    println!("Duplicate: {}", summary.duplicate);
+    println!("Invalid:   {}", summary.invalid);
+    println!("Failed:    {}", summary.failed);
+    if summary.unreadable > 0 {
+        println!("Unreadable files: {}", summary.unreadable);
+    }
+    if let Some(path) = &args.rejects {
+        println!("Rejects written to {}", path.display());
+    }
+    Ok(())
+}
+
+// Пачка вставляется тем же insert_batch что и POST /orders/bulk в режиме best_effort
+async fn worker(
+    mut client: tokio_postgres::Client,
+    batches: Arc<Mutex<mpsc::Receiver<Vec<Entry>>>>,
+    results: mpsc::Sender<Outcome>,
+    tenant: Tenant,
+    policy: CustomerPolicy,
+) {
+    let statements = StatementCache::default();
+    loop {
+        let Some(batch) = batches.lock().await.recv().await else {
+            return;
+        };
+
+        let orders: Vec<ParsedOrder> = batch
+            .iter()
+            .map(|entry| match &entry.value {
+                Ok(value) => ParsedOrder::from_value(value.clone()),
+                Err(msg) => ParsedOrder {
+                    order_uid: None,
+                    order: Err(OrderError::Validation {
+                        msg: msg.clone(),
+                        field: String::new(),
+                    }),
+                },
+            })
+            .collect();
+
+        let batch_results = match client.transaction().await {
+            Ok(mut tx) => {
+                let outcome = insert_batch(&mut tx, &statements, &tenant, orders, policy).await;
+                match tx.commit().await {
+                    Ok(()) => outcome.results,
+                    Err(e) => failed_batch(&batch, &OrderError::from(e)),
+                }
+            }
+            Err(e) => failed_batch(&batch, &OrderError::from(e)),
+        };
+
+        for (entry, result) in batch.into_iter().zip(batch_results) {
+            if results.send(Outcome::Order(entry, result)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Вся пачка упала на начале или комите транзакции, order_uid берем из исходного JSON
+fn failed_batch(batch: &[Entry], err: &OrderError) -> Vec<BulkOrderResult> {
+    batch
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let order_uid = entry
+                .value
+                .as_ref()
+                .ok()
+                .and_then(|value| value.get("order_uid"))
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            BulkOrderResult::from_error(index, order_uid, err)
+        })
+        .collect()
+}
+
+// Файл, директория (берутся .json, .ndjson и .jsonl) или "-" для stdin
+fn collect_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(path)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| matches!(ext, "json" | "ndjson" | "jsonl"))
+        })
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        warn!("No .json/.ndjson/.jsonl files in {}", path.display());
+    }
+    Ok(files)
+}
+
+// Формат определяю по содержимому: JSON массив, один JSON объект, иначе NDJSON
+fn read_entries(path: &Path) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let mut body = Vec::new();
+    if path == Path::new("-") {
+        io::stdin().read_to_end(&mut body)?;
+    } else {
+        body = fs::read(path)?;
+    }
+
+    let values = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(values)) => values.into_iter().map(Ok).collect(),
+        Ok(value @ Value::Object(_)) => vec![Ok(value)],
+        _ => parse_values(&body, true)?,
+    };
+
+    Ok(values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| Entry {
+            source: format!("{}#{index}", path.display()),
+            value: value.map_err(|e| format!("Deserialization error: {e}")),
+        })
+        .collect())
+}
@@ -0,0 +1,194 @@
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde::Deserialize;
+use std::{collections::HashMap, fs};
+
+use crate::{config, order_errors::OrderError};
+
+// Клеймы которые выдает gateway, остальные игнорируются
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    // sub уникален только у своего издателя
+    pub iss: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub tenant_id: Option<String>,
+    // для роли customer: чьи ордера можно читать
+    pub customer_id: Option<String>,
+}
+
+// Ключи для проверки bearer токенов: общий секрет для HS256 и публичные ключи RS256 из JWKS файла
+pub struct JwtKeys {
+    hs256: Option<DecodingKey>,
+    // по kid из заголовка токена
+    rs256: HashMap<String, DecodingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtKeys {
+    // None если не задан ни JWT_SECRET ни JWT_JWKS_FILE, тогда bearer токены не принимаются.
+    // Кривой JWKS файл - ошибка запуска, иначе все токены молча отклонялись бы
+    pub fn from_env() -> Result<Option<Self>, OrderError> {
+        let secret = config::jwt_secret();
+        let jwks_file = config::jwt_jwks_file();
+        if secret.is_none() && jwks_file.is_none() {
+            return Ok(None);
+        }
+
+        let hs256 = secret.map(|secret| {
+            if secret.len() < 32 {
+                warn!("JWT_SECRET is shorter than 32 bytes");
+            }
+            DecodingKey::from_secret(secret.as_bytes())
+        });
+
+        let mut rs256 = HashMap::new();
+        if let Some(path) = jwks_file {
+            let jwks: JwkSet = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+                .map_err(|e| OrderError::Internal(format!("Failed to read JWKS file {path}: {e}")))?;
+            for jwk in &jwks.keys {
+                let Some(kid) = jwk.common.key_id.clone() else {
+                    warn!("Skipping JWKS key without kid in {path}");
+                    continue;
+                };
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|e| OrderError::Internal(format!("Bad JWKS key {kid} in {path}: {e}")))?;
+                rs256.insert(kid, key);
+            }
+            info!("Loaded {} JWKS keys from {path}", rs256.len());
+        }
+
+        Ok(Some(JwtKeys {
+            hs256,
+            rs256,
+            issuer: config::jwt_issuer(),
+            audience: config::jwt_audience(),
+        }))
+    }
+
+    // Только HS256 с этим секретом, без iss и aud, для тестов проверки учетных данных
+    #[cfg(test)]
+    pub fn hs256(secret: &str) -> Self {
+        JwtKeys {
+            hs256: Some(DecodingKey::from_secret(secret.as_bytes())),
+            rs256: HashMap::new(),
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    // Проверяет подпись, срок действия (exp) и, если заданы, iss и aud
+    pub fn verify(&self, token: &str) -> Result<Claims, OrderError> {
+        let header = decode_header(token).map_err(|e| invalid(&e))?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref(),
+            Algorithm::RS256 => match header.kid.as_deref() {
+                Some(kid) => self.rs256.get(kid),
+                // без kid подходит только единственный ключ
+                None if self.rs256.len() == 1 => self.rs256.values().next(),
+                None => None,
+            },
+            _ => None,
+        };
+        let Some(key) = key else {
+            return Err(OrderError::Unauthorized(format!(
+                "No key to verify {:?} token{}",
+                header.alg,
+                header.kid.map(|kid| format!(" with kid {kid}")).unwrap_or_default()
+            )));
+        };
+
+        let mut validation = Validation::new(header.alg);
+        // без required_spec_claims jsonwebtoken проверяет iss и aud только если они есть в токене
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+        decode::<Claims>(token, key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| invalid(&e))
+    }
+}
+
+fn invalid(error: &jsonwebtoken::errors::Error) -> OrderError {
+    OrderError::Unauthorized(format!("Invalid bearer token: {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    const SECRET: &str = "test-secret-test-secret-test-secret";
+
+    fn keys(issuer: Option<&str>, audience: Option<&str>) -> JwtKeys {
+        JwtKeys {
+            issuer: issuer.map(str::to_string),
+            audience: audience.map(str::to_string),
+            ..JwtKeys::hs256(SECRET)
+        }
+    }
+
+    fn token(secret: &str, mut claims: Value) -> String {
+        if claims.get("exp").is_none() {
+            claims["exp"] = json!(chrono::Utc::now().timestamp() + 3600);
+        }
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn valid_token_gives_claims() {
+        let token = token(SECRET, json!({"sub": "u1", "roles": ["customer"], "customer_id": "c1", "tenant_id": "t1"}));
+        let claims = keys(None, None).verify(&token).unwrap();
+        assert_eq!(claims.sub, "u1");
+        assert_eq!(claims.roles, ["customer"]);
+        assert_eq!(claims.customer_id.as_deref(), Some("c1"));
+        assert_eq!(claims.tenant_id.as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn expired_or_foreign_token_is_rejected() {
+        let expired = token(SECRET, json!({"sub": "u1", "exp": chrono::Utc::now().timestamp() - 3600}));
+        assert!(matches!(keys(None, None).verify(&expired), Err(OrderError::Unauthorized(_))));
+        let foreign = token("another-secret-another-secret-another", json!({"sub": "u1"}));
+        assert!(matches!(keys(None, None).verify(&foreign), Err(OrderError::Unauthorized(_))));
+        assert!(keys(None, None).verify("not-a-token").is_err());
+    }
+
+    #[test]
+    fn issuer_and_audience_are_checked_when_configured() {
+        let keys = keys(Some("gateway"), Some("orders"));
+        let good = token(SECRET, json!({"sub": "u1", "iss": "gateway", "aud": "orders"}));
+        assert!(keys.verify(&good).is_ok());
+        let wrong_issuer = token(SECRET, json!({"sub": "u1", "iss": "other", "aud": "orders"}));
+        assert!(keys.verify(&wrong_issuer).is_err());
+        let wrong_audience = token(SECRET, json!({"sub": "u1", "iss": "gateway", "aud": "billing"}));
+        assert!(keys.verify(&wrong_audience).is_err());
+        let missing = token(SECRET, json!({"sub": "u1"}));
+        assert!(keys.verify(&missing).is_err());
+    }
+
+    #[test]
+    fn algorithm_without_configured_key_is_rejected() {
+        let token = token(SECRET, json!({"sub": "u1"}));
+        let keys = JwtKeys {
+            hs256: None,
+            ..keys(None, None)
+        };
+        assert!(matches!(keys.verify(&token), Err(OrderError::Unauthorized(_))));
+    }
+}
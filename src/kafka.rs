@@ -0,0 +1,240 @@
+use apache_avro::{to_avro_datum, Schema};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use rdkafka::{
+    config::ClientConfig,
+    error::{KafkaError, RDKafkaErrorCode},
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord, Producer},
+    util::Timeout,
+};
+use serde::Serialize;
+use std::str::FromStr;
+use tokio::{
+    sync::Mutex,
+    task,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config,
+    models::Order,
+    order_errors::OrderError,
+    order_events::{OrderEvent, OrderEventKind},
+    outbox::EventSink,
+};
+
+// Схема значения записи в формате avro, ее же стоит выложить потребителям
+pub const AVRO_SCHEMA: &str = include_str!("../schemas/order_event.avsc");
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+// Как часто перечитывать число партиций: после добавления партиций в топик
+// новые записи начнут расходиться по всем не позже чем через минуту
+const PARTITIONS_TTL: Duration = Duration::from_mins(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KafkaFormat {
+    Json,
+    Avro,
+}
+
+impl FromStr for KafkaFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(KafkaFormat::Json),
+            "avro" => Ok(KafkaFormat::Avro),
+            other => Err(format!("Unknown kafka format: {other}")),
+        }
+    }
+}
+
+// Значение записи: OrderCreated для нового заказа, OrderUpdated для изменений (что именно поменялось - в change)
+#[derive(Debug, Serialize)]
+struct OrderRecord<'a> {
+    event_id: i64,
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    change: &'static str,
+    chrt_id: Option<i64>,
+    status: Option<i32>,
+    tenant_id: &'a str,
+    order: &'a Order,
+}
+
+impl<'a> OrderRecord<'a> {
+    fn new(event: &'a OrderEvent) -> Self {
+        let status = match event.kind {
+            OrderEventKind::ItemStatusChanged { status, .. } => Some(status),
+            _ => None,
+        };
+        OrderRecord {
+            event_id: i64::try_from(event.id).unwrap_or(i64::MAX),
+            record_type: match event.kind {
+                OrderEventKind::Created => "OrderCreated",
+                _ => "OrderUpdated",
+            },
+            change: event.kind.as_str(),
+            chrt_id: event.kind.chrt_id(),
+            status,
+            tenant_id: event.tenant.as_str(),
+            order: &event.order,
+        }
+    }
+}
+
+// Получатель событий для outbox relay. Ключ записи - order_uid, партиция выбирается по shardkey,
+// так все события одного заказа попадают в одну партицию по порядку.
+// Продюсер идемпотентный (acks=all), повтор отправки внутри librdkafka не дает дублей,
+// но повтор события из outbox после сбоя - даст, потребитель узнает его по event_id
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+    format: KafkaFormat,
+    schema: Option<Schema>,
+    // число партиций топика и когда оно прочитано из метаданных
+    partitions: Mutex<Option<(i32, Instant)>>,
+}
+
+impl KafkaSink {
+    pub fn from_env() -> Result<Self, OrderError> {
+        let brokers = config::kafka_brokers()
+            .ok_or_else(|| OrderError::Internal("KAFKA_BROKERS is not set".to_string()))?;
+        let format: KafkaFormat = config::kafka_format().parse().map_err(OrderError::Internal)?;
+        let schema = match format {
+            KafkaFormat::Avro => Some(Schema::parse_str(AVRO_SCHEMA).map_err(kafka_error)?),
+            KafkaFormat::Json => None,
+        };
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .set("client.id", "orders-service")
+            .set("enable.idempotence", "true")
+            .set("message.timeout.ms", "30000")
+            .create()
+            .map_err(kafka_error)?;
+        let topic = config::kafka_topic();
+        info!("Kafka sink: brokers {brokers}, topic {topic}, format {format:?}");
+        Ok(KafkaSink { producer, topic, format, schema, partitions: Mutex::new(None) })
+    }
+
+    // Число партиций из кэша, раз в PARTITIONS_TTL и после сброса (forget_partitions) перечитывается.
+    // Если метаданные не прочитались, а старое значение есть - отправляем по нему
+    async fn partitions(&self) -> Result<i32, OrderError> {
+        let mut cached = self.partitions.lock().await;
+        if let Some((partitions, read_at)) = *cached {
+            if read_at.elapsed() < PARTITIONS_TTL {
+                return Ok(partitions);
+            }
+        }
+        match self.fetch_partitions().await {
+            Ok(partitions) => {
+                *cached = Some((partitions, Instant::now()));
+                Ok(partitions)
+            }
+            Err(e) => match *cached {
+                Some((partitions, _)) => {
+                    warn!("Failed to refresh kafka topic {} partitions, using {partitions}: {e}", self.topic);
+                    Ok(partitions)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    // fetch_metadata блокирующий, поэтому в spawn_blocking
+    async fn fetch_partitions(&self) -> Result<i32, OrderError> {
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        let metadata = task::spawn_blocking(move || {
+            producer.client().fetch_metadata(Some(&topic), Timeout::After(METADATA_TIMEOUT))
+        })
+        .await
+        .map_err(|e| OrderError::Internal(e.to_string()))?
+        .map_err(kafka_error)?;
+        let partitions = metadata
+            .topics()
+            .first()
+            .map_or(0, |topic| i32::try_from(topic.partitions().len()).unwrap_or(i32::MAX));
+        if partitions == 0 {
+            return Err(OrderError::Internal(format!("Kafka topic {} has no partitions", self.topic)));
+        }
+        debug!("Kafka topic {} has {partitions} partitions", self.topic);
+        Ok(partitions)
+    }
+
+    // Брокер не знает такой партиции или топика (топик пересоздали с другим числом партиций):
+    // кэш сбрасывается и при повторе события relay-ем число партиций прочитается заново
+    async fn forget_partitions(&self, e: &KafkaError) {
+        if matches!(
+            e.rdkafka_error_code(),
+            Some(RDKafkaErrorCode::UnknownPartition | RDKafkaErrorCode::UnknownTopicOrPartition | RDKafkaErrorCode::UnknownTopic)
+        ) {
+            warn!("Kafka topic {} metadata is stale: {e}", self.topic);
+            *self.partitions.lock().await = None;
+        }
+    }
+
+    fn encode(&self, record: &OrderRecord<'_>) -> Result<Vec<u8>, OrderError> {
+        match &self.schema {
+            Some(schema) => {
+                let value = apache_avro::to_value(record).map_err(kafka_error)?;
+                let value = value.resolve(schema).map_err(kafka_error)?;
+                to_avro_datum(schema, value).map_err(kafka_error)
+            }
+            None => serde_json::to_vec(record).map_err(|e| OrderError::Internal(e.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, event: &OrderEvent) -> Result<(), OrderError> {
+        let record = OrderRecord::new(event);
+        let payload = self.encode(&record)?;
+        let partition = partition(&event.order.shardkey, self.partitions().await?);
+        let event_id = record.event_id.to_string();
+        let headers = OwnedHeaders::new()
+            .insert(Header { key: "event-type", value: Some(record.record_type) })
+            .insert(Header { key: "event-id", value: Some(&event_id) })
+            .insert(Header { key: "tenant-id", value: Some(record.tenant_id) })
+            .insert(Header {
+                key: "content-type",
+                value: Some(match self.format {
+                    KafkaFormat::Json => "application/json",
+                    KafkaFormat::Avro => "avro/binary",
+                }),
+            });
+        let kafka_record = FutureRecord::to(&self.topic)
+            .key(&event.order.order_uid)
+            .partition(partition)
+            .payload(&payload)
+            .headers(headers);
+        if let Err((e, _)) = self.producer.send(kafka_record, Timeout::After(SEND_TIMEOUT)).await {
+            self.forget_partitions(&e).await;
+            return Err(kafka_error(e));
+        }
+        debug!("Order event {} sent to kafka partition {partition}", event.id);
+        Ok(())
+    }
+}
+
+// Партиция по shardkey: числовой ключ берется как есть, остальные через FNV-1a,
+// так у одного shardkey всегда одна партиция пока их число в топике не поменяется
+pub fn partition(shardkey: &str, partitions: i32) -> i32 {
+    let hash = shardkey.trim().parse::<u64>().unwrap_or_else(|_| {
+        shardkey.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    });
+    i32::try_from(hash % u64::try_from(partitions.max(1)).unwrap_or(1)).unwrap_or_default()
+}
+
+fn kafka_error(e: impl std::fmt::Display) -> OrderError {
+    OrderError::Internal(format!("Kafka: {e}"))
+}
@@ -0,0 +1,22 @@
+use log::warn;
+
+use crate::{
+    logging::{self, LogLevels, LogLevelsBody},
+    negotiation::Negotiated,
+    order_errors::OrderError,
+};
+
+// Уровни логов которые сейчас действуют в этом экземпляре сервиса
+pub async fn get_log_levels() -> Negotiated<LogLevelsBody> {
+    Negotiated(LogLevelsBody::from(&logging::levels()))
+}
+
+// Меняет уровни целиком (модули которых нет в запросе пишут по общему уровню), до перезапуска.
+// Другие экземпляры сервиса не затрагиваются
+pub async fn set_log_levels(Negotiated(body): Negotiated<LogLevelsBody>) -> Result<Negotiated<LogLevelsBody>, OrderError> {
+    let levels = LogLevels::try_from(body)?;
+    // warn чтобы смена была видна при любом уровне кроме error и off
+    warn!("Log levels changed from {} to {levels}", logging::levels());
+    logging::set_levels(levels);
+    Ok(Negotiated(LogLevelsBody::from(&logging::levels())))
+}
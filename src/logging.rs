@@ -0,0 +1,496 @@
+use chrono::{Local, SecondsFormat};
+use fern::Dispatch;
+use log::{Level, LevelFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{LazyLock, PoisonError, RwLock},
+};
+
+use crate::{config, order_errors::OrderError, redact::{self, Redaction}, request_id, telemetry};
+
+// Уровни которые сейчас действуют, фильтр логгера читает их на каждую запись,
+// так PUT /admin/log-level меняет их без перезапуска
+static LEVELS: LazyLock<RwLock<LogLevels>> = LazyLock::new(|| RwLock::new(LogLevels::default()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // как было: время, уровень, id запроса и сообщение одной строкой
+    Text,
+    // JSON объект на строку, для сборщиков логов
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format: {other}")),
+        }
+    }
+}
+
+// Когда начинать новый файл лога кроме превышения размера
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            other => Err(format!("Unknown log rotation: {other}")),
+        }
+    }
+}
+
+// Общий уровень и уровни для отдельных модулей (target записи, например tokio_postgres
+// или axum_study_project_1::outbox), для записи берется самый длинный подходящий модуль
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLevels {
+    pub level: LevelFilter,
+    pub modules: BTreeMap<String, LevelFilter>,
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        LogLevels { level: LevelFilter::Debug, modules: BTreeMap::new() }
+    }
+}
+
+impl LogLevels {
+    // Из LOG_LEVEL и LOG_MODULES вида "tokio_postgres=info,axum_study_project_1::outbox=trace"
+    pub fn parse(level: &str, modules: &str) -> Result<Self, OrderError> {
+        let modules = modules
+            .split(',')
+            .map(str::trim)
+            .filter(|module| !module.is_empty())
+            .map(|module| match module.split_once('=') {
+                Some((name, level)) if !name.trim().is_empty() => {
+                    Ok((name.trim().to_string(), parse_level(level, "modules")?))
+                }
+                _ => Err(OrderError::Validation {
+                    msg: format!("Module level must look like module=level, got {module}"),
+                    field: "modules".to_string(),
+                }),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(LogLevels { level: parse_level(level, "level")?, modules })
+    }
+
+    fn enabled(&self, target: &str, level: Level) -> bool {
+        let filter = self
+            .modules
+            .iter()
+            .filter(|(module, _)| {
+                target.strip_prefix(module.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |(_, filter)| *filter);
+        level <= filter
+    }
+
+    // Самый подробный из уровней, записи подробнее log отбрасывает еще до фильтра
+    fn max(&self) -> LevelFilter {
+        self.modules.values().copied().fold(self.level, Ord::max)
+    }
+}
+
+fn parse_level(level: &str, field: &str) -> Result<LevelFilter, OrderError> {
+    level.trim().parse().map_err(|_| OrderError::Validation {
+        msg: format!("Unknown log level: {}, expected off, error, warn, info, debug or trace", level.trim()),
+        field: field.to_string(),
+    })
+}
+
+impl fmt::Display for LogLevels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.level.as_str().to_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{module}={}", level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+// Уровни в запросе и ответе /admin/log-level
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevelsBody {
+    pub level: String,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+impl From<&LogLevels> for LogLevelsBody {
+    fn from(levels: &LogLevels) -> Self {
+        LogLevelsBody {
+            level: levels.level.as_str().to_lowercase(),
+            modules: levels
+                .modules
+                .iter()
+                .map(|(module, level)| (module.clone(), level.as_str().to_lowercase()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<LogLevelsBody> for LogLevels {
+    type Error = OrderError;
+
+    fn try_from(body: LogLevelsBody) -> Result<Self, Self::Error> {
+        let modules = body
+            .modules
+            .into_iter()
+            .map(|(module, level)| Ok((module, parse_level(&level, "modules")?)))
+            .collect::<Result<_, OrderError>>()?;
+        Ok(LogLevels { level: parse_level(&body.level, "level")?, modules })
+    }
+}
+
+pub fn levels() -> LogLevels {
+    LEVELS.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+pub fn set_levels(levels: LogLevels) {
+    let max = levels.max();
+    *LEVELS.write().unwrap_or_else(PoisonError::into_inner) = levels;
+    log::set_max_level(max);
+}
+
+// Настройка логгера из LOG_LEVEL, LOG_MODULES, LOG_FORMAT и LOG_FILE.
+// serve пишет в stdout, cli команды - в stderr (export может писать результат в stdout), в файл пишет только serve.
+// Записи сделанные внутри спана еще и добавляются в него событием, если включен трейсинг
+pub fn init(serve: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let levels = LogLevels::parse(&config::log_level(), &config::log_modules())?;
+    let format: LogFormat = config::log_format().parse()?;
+    let redaction = Redaction::from_env()?;
+
+    let dispatch = Dispatch::new()
+        .format(move |out, message, record| {
+            let redacted = redact::log_message(record.target(), message);
+            let message: &dyn fmt::Display = match &redacted {
+                Some(redacted) => redacted,
+                None => message,
+            };
+            match format {
+                LogFormat::Text => match request_id::current() {
+                    // записи сделанные во время обработки HTTP запроса помечаются его id
+                    Some(request_id) => out.finish(format_args!(
+                        "{} [{}] [{}] - {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        record.level(),
+                        request_id,
+                        message
+                    )),
+                    None => out.finish(format_args!(
+                        "{} [{}] - {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        record.level(),
+                        message
+                    )),
+                },
+                LogFormat::Json => {
+                    let mut entry = json!({
+                        "ts": Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": message.to_string(),
+                    });
+                    if let Some(request_id) = request_id::current() {
+                        entry["request_id"] = json!(request_id);
+                    }
+                    out.finish(format_args!("{entry}"));
+                }
+            }
+        });
+
+    let dispatch = if serve { dispatch.chain(io::stdout()) } else { dispatch.chain(io::stderr()) };
+    let dispatch = match config::log_file().filter(|_| serve) {
+        Some(path) => {
+            let file = RotatingFile::open(
+                PathBuf::from(path),
+                u64::from(config::log_rotate_size_mb()) * 1024 * 1024,
+                config::log_rotate().parse()?,
+                config::log_keep_files() as usize,
+            )?;
+            dispatch.chain(Box::new(file) as Box<dyn Write + Send>)
+        }
+        None => dispatch,
+    };
+    // форматирует только вложенный dispatch, в спан запись уходит без времени и уровня в тексте
+    Dispatch::new()
+        .filter(|metadata| {
+            LEVELS
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .enabled(metadata.target(), metadata.level())
+        })
+        .chain(dispatch)
+        .chain(fern::Output::call(telemetry::log_to_span))
+        .apply()?;
+    set_levels(levels);
+    redact::init(redaction);
+    Ok(())
+}
+
+// Файл лога который сам переименовывает себя в <файл>.<время> когда вырос больше max_size
+// или наступил новый час/день, и удаляет старые файлы сверх keep.
+// fern сбрасывает writer после каждой записи, так что новый файл начинается только между записями
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<fs::File>,
+    size: u64,
+    // 0 - без ограничения размера
+    max_size: u64,
+    rotation: Rotation,
+    period: String,
+    keep: usize,
+    // идет запись, начатая запись не переносится в новый файл
+    writing: bool,
+    // время в имени и номер последнего переименованного файла
+    last_rotated: (String, u32),
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, rotation: Rotation, keep: usize) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file: BufWriter::new(file),
+            size,
+            max_size,
+            rotation,
+            period: period(rotation),
+            keep,
+            writing: false,
+            last_rotated: (String::new(), 0),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let stamp = Local::now().format(".%Y%m%d-%H%M%S%3f").to_string();
+        // две ротации за одну миллисекунду (маленький LOG_ROTATE_SIZE_MB) не должны затирать друг друга.
+        // Суффикс -NNNN сортируется после имени без него, а с нулями впереди -0010 идет после -0002,
+        // по этому порядку remove_old удаляет старые. Номер только растет: занять номер файла который
+        // remove_old уже удалил значит поставить новый файл перед старыми
+        let mut n = if self.last_rotated.0 == stamp { self.last_rotated.1 + 1 } else { 0 };
+        let rotated = loop {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(&stamp);
+            if n > 0 {
+                rotated.push(format!("-{n:04}"));
+            }
+            if !Path::new(&rotated).exists() {
+                break rotated;
+            }
+            n += 1;
+        };
+        self.last_rotated = (stamp, n);
+        fs::rename(&self.path, &rotated)?;
+        self.file = BufWriter::new(fs::OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.size = 0;
+        self.remove_old();
+        Ok(())
+    }
+
+    // старые файлы сортируются по имени, время в имени идет по порядку
+    fn remove_old(&self) {
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return;
+        };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut rotated: Vec<PathBuf> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.keep);
+        for path in &rotated[..excess] {
+            // логировать отсюда нельзя, мы внутри логгера
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn period(rotation: Rotation) -> String {
+    match rotation {
+        Rotation::Never => String::new(),
+        Rotation::Hourly => Local::now().format("%Y%m%d%H").to_string(),
+        Rotation::Daily => Local::now().format("%Y%m%d").to_string(),
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writing {
+            self.writing = true;
+            let period = period(self.rotation);
+            if (self.max_size > 0 && self.size >= self.max_size) || period != self.period {
+                self.period = period;
+                self.rotate()?;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += u64::try_from(written).unwrap_or(u64::MAX);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writing = false;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_levels_and_modules() {
+        let levels = LogLevels::parse("Info", " tokio_postgres=warn, axum_study_project_1::outbox=trace ,").unwrap();
+        assert_eq!(levels.level, LevelFilter::Info);
+        assert_eq!(levels.modules["tokio_postgres"], LevelFilter::Warn);
+        assert_eq!(levels.to_string(), "info,axum_study_project_1::outbox=trace,tokio_postgres=warn");
+        assert_eq!(levels.max(), LevelFilter::Trace);
+        assert_eq!(LogLevels::parse("debug", "").unwrap(), LogLevels::default());
+
+        for (level, modules) in [("loud", ""), ("info", "tokio_postgres"), ("info", "=warn"), ("info", "a=loud")] {
+            assert!(
+                matches!(LogLevels::parse(level, modules), Err(OrderError::Validation { .. })),
+                "{level} {modules}"
+            );
+        }
+    }
+
+    #[test]
+    fn longest_matching_module_wins() {
+        let levels = LogLevels::parse("info", "axum_study_project_1=warn,axum_study_project_1::outbox=trace").unwrap();
+        assert!(levels.enabled("axum_study_project_1::outbox", Level::Trace));
+        assert!(levels.enabled("axum_study_project_1::outbox::relay", Level::Trace));
+        assert!(!levels.enabled("axum_study_project_1::order_handler", Level::Info));
+        assert!(levels.enabled("axum_study_project_1::order_handler", Level::Warn));
+        // модуль совпадает только целиком, outbox не включает outbox_extra
+        assert!(!levels.enabled("axum_study_project_1::outbox_extra", Level::Info));
+        assert!(levels.enabled("hyper", Level::Info));
+        assert!(!levels.enabled("hyper", Level::Debug));
+    }
+
+    // временная папка для файлов лога, удаляется в конце теста
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn record(file: &mut RotatingFile, parts: &[&str]) {
+        for part in parts {
+            file.write_all(part.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = TempDir::new("rotating-file-size");
+        let path = dir.0.join("app.log");
+        let mut file = RotatingFile::open(path.clone(), 10, Rotation::Never, 2).unwrap();
+        // каждая запись больше max_size, так что следующая начинает новый файл.
+        // Запись пишется несколькими write, в новый файл она не разрывается
+        record(&mut file, &["first record\n"]);
+        record(&mut file, &["second record", " continues\n"]);
+        record(&mut file, &["third record\n"]);
+        record(&mut file, &["fourth record\n"]);
+
+        let files = dir.files();
+        assert_eq!(files.len(), 3, "{files:?}");
+        assert_eq!(files[0], "app.log");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth record\n");
+        // первый файл удален, остались два последних
+        assert_eq!(fs::read_to_string(dir.0.join(&files[1])).unwrap(), "second record continues\n");
+        assert_eq!(fs::read_to_string(dir.0.join(&files[2])).unwrap(), "third record\n");
+    }
+
+    #[test]
+    fn many_rotations_in_one_millisecond_keep_the_newest() {
+        let dir = TempDir::new("rotating-file-burst");
+        let path = dir.0.join("app.log");
+        let mut file = RotatingFile::open(path.clone(), 1, Rotation::Never, 3).unwrap();
+        // больше десяти ротаций подряд, почти все в одну миллисекунду и с суффиксами
+        for i in 0..15 {
+            record(&mut file, &[&format!("record {i}\n")]);
+        }
+        let files = dir.files();
+        assert_eq!(files.len(), 4, "{files:?}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "record 14\n");
+        let kept: Vec<String> = files[1..].iter().map(|name| fs::read_to_string(dir.0.join(name)).unwrap()).collect();
+        assert_eq!(kept, ["record 11\n", "record 12\n", "record 13\n"], "{files:?}");
+    }
+
+    #[test]
+    fn appends_to_existing_file_until_it_is_full() {
+        let dir = TempDir::new("rotating-file-append");
+        let path = dir.0.join("app.log");
+        fs::write(&path, "old\n").unwrap();
+        let mut file = RotatingFile::open(path.clone(), 0, Rotation::Never, 2).unwrap();
+        record(&mut file, &["new\n"]);
+        assert_eq!(dir.files(), ["app.log"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old\nnew\n");
+    }
+
+    #[test]
+    fn rotates_when_period_changes() {
+        let dir = TempDir::new("rotating-file-period");
+        let path = dir.0.join("app.log");
+        let mut file = RotatingFile::open(path.clone(), 0, Rotation::Daily, 5).unwrap();
+        record(&mut file, &["today\n"]);
+        // как будто файл открыт вчера
+        file.period = "19700101".to_string();
+        record(&mut file, &["tomorrow\n"]);
+        let files = dir.files();
+        assert_eq!(files.len(), 2, "{files:?}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "tomorrow\n");
+        assert_eq!(file.period, period(Rotation::Daily));
+    }
+}
@@ -4,23 +4,72 @@ mod order_errors;
 mod order_handler;
 mod models;
 mod order_impl;
-use order_handler::{create_order, get_order_by_id, get_orders};
+mod config;
+mod order_bulk;
+mod statements;
+mod db;
+mod state;
+mod cli;
+mod bench;
+mod import;
+mod order_export;
+mod export;
+mod negotiation;
+mod order_events;
+mod grpc;
+mod graphql;
+mod order_ws;
+mod webhooks;
+mod webhook_handler;
+mod webhook_receiver;
+mod webhook_secrets;
+mod outbox;
+mod order_changes;
+mod order_cache;
+mod idempotency;
+mod api_keys;
+mod auth;
+mod jwt;
+mod tenant;
+mod rate_limit;
+mod request_id;
+mod logging;
+mod redact;
+mod monitoring;
+mod telemetry;
+mod log_handler;
+#[cfg(feature = "kafka")]
+mod kafka;
+use order_handler::{
+    create_order, create_orders_bulk, export_orders, get_order_by_id, get_orders, get_orders_by_track,
+    get_tracking, stream_order_events, update_item_status, add_order_item,
+};
+use config::CustomerPolicy;
+use state::AppState;
+use db::Db;
+use order_errors::OrderError;
+use order_events::OrderEvents;
+use webhooks::WebhookQueue;
+use order_changes::OrderChanges;
+use order_cache::OrderCache;
+use cli::{Cli, Command};
 
 
-use log::{info, error};
-use fern::Dispatch;
-use chrono::Local;
-use std::{env, fs, io};
+use log::{info, warn, error};
+use std::{env, fs};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use dotenvy::dotenv;
+use clap::Parser;
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
     // Extension
 };
 use tokio::task;
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
 use tokio_postgres::{NoTls, Client};
 
 fn load_env() {
@@ -35,8 +84,10 @@ fn load_env() {
     }
 }
 
-async fn get_db() -> Result<Client, Box<dyn std::error::Error>> {
-    let database_url = env::var("DATABASE_URL")?;
+// Отдельное соединение с базой, кроме основного клиента сервера используется для экспорта и cli команд
+async fn get_db() -> Result<Client, OrderError> {
+    let database_url = env::var("DATABASE_URL")
+        .map_err(|_| OrderError::Internal("DATABASE_URL is not set".to_string()))?;
     let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
     info!("Successfully connected to the database");
     task::spawn(async move {
@@ -48,27 +99,142 @@ async fn get_db() -> Result<Client, Box<dyn std::error::Error>> {
     Ok(client)
 }
 
+// Соединение с базой для фоновых задач, пока база недоступна - пробуем снова
+async fn connect_retry(task: &str) -> Client {
+    loop {
+        match get_db().await {
+            Ok(client) => return client,
+            Err(e) => {
+                error!("{task}: failed to connect to the database: {e}");
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>>{
     load_env();
+    let command = Cli::parse().command.unwrap_or(Command::Serve);
+
+    logging::init(matches!(command, Command::Serve))?;
+
+    match command {
+        Command::Serve => serve().await,
+        Command::Bench(args) => bench::run(args).await,
+        Command::Import(args) => import::run(args).await,
+        Command::Export(args) => export::run(args).await,
+        Command::WebhookReceiver(args) => webhook_receiver::run(args).await,
+        Command::Keys(args) => api_keys::run(args).await,
+    }
+}
 
-    let dispatch = Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{} [{}] - {}",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                message
-            ));
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(io::stdout());
+// Все HTTP маршруты, сгруппированные по scope API ключа который им нужен
+fn router(state: AppState) -> Router {
+    // запросы GraphQL только читают ордера, песочница GraphiQL открыта и отдается на GET /graphql только в dev режиме
+    let graphql_query = post(graphql::graphql_handler)
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::read))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::orders_read_all));
+    let graphql_route = if config::dev_mode() {
+        info!("Dev mode: GraphiQL is available at /graphql");
+        get(graphql::graphiql).merge(graphql_query)
+    } else {
+        graphql_query
+    };
+
+    // лимит на клиента ставится под auth, так он считается на ключ или пользователя а не на IP (лимит на IP - снаружи)
+    // покупатель из JWT получает здесь только свои ордера
+    let customer_routes = Router::new()
+        .route("/order/:order_uid", get(get_order_by_id))
+        .route("/orders", get(get_orders))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::read))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::orders_read));
+
+    let read_routes = Router::new()
+        .route("/orders/export", get(export_orders))
+        .route("/orders/stream", get(stream_order_events))
+        .route("/orders/by-track/:track_number", get(get_orders_by_track))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::read))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::orders_read_all));
 
-    dispatch.apply()?;
+    let write_routes = Router::new()
+        // повторы с тем же Idempotency-Key получают сохраненный ответ
+        .route(
+            "/order",
+            post(create_order).layer(middleware::from_fn_with_state(state.clone(), idempotency::idempotent)),
+        )
+        .route("/order/:order_uid/items", post(add_order_item))
+        .route("/order/:order_uid/items/:chrt_id/status", put(update_item_status))
+        .route("/orders/bulk", post(create_orders_bulk))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::write))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::orders_write));
+
+    let admin_routes = Router::new()
+        .route("/webhooks", post(webhook_handler::create_webhook).get(webhook_handler::list_webhooks))
+        .route("/webhooks/:id", delete(webhook_handler::delete_webhook))
+        .route("/webhooks/:id/deliveries", get(webhook_handler::list_webhook_deliveries))
+        .route("/webhooks/deliveries/:id/redeliver", post(webhook_handler::redeliver_webhook))
+        .route("/admin/log-level", get(log_handler::get_log_levels).put(log_handler::set_log_levels))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::write))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::admin));
+
+    Router::new()
+        .merge(customer_routes)
+        .merge(read_routes)
+        .merge(write_routes)
+        .merge(admin_routes)
+        // публичный трекинг без ключа, /ws проверяет ключ сам: браузер передает его подпротоколом, а не заголовком
+        .route(
+            "/track/:track_number",
+            get(get_tracking).route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::read)),
+        )
+        .route(
+            "/ws",
+            get(order_ws::ws_handler).route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::read)),
+        )
+        .route("/graphql", graphql_route)
+        // метрики для Prometheus без ключа, как и health check их собирают изнутри сети
+        .route("/metrics", get(monitoring::metrics_handler))
+        // .layer(Extension(client_arc));
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::in_flight))
+        // лимит на IP снаружи auth и общего лимита: запросы с неверным ключом тоже считаются
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::per_ip))
+        // над лимитом, так отказы по перегрузке тоже попадают в метрики
+        .layer(middleware::from_fn(monitoring::http_metrics))
+        .layer(middleware::from_fn(negotiation::negotiate))
+        // внутри request_id, чтобы id попал в спан
+        .layer(middleware::from_fn(telemetry::trace_request))
+        // снаружи всего, так id запроса есть у всех записей лога и у всех ответов
+        .layer(middleware::from_fn(request_id::request_id))
+        .with_state(state)
+}
 
+// Предупреждения о настройках из-за которых что-то открыто или выключено
+fn check_access_settings() -> Result<(), String> {
+    if !config::api_auth() {
+        warn!("API_AUTH=off, all routes and gRPC are open to anyone");
+        if config::ws_auth_token().is_none() {
+            warn!("WS_AUTH_TOKEN is not set, /ws is open to anyone");
+        }
+    }
+    // кривой ключ - ошибка запуска, без ключа просто нельзя создавать вебхуки
+    if config::webhook_secret_key()?.is_none() {
+        warn!("WEBHOOK_SECRET_KEY is not set, webhooks can't be created");
+    }
+    if config::webhook_allow_private() {
+        warn!("WEBHOOK_ALLOW_PRIVATE=on, webhooks may target internal addresses");
+    }
+    Ok(())
+}
+
+async fn serve() -> Result<(), Box<dyn std::error::Error>> {
     let server_address: String = env::var("SERVER_ADDRESS").unwrap_or("127.0.0.1:8080".to_owned());
     info!("Server address: {server_address}");
+    let grpc_address: String = env::var("GRPC_ADDRESS").unwrap_or("127.0.0.1:50051".to_owned());
+    info!("gRPC address: {grpc_address}");
+    let grpc_address = grpc_address.parse()?;
+    let tracer = telemetry::init()?;
 
     let client = match get_db().await {
         Ok(client) => {
@@ -77,17 +243,66 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
         },
         Err(e) => {
             error!("Failed to connect to the database: {}", e);
-            return Err(e);
+            return Err(e.into());
         }
     };
-    let client_arc = Arc::new(Mutex::new(client));
+    info!(
+        "Default tenant: {}, row level security: {}",
+        config::default_tenant(),
+        if config::tenant_rls() { "on" } else { "off" }
+    );
+    tenant::check_rls(&client).await?;
+    let db = Db::new(client);
 
-    let app = Router::new()
-        .route("/order/:order_uid", get(get_order_by_id))
-        .route("/orders", get(get_orders))
-        .route("/order", post(create_order))
-        // .layer(Extension(client_arc));
-        .with_state(client_arc);
+    let customer_policy = CustomerPolicy::from_env();
+    info!("Customer upsert policy: {customer_policy}");
+    check_access_settings()?;
+    let (ip_rate, ip_burst) = config::rate_limit_ip();
+    let (read_rate, read_burst) = config::rate_limit_read();
+    let (write_rate, write_burst) = config::rate_limit_write();
+    info!(
+        "Rate limits per IP: {ip_rate}/s (burst {ip_burst}), per client: read {read_rate}/s (burst {read_burst}), \
+        write {write_rate}/s (burst {write_burst}), max in flight {}",
+        config::max_in_flight()
+    );
+
+    let jwt = jwt::JwtKeys::from_env()?;
+    if jwt.is_none() {
+        info!("JWT_SECRET and JWT_JWKS_FILE are not set, bearer tokens are not accepted");
+    }
+
+    let state = AppState {
+        auth: auth::Auth::new(db.clone(), jwt),
+        db,
+        customer_policy,
+        events: OrderEvents::new(config::event_log_size()),
+        graphql: graphql::schema(),
+        webhooks: WebhookQueue::default(),
+        orders_cache: OrderCache::new(config::order_cache_size()),
+        changes: OrderChanges::default(),
+        rate_limits: rate_limit::RateLimits::from_env(),
+        metrics: monitoring::install()?,
+        exports: Arc::new(Semaphore::new(config::export_max_concurrent())),
+    };
+    // listener узнает об изменениях в базе от любого экземпляра сервиса (LISTEN order_changes, outbox),
+    // по ним сбрасывается кэш заказов и просыпается outbox relay
+    state.orders_cache.spawn_invalidation(&state.changes);
+    // события из таблицы outbox отправляет relay, доставка вебхуков идет отдельно, обе задачи на своих соединениях
+    outbox::spawn(
+        state.events.clone(),
+        outbox::sinks(&state.db, &state.webhooks)?,
+        &state.changes,
+    );
+    order_changes::spawn(state.changes.clone());
+    webhooks::spawn(state.webhooks.clone());
+    idempotency::spawn_cleanup(state.db.clone());
+    state.rate_limits.spawn_sweep();
+    // gRPC сервис работает на своем порту, но с тем же состоянием что и HTTP роутер
+    let grpc = tonic::transport::Server::builder()
+        .add_service(grpc::OrderGrpc::server(state.clone()))
+        .serve(grpc_address);
+
+    let app = router(state);
     info!("Application routes configured");
 
     let listener = match TcpListener::bind(&server_address).await {
@@ -101,10 +316,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
         }
     };
 
-    if let Err(e) = axum::serve(listener, app).await {
-        error!("Error serving application: {}", e);
-        return Err(e.into());
-    }
+    // если падает один из серверов, останавливается весь процесс
+    let result: Result<(), Box<dyn std::error::Error>> = tokio::select! {
+        // адрес клиента нужен лимиту запросов для запросов без учетных данных
+        result = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()) => result.map_err(|e| {
+            error!("Error serving application: {}", e);
+            e.into()
+        }),
+        result = grpc => result.map_err(|e| {
+            error!("Error serving gRPC: {}", e);
+            e.into()
+        }),
+    };
+    // отправляем спаны которые еще не ушли коллектору
+    telemetry::shutdown(tracer);
+    result?;
     info!("Server is running");
 Ok(())
 }
\ No newline at end of file
@@ -1,4 +1,8 @@
+use async_graphql::SimpleObject;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::redact;
 // не определился с названием самого файла схемы или модели?
 // Создаю структуры для обработки запроса serde нужен для сереализации и десериализации json
 
@@ -7,13 +11,91 @@ pub struct OrderResponse {
     pub orders: Vec<Order>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct TrackingResponse {
+    pub tracking: Vec<TrackingView>,
+}
+
+// Публичный трекинг: только статус, служба доставки и товары, без данных покупателя и оплаты
+#[derive(Debug, Serialize)]
+pub struct TrackingView {
+    pub track_number: String,
+    pub delivery_service: String,
+    pub items: Vec<TrackingItem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackingItem {
+    pub track_number: String,
+    pub name: String,
+    pub brand: String,
+    pub size: String,
+    pub status: i32,
+}
+
+// Тело PUT /order/:order_uid/items/:chrt_id/status
+#[derive(Debug, Deserialize)]
+pub struct ItemStatusUpdate {
+    pub status: i32,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Pagination {
     pub limit: Option<i64>,
     pub offset: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Режим bulk вставки: atomic - если хоть один заказ не прошел откатываем все,
+// best_effort - сохраняем все что получилось
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkMode {
+    #[default]
+    Atomic,
+    BestEffort,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkParams {
+    #[serde(default)]
+    pub mode: BulkMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkStatus {
+    Created,
+    Conflict,
+    Invalid,
+    Failed,
+    // заказ вставился, но вся пачка откатилась в режиме atomic
+    RolledBack,
+    // в режиме atomic заказ не вставлялся: в пачке уже есть ошибка
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkOrderResult {
+    pub index: usize,
+    pub order_uid: Option<String>,
+    pub status: BulkStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkResponse {
+    pub success: bool,
+    pub mode: BulkMode,
+    pub created: usize,
+    pub failed: usize,
+    pub results: Vec<BulkOrderResult>,
+}
+
+// Debug у доставки и оплаты свой: персональные данные в логах скрываются по правилам из redact.rs
+#[derive(Clone, Deserialize, Serialize, SimpleObject)]
 pub struct Delivery {
     pub name: String,
     pub phone: String,
@@ -24,7 +106,9 @@ pub struct Delivery {
     pub email: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// имена полей повторяют json заказа, переименовывать нельзя
+#[allow(clippy::struct_field_names)]
+#[derive(Clone, Deserialize, Serialize, SimpleObject)]
 pub struct Payment {
     pub transaction: String,
     pub request_id: String,
@@ -38,7 +122,38 @@ pub struct Payment {
     pub custom_fee: i32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delivery")
+            .field("name", &redact::field("delivery.name", &self.name))
+            .field("phone", &redact::field("delivery.phone", &self.phone))
+            .field("zip", &redact::field("delivery.zip", &self.zip))
+            .field("city", &redact::field("delivery.city", &self.city))
+            .field("address", &redact::field("delivery.address", &self.address))
+            .field("region", &redact::field("delivery.region", &self.region))
+            .field("email", &redact::field("delivery.email", &self.email))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Payment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Payment")
+            .field("transaction", &redact::field("payment.transaction", &self.transaction))
+            .field("request_id", &redact::field("payment.request_id", &self.request_id))
+            .field("currency", &redact::field("payment.currency", &self.currency))
+            .field("provider", &redact::field("payment.provider", &self.provider))
+            .field("amount", &self.amount)
+            .field("payment_dt", &self.payment_dt)
+            .field("bank", &redact::field("payment.bank", &self.bank))
+            .field("delivery_cost", &self.delivery_cost)
+            .field("goods_total", &self.goods_total)
+            .field("custom_fee", &self.custom_fee)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, SimpleObject)]
 pub struct Item {
     pub chrt_id: i64,
     pub track_number: String,
@@ -53,7 +168,9 @@ pub struct Item {
     pub status: i32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// order_uid тоже из json
+#[allow(clippy::struct_field_names)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Order {
     pub order_uid: String,
     pub track_number: String,
@@ -67,4 +184,37 @@ pub struct Order {
     pub sm_id: i32,
     pub date_created: String,
     pub oof_shard: String,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl Order {
+    // Заказ из примера в README, для тестов
+    pub fn sample() -> Self {
+        serde_json::from_value(serde_json::json!({
+            "order_uid": "b563feb7b2b84b6test",
+            "track_number": "WBILMTESTTRACK",
+            "entry": "WBIL",
+            "delivery": {
+                "name": "Test Testov", "phone": "+9720000000", "zip": "2639809", "city": "Kiryat Mozkin",
+                "address": "Ploshad Mira 15", "region": "Kraiot", "email": "test@gmail.com"
+            },
+            "payment": {
+                "transaction": "b563feb7b2b84b6test", "request_id": "", "currency": "USD", "provider": "wbpay",
+                "amount": 1817, "payment_dt": 1_637_907_727, "bank": "alpha", "delivery_cost": 1500,
+                "goods_total": 317, "custom_fee": 0
+            },
+            "items": [{
+                "chrt_id": 9_934_930, "track_number": "WBILMTESTTRACK", "price": 453, "rid": "ab4219087a764ae0btest",
+                "name": "Mascaras", "sale": 30, "size": "0", "total_price": 317, "nm_id": 2_389_212,
+                "brand": "Vivienne Sabo", "status": 202
+            }],
+            "delivery_service": "meest",
+            "customer_id": "test",
+            "shardkey": "9",
+            "sm_id": 99,
+            "date_created": "2021-11-26T06:22:19Z",
+            "oof_shard": "1"
+        }))
+        .unwrap()
+    }
+}
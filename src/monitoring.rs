@@ -0,0 +1,96 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::info;
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{fmt, future::Future, time::{Duration, Instant}};
+use tracing::Instrument;
+
+use crate::{order_errors::OrderError, telemetry::{self, RowCount}};
+
+// Корзины гистограмм для всех *_seconds: от миллисекунды до таймаута запроса к базе
+const SECONDS_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Регистратор метрик процесса, отдает их в формате Prometheus на /metrics. Метрики пишутся макросами
+// metrics (counter!, histogram!) из любого места, до вызова install они никуда не попадают (cli команды)
+pub fn install() -> Result<PrometheusHandle, OrderError> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), SECONDS_BUCKETS)
+        .and_then(PrometheusBuilder::install_recorder)
+        .map_err(|e| OrderError::Internal(format!("Failed to install metrics recorder: {e}")))?;
+    // гистограммы копят значения до сборки, upkeep сворачивает их в корзины
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(UPKEEP_INTERVAL).await;
+            upkeep.run_upkeep();
+        }
+    });
+    info!("Metrics are available at /metrics");
+    Ok(handle)
+}
+
+pub async fn metrics_handler(State(handle): State<PrometheusHandle>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"))],
+        handle.render(),
+    )
+}
+
+// Число и длительность HTTP запросов по маршруту (шаблон из роутера, а не путь, иначе на каждый
+// order_uid была бы своя метрика), методу и статусу ответа. Для потоков - время до начала ответа
+pub async fn http_metrics(req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let response = next.run(req).await;
+    let labels = [("method", method), ("route", route), ("status", response.status().as_u16().to_string())];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!("http_request_duration_seconds", &labels).record(started.elapsed());
+    response
+}
+
+// Длительность и спан запроса к базе, query - имя запроса (insert_orders, query_orders и т.п.)
+pub async fn time_query<T: RowCount, E: fmt::Display>(query: &str, fut: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let span = telemetry::query_span(query);
+    let started = Instant::now();
+    let result = fut.instrument(span.clone()).await;
+    record_query(query, started, result.is_ok());
+    telemetry::finish_query(&span, &result);
+    result
+}
+
+pub fn record_query(query: &str, started: Instant, ok: bool) {
+    let labels = [
+        ("query", query.replace(' ', "_")),
+        ("outcome", if ok { "ok" } else { "error" }.to_string()),
+    ];
+    histogram!("db_query_duration_seconds", &labels).record(started.elapsed());
+}
+
+// Сколько запрос ждал своей очереди к общему клиенту базы (db.rs)
+pub fn client_waited(started: Instant) {
+    histogram!("db_client_wait_seconds").record(started.elapsed());
+}
+
+// Заказы и товары после комита транзакции которая их сохранила
+pub fn orders_created(orders: usize, items: usize) {
+    counter!("orders_created_total").increment(orders as u64);
+    counter!("order_items_created_total").increment(items as u64);
+}
+
+pub fn item_added() {
+    counter!("order_items_created_total").increment(1);
+}
+
+pub fn item_status_changed() {
+    counter!("order_item_status_changes_total").increment(1);
+}
@@ -0,0 +1,235 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::order_errors::{error_body, OrderError};
+
+// Форматы в которых можно присылать и получать заказы, выбираются по Content-Type и Accept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+tokio::task_local! {
+    // формат ответа для текущего запроса, выставляется в negotiate
+    static RESPONSE_FORMAT: Format;
+}
+
+impl Format {
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.trim().to_lowercase().as_str() {
+            "application/json" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MsgPack),
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    // Content-Type без параметров (charset и т.п.), без заголовка считаю что пришел JSON
+    fn from_content_type(headers: &HeaderMap) -> Result<Self, OrderError> {
+        let Some(value) = headers.get(header::CONTENT_TYPE) else {
+            return Ok(Format::Json);
+        };
+        let content_type = value.to_str().unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        Format::from_media_type(media_type)
+            .ok_or_else(|| OrderError::UnsupportedMediaType(content_type.to_string()))
+    }
+
+    // Из Accept берется поддерживаемый формат с наибольшим q, если подходящего нет - JSON
+    fn from_accept(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return Format::Json;
+        };
+        let mut best: Option<(Format, f32)> = None;
+        for part in accept.split(',') {
+            let mut params = part.split(';');
+            let Some(format) = params.next().and_then(Format::from_media_type) else {
+                continue;
+            };
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((format, q));
+            }
+        }
+        best.map_or(Format::Json, |(format, _)| format)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    // Формат ответа текущего запроса, вне negotiate всегда JSON
+    pub fn current() -> Self {
+        RESPONSE_FORMAT.try_with(|format| *format).unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, OrderError> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            // to_vec_named пишет структуры как map с именами полей, а не массивом,
+            // иначе клиенту пришлось бы знать порядок полей в моделях
+            Format::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| OrderError::Internal(e.to_string())),
+            Format::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(|e| OrderError::Internal(e.to_string()))?;
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, OrderError> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(body)?),
+            Format::MsgPack => rmp_serde::from_slice(body).map_err(|e| OrderError::Decode(e.to_string())),
+            Format::Cbor => ciborium::from_reader(body).map_err(|e| OrderError::Decode(e.to_string())),
+        }
+    }
+
+    // Ответ в этом формате, если закодировать не вышло - отдаю ошибку JSON'ом,
+    // чтобы не уйти в бесконечную рекурсию через OrderError::into_response
+    pub fn render<T: Serialize>(self, status: StatusCode, value: &T) -> Response {
+        match self.encode(value) {
+            Ok(body) => (
+                status,
+                [(header::CONTENT_TYPE, HeaderValue::from_static(self.content_type()))],
+                body,
+            )
+                .into_response(),
+            Err(e) => {
+                let (status, message, field) = e.parts();
+                Format::Json.render(status, &error_body(&message, &field))
+            }
+        }
+    }
+}
+
+// Выбирает формат ответа по Accept и держит его на время обработки запроса,
+// так и хендлеры (через Negotiated) и ошибки (OrderError) отвечают в одном формате
+pub async fn negotiate(req: Request, next: Next) -> Response {
+    let format = Format::from_accept(req.headers());
+    RESPONSE_FORMAT.scope(format, next.run(req)).await
+}
+
+// Замена Json<T>: как экстрактор разбирает тело по Content-Type,
+// как ответ кодирует значение в формате из Accept
+pub struct Negotiated<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = OrderError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format = Format::from_content_type(req.headers())?;
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| OrderError::Decode(e.body_text()))?;
+        Ok(Negotiated(format.decode(&body)?))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        Format::current().render(StatusCode::OK, &self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Order;
+    use serde_json::Value;
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn accept(value: &str) -> Format {
+        Format::from_accept(&headers(header::ACCEPT, value))
+    }
+
+    #[test]
+    fn accept_picks_highest_q() {
+        assert_eq!(accept("application/msgpack"), Format::MsgPack);
+        assert_eq!(accept("application/json;q=0.5, application/cbor"), Format::Cbor);
+        assert_eq!(accept("application/cbor; q=0.2, application/x-msgpack; q=0.9"), Format::MsgPack);
+        // при равном q остается первый
+        assert_eq!(accept("application/cbor;q=0.5, application/msgpack;q=0.5"), Format::Cbor);
+        // регистр и пробелы не важны
+        assert_eq!(accept("  Application/CBOR "), Format::Cbor);
+    }
+
+    #[test]
+    fn accept_skips_q_zero() {
+        assert_eq!(accept("application/msgpack;q=0, application/cbor;q=0.1"), Format::Cbor);
+        assert_eq!(accept("application/cbor;q=0"), Format::Json);
+        assert_eq!(accept("application/cbor;q=0.0, application/msgpack;q=0"), Format::Json);
+    }
+
+    #[test]
+    fn accept_falls_back_to_json() {
+        assert_eq!(Format::from_accept(&HeaderMap::new()), Format::Json);
+        assert_eq!(accept("*/*"), Format::Json);
+        assert_eq!(accept("text/html, application/xml;q=0.9"), Format::Json);
+        // q который не разобрать считается как 1
+        assert_eq!(accept("application/msgpack;q=abc"), Format::MsgPack);
+    }
+
+    #[test]
+    fn content_type_selects_decoder() {
+        let content_type = |value: &str| Format::from_content_type(&headers(header::CONTENT_TYPE, value));
+        assert_eq!(Format::from_content_type(&HeaderMap::new()).unwrap(), Format::Json);
+        assert_eq!(content_type("application/json; charset=utf-8").unwrap(), Format::Json);
+        assert_eq!(content_type("application/vnd.msgpack").unwrap(), Format::MsgPack);
+        assert_eq!(content_type("application/cbor").unwrap(), Format::Cbor);
+
+        let err = content_type("text/plain").unwrap_err();
+        assert!(matches!(&err, OrderError::UnsupportedMediaType(value) if value == "text/plain"));
+        assert_eq!(err.parts().0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn order_round_trips_in_every_format() {
+        let expected = serde_json::to_value(Order::sample()).unwrap();
+        for format in [Format::Json, Format::MsgPack, Format::Cbor] {
+            let body = format.encode(&Order::sample()).unwrap();
+            let decoded: Order = format.decode(&body).unwrap();
+            assert_eq!(serde_json::to_value(decoded).unwrap(), expected, "{format:?}");
+        }
+        // MessagePack с именами полей, а не массивом
+        let body = Format::MsgPack.encode(&Order::sample()).unwrap();
+        let map: Value = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(map["order_uid"], "b563feb7b2b84b6test");
+    }
+
+    #[test]
+    fn broken_body_is_decode_error() {
+        for format in [Format::MsgPack, Format::Cbor] {
+            let err = format.decode::<Order>(b"\xff\x00").unwrap_err();
+            assert!(matches!(err, OrderError::Decode(_)), "{format:?}");
+            assert_eq!(err.parts().0, StatusCode::BAD_REQUEST);
+        }
+    }
+}
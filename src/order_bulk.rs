@@ -0,0 +1,467 @@
+use axum::{body::Body, http::StatusCode};
+use log::{debug, error};
+use serde_json::Value;
+use tokio_postgres::Transaction;
+use tokio_stream::StreamExt;
+
+use crate::{
+    config::CustomerPolicy,
+    models::{BulkMode, BulkOrderResult, BulkStatus, Order},
+    order_errors::OrderError,
+    redact,
+    statements::StatementCache,
+    tenant::Tenant,
+};
+
+// Заказ из bulk запроса: order_uid пытаюсь достать даже если сам заказ не разобрался,
+// чтобы в ответе было понятно о каком заказе речь
+pub struct ParsedOrder {
+    pub order_uid: Option<String>,
+    pub order: Result<Order, OrderError>,
+}
+
+impl ParsedOrder {
+    pub fn from_value(value: Value) -> Self {
+        let order_uid = value
+            .get("order_uid")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        ParsedOrder {
+            order_uid,
+            order: serde_json::from_value(value).map_err(OrderError::from),
+        }
+    }
+}
+
+impl From<Result<Value, serde_json::Error>> for ParsedOrder {
+    fn from(value: Result<Value, serde_json::Error>) -> Self {
+        match value {
+            Ok(value) => ParsedOrder::from_value(value),
+            Err(e) => ParsedOrder {
+                order_uid: None,
+                order: Err(OrderError::from(e)),
+            },
+        }
+    }
+}
+
+// Разбор тела на отдельные JSON значения, NDJSON построчно (пустые строки пропускаются) или JSON массив.
+// Каждое значение разбирается отдельно чтобы одна кривая строка не роняла весь батч,
+// ошибкой целиком считается только тело которое вообще не получилось разобрать
+pub fn parse_values(body: &[u8], ndjson: bool) -> Result<Vec<Result<Value, serde_json::Error>>, OrderError> {
+    if ndjson {
+        return Ok(body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(serde_json::from_slice::<Value>)
+            .collect());
+    }
+
+    let values: Vec<Value> = serde_json::from_slice(body)?;
+    Ok(values.into_iter().map(Ok).collect())
+}
+
+// Чтение тела POST /orders/bulk по кускам: каждый заказ разбирается как только пришел целиком,
+// так в памяти не лежит все тело сразу. Тело больше limit байт обрывается с 413
+pub async fn read_orders(body: Body, ndjson: bool, limit: usize) -> Result<Vec<ParsedOrder>, OrderError> {
+    let mut parser = BulkParser::new(ndjson);
+    let mut orders = Vec::new();
+    let mut read = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| OrderError::Decode(format!("Failed to read request body: {e}")))?;
+        read += chunk.len();
+        if read > limit {
+            return Err(OrderError::PayloadTooLarge(format!("Request body is larger than {limit} bytes")));
+        }
+        parser.push(&chunk, &mut orders)?;
+    }
+    parser.finish(&mut orders)?;
+    Ok(orders)
+}
+
+// Где сейчас разбор JSON массива заказов
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    // до [
+    Start,
+    // ждем заказ, first - сразу после [, тут можно и ]
+    Value { first: bool },
+    // внутри заказа
+    InValue,
+    // после заказа ждем , или ]
+    AfterValue,
+    // после ], дальше только пробелы
+    Done,
+}
+
+// Достает из кусков тела законченные заказы: строки NDJSON или элементы JSON массива.
+// В buffer только незаконченная строка или элемент
+struct BulkParser {
+    ndjson: bool,
+    buffer: Vec<u8>,
+    state: ArrayState,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl BulkParser {
+    fn new(ndjson: bool) -> Self {
+        BulkParser {
+            ndjson,
+            buffer: Vec::new(),
+            state: ArrayState::Start,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8], orders: &mut Vec<ParsedOrder>) -> Result<(), OrderError> {
+        if self.ndjson {
+            for &byte in chunk {
+                if byte == b'\n' {
+                    self.emit_line(orders);
+                } else {
+                    self.buffer.push(byte);
+                }
+            }
+            return Ok(());
+        }
+        for &byte in chunk {
+            self.push_array_byte(byte, orders)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, orders: &mut Vec<ParsedOrder>) -> Result<(), OrderError> {
+        if self.ndjson {
+            self.emit_line(orders);
+            return Ok(());
+        }
+        if self.state != ArrayState::Done {
+            return Err(OrderError::Decode("Unexpected end of JSON array of orders".to_string()));
+        }
+        Ok(())
+    }
+
+    // пустые строки пропускаются
+    fn emit_line(&mut self, orders: &mut Vec<ParsedOrder>) {
+        if !self.buffer.trim_ascii().is_empty() {
+            orders.push(serde_json::from_slice::<Value>(&self.buffer).into());
+        }
+        self.buffer.clear();
+    }
+
+    fn emit_value(&mut self, orders: &mut Vec<ParsedOrder>) {
+        orders.push(serde_json::from_slice::<Value>(&self.buffer).into());
+        self.buffer.clear();
+    }
+
+    fn push_array_byte(&mut self, byte: u8, orders: &mut Vec<ParsedOrder>) -> Result<(), OrderError> {
+        let unexpected = || OrderError::Decode(format!("Body must be a JSON array of orders, unexpected '{}'", byte as char));
+        match self.state {
+            _ if self.state != ArrayState::InValue && byte.is_ascii_whitespace() => {}
+            ArrayState::Start if byte == b'[' => self.state = ArrayState::Value { first: true },
+            ArrayState::Value { first: true } if byte == b']' => self.state = ArrayState::Done,
+            ArrayState::Value { .. } if byte != b']' && byte != b',' => {
+                self.state = ArrayState::InValue;
+                self.push_value_byte(byte, orders);
+            }
+            ArrayState::InValue => self.push_value_byte(byte, orders),
+            ArrayState::AfterValue if byte == b',' => self.state = ArrayState::Value { first: false },
+            ArrayState::AfterValue if byte == b']' => self.state = ArrayState::Done,
+            _ => return Err(unexpected()),
+        }
+        Ok(())
+    }
+
+    // Внутри элемента считаю вложенность скобок вне строк: объект или массив заканчивается
+    // на своей закрывающей скобке, остальное (кривые заказы вроде чисел) - на , или ] снаружи
+    fn push_value_byte(&mut self, byte: u8, orders: &mut Vec<ParsedOrder>) {
+        if self.in_string {
+            self.buffer.push(byte);
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+            }
+            return;
+        }
+        match byte {
+            b'"' => {
+                self.buffer.push(byte);
+                self.in_string = true;
+            }
+            b'{' | b'[' => {
+                self.buffer.push(byte);
+                self.depth += 1;
+            }
+            b'}' | b']' if self.depth > 0 => {
+                self.buffer.push(byte);
+                self.depth -= 1;
+                if self.depth == 0 {
+                    self.emit_value(orders);
+                    self.state = ArrayState::AfterValue;
+                }
+            }
+            b',' if self.depth == 0 => {
+                self.emit_value(orders);
+                self.state = ArrayState::Value { first: false };
+            }
+            b']' if self.depth == 0 => {
+                self.emit_value(orders);
+                self.state = ArrayState::Done;
+            }
+            _ => self.buffer.push(byte),
+        }
+    }
+}
+
+impl BulkOrderResult {
+    pub fn created(index: usize, order_uid: &str) -> Self {
+        BulkOrderResult {
+            index,
+            order_uid: Some(order_uid.to_string()),
+            status: BulkStatus::Created,
+            message: None,
+            field: None,
+        }
+    }
+
+    // в режиме atomic заказ не вставлялся, потому что в пачке уже есть ошибка
+    pub fn skipped(index: usize, order_uid: &str) -> Self {
+        BulkOrderResult {
+            index,
+            order_uid: Some(order_uid.to_string()),
+            status: BulkStatus::Skipped,
+            message: None,
+            field: None,
+        }
+    }
+
+    // Раскладываю ошибку по тем же статусам что и в IntoResponse для OrderError
+    pub fn from_error(index: usize, order_uid: Option<String>, err: &OrderError) -> Self {
+        let (status, message, field) = err.parts();
+        let status = match status {
+            StatusCode::CONFLICT => BulkStatus::Conflict,
+            StatusCode::BAD_REQUEST => BulkStatus::Invalid,
+            _ => BulkStatus::Failed,
+        };
+        BulkOrderResult {
+            index,
+            order_uid,
+            status,
+            message: Some(message),
+            field: (!field.is_empty()).then_some(field),
+        }
+    }
+}
+
+// Разбор и валидация всех заказов пачки до записи в базу:
+// результаты для отклоненных и заказы которые можно вставлять, с их номером в пачке
+pub fn validate_batch(orders: Vec<ParsedOrder>) -> (Vec<BulkOrderResult>, Vec<(usize, Order)>) {
+    let mut results = Vec::new();
+    let mut valid: Vec<(usize, Order)> = Vec::with_capacity(orders.len());
+
+    for (index, parsed) in orders.into_iter().enumerate() {
+        match parsed.order.and_then(|order| order.validate_fields().map(|()| order)) {
+            Ok(order) => valid.push((index, order)),
+            Err(e) => {
+                debug!("Bulk order #{index} rejected: {e}");
+                results.push(BulkOrderResult::from_error(index, parsed.order_uid, &e));
+            }
+        }
+    }
+    (results, valid)
+}
+
+// Результат вставки пачки: статус по каждому заказу и сами вставленные заказы,
+// чтобы после комита по ним можно было посчитать метрики
+pub struct BatchOutcome {
+    pub results: Vec<BulkOrderResult>,
+    pub created: Vec<Order>,
+}
+
+// Валидация и вставка пачки в режиме best_effort, для import
+pub async fn insert_batch(
+    tx: &mut Transaction<'_>,
+    statements: &StatementCache,
+    tenant: &Tenant,
+    orders: Vec<ParsedOrder>,
+    policy: CustomerPolicy,
+) -> BatchOutcome {
+    let (mut results, valid) = validate_batch(orders);
+    let mut outcome = insert_valid(tx, statements, tenant, valid, policy, BulkMode::BestEffort).await;
+    results.append(&mut outcome.results);
+    results.sort_by_key(|result| result.index);
+    BatchOutcome { results, created: outcome.created }
+}
+
+// Вставка уже проверенных заказов в одной транзакции. Сначала пробую вставить все разом (save_many),
+// если не вышло - откатываю и прохожу по одному, каждый в своем savepoint, чтобы понять какой именно
+// заказ упал и не сломать транзакцию для остальных. В режиме atomic на первом упавшем заказе
+// остальные уже не пробую. Комит или откат всей транзакции решает вызывающий код
+pub async fn insert_valid(
+    tx: &mut Transaction<'_>,
+    statements: &StatementCache,
+    tenant: &Tenant,
+    valid: Vec<(usize, Order)>,
+    policy: CustomerPolicy,
+    mode: BulkMode,
+) -> BatchOutcome {
+    let mut results = Vec::with_capacity(valid.len());
+    let mut created = Vec::new();
+    if valid.is_empty() {
+        return BatchOutcome { results, created };
+    }
+
+    let (indexes, valid_orders): (Vec<usize>, Vec<Order>) = valid.into_iter().unzip();
+    match insert_all(tx, statements, tenant, &valid_orders, policy).await {
+        Ok(()) => {
+            for (index, order) in indexes.into_iter().zip(&valid_orders) {
+                results.push(BulkOrderResult::created(index, &order.order_uid));
+            }
+            created = valid_orders;
+        }
+        Err(e) => {
+            debug!("Batch insert failed, falling back to per-order inserts: {e}");
+            let mut failed = false;
+            for (index, order) in indexes.into_iter().zip(valid_orders) {
+                if failed && mode == BulkMode::Atomic {
+                    results.push(BulkOrderResult::skipped(index, &order.order_uid));
+                    continue;
+                }
+                let result = insert_one(tx, statements, tenant, index, &order, policy).await;
+                if result.status == BulkStatus::Created {
+                    created.push(order);
+                } else {
+                    failed = true;
+                }
+                results.push(result);
+            }
+        }
+    }
+    BatchOutcome { results, created }
+}
+
+// Все заказы одним save_many внутри savepoint, при ошибке savepoint откатывается
+async fn insert_all(
+    tx: &mut Transaction<'_>,
+    statements: &StatementCache,
+    tenant: &Tenant,
+    orders: &[Order],
+    policy: CustomerPolicy,
+) -> Result<(), OrderError> {
+    let savepoint = tx.savepoint("bulk_batch").await?;
+    match Order::save_many(&savepoint, statements, tenant, orders, policy).await {
+        Ok(()) => savepoint.commit().await.map_err(OrderError::from),
+        Err(e) => {
+            if let Err(rollback_err) = savepoint.rollback().await {
+                error!("Failed to rollback savepoint: {}", rollback_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn insert_one(
+    tx: &mut Transaction<'_>,
+    statements: &StatementCache,
+    tenant: &Tenant,
+    index: usize,
+    order: &Order,
+    policy: CustomerPolicy,
+) -> BulkOrderResult {
+    let savepoint = match tx.savepoint("bulk_order").await {
+        Ok(savepoint) => savepoint,
+        Err(e) => {
+            error!("Failed to create savepoint: {}", redact::db_error(&e));
+            return BulkOrderResult::from_error(index, Some(order.order_uid.clone()), &OrderError::Database(e));
+        }
+    };
+
+    let saved = match order.save(&savepoint, statements, tenant, policy).await {
+        Ok(()) => savepoint.commit().await.map_err(OrderError::from),
+        Err(e) => {
+            if let Err(rollback_err) = savepoint.rollback().await {
+                error!("Failed to rollback savepoint: {}", rollback_err);
+            }
+            Err(e)
+        }
+    };
+
+    match saved {
+        Ok(()) => BulkOrderResult::created(index, &order.order_uid),
+        Err(e) => {
+            debug!("Bulk order {} failed: {}", order.order_uid, e);
+            BulkOrderResult::from_error(index, Some(order.order_uid.clone()), &e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // разбор с телом порезанным на куски по size байт, так граница куска попадает в любое место
+    fn parse_chunked(body: &[u8], ndjson: bool, size: usize) -> Result<Vec<ParsedOrder>, OrderError> {
+        let mut parser = BulkParser::new(ndjson);
+        let mut orders = Vec::new();
+        for chunk in body.chunks(size) {
+            parser.push(chunk, &mut orders)?;
+        }
+        parser.finish(&mut orders)?;
+        Ok(orders)
+    }
+
+    fn uids(orders: &[ParsedOrder]) -> Vec<Option<&str>> {
+        orders.iter().map(|order| order.order_uid.as_deref()).collect()
+    }
+
+    #[test]
+    fn splits_array_elements_on_any_chunk_boundary() {
+        let body = br#" [ {"order_uid": "a,]}\"[{", "items": [1, {"x": []}]} , {"order_uid": "b"}, 42 ] "#;
+        for size in 1..=body.len() {
+            let orders = parse_chunked(body, false, size).unwrap();
+            assert_eq!(uids(&orders), [Some("a,]}\"[{"), Some("b"), None], "chunk size {size}");
+            assert!(orders.iter().all(|order| order.order.is_err()));
+        }
+    }
+
+    #[test]
+    fn splits_ndjson_lines() {
+        let body = b"{\"order_uid\": \"a\"}\n\n  \n{\"order_uid\": \"b\"}\nnot json";
+        for size in 1..=body.len() {
+            let orders = parse_chunked(body, true, size).unwrap();
+            assert_eq!(uids(&orders), [Some("a"), Some("b"), None], "chunk size {size}");
+        }
+    }
+
+    #[test]
+    fn empty_array_has_no_orders() {
+        assert!(parse_chunked(b"[]", false, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_broken_arrays() {
+        for body in [&b"{\"order_uid\": \"a\"}"[..], b"[{}", b"[{},]", b"[,{}]", b"[{}] {}", b"", b"[{} {}]"] {
+            assert!(
+                matches!(parse_chunked(body, false, 3), Err(OrderError::Decode(_))),
+                "{}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn body_over_limit_is_rejected() {
+        let body = Body::from(vec![b' '; 100]);
+        assert!(matches!(read_orders(body, false, 99).await, Err(OrderError::PayloadTooLarge(_))));
+        let orders = read_orders(Body::from("[{\"order_uid\": \"a\"}]"), false, 100).await.unwrap();
+        assert_eq!(uids(&orders), [Some("a")]);
+    }
+}
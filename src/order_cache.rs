@@ -0,0 +1,140 @@
+use log::{debug, info};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_postgres::Client;
+
+use crate::{
+    models::Order,
+    order_changes::{OrderChange, OrderChanges},
+    order_errors::OrderError,
+    tenant::Tenant,
+};
+
+#[derive(Default)]
+struct CacheInner {
+    // order_uid уникален только в пределах арендатора, поэтому ключ - пара
+    orders: HashMap<(Tenant, String), Order>,
+    // порядок добавления, самые старые вытесняются первыми
+    added: VecDeque<(Tenant, String)>,
+    // растет на каждой инвалидации: заказ прочитанный из базы до нее в кэш уже не кладем,
+    // иначе он мог бы остаться там устаревшим навсегда
+    generation: u64,
+    // listener подключен и слушает изменения. Без него об изменениях с других экземпляров не узнать,
+    // поэтому пока его нет кэш пустой и заказы в него не кладутся
+    listening: bool,
+}
+
+// Кэш заказов для чтения по order_uid (GET /order/:order_uid, gRPC GetOrder).
+// Заказы в нем сбрасываются по уведомлениям из базы (order_changes), поэтому изменения
+// с других экземпляров сервиса тоже видны. Размер 0 выключает кэш
+#[derive(Clone)]
+pub struct OrderCache {
+    capacity: usize,
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+impl OrderCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, inner: Arc::default() }
+    }
+
+    fn inner(&self) -> MutexGuard<'_, CacheInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub async fn find_by_id(&self, client: &Client, tenant: &Tenant, order_uid: &str) -> Result<Option<Order>, OrderError> {
+        if self.capacity == 0 {
+            return Order::find_by_id(client, tenant, order_uid).await;
+        }
+        let key = (tenant.clone(), order_uid.to_string());
+        let generation = {
+            let inner = self.inner();
+            if let Some(order) = inner.orders.get(&key) {
+                return Ok(Some(order.clone()));
+            }
+            inner.generation
+        };
+
+        let Some(order) = Order::find_by_id(client, tenant, order_uid).await? else {
+            return Ok(None);
+        };
+        let mut inner = self.inner();
+        if inner.listening && inner.generation == generation && !inner.orders.contains_key(&key) {
+            while inner.added.len() >= self.capacity {
+                if let Some(oldest) = inner.added.pop_front() {
+                    inner.orders.remove(&oldest);
+                }
+            }
+            inner.added.push_back(key.clone());
+            inner.orders.insert(key, order.clone());
+        }
+        Ok(Some(order))
+    }
+
+    // Сбрасывает заказ, его вызывают и хендлеры сразу после своих изменений,
+    // чтобы не ждать уведомления из базы. В уведомлении нет арендатора, поэтому сбрасывается
+    // order_uid у всех арендаторов, лишний сброс не страшен
+    pub fn invalidate(&self, order_uid: &str) {
+        let mut inner = self.inner();
+        inner.generation += 1;
+        let before = inner.orders.len();
+        inner.orders.retain(|(_, uid), _| uid != order_uid);
+        if inner.orders.len() != before {
+            inner.added.retain(|(_, uid)| uid != order_uid);
+        }
+    }
+
+    fn invalidate_customer(&self, customer_id: &str) {
+        let mut inner = self.inner();
+        inner.generation += 1;
+        // customer_id повторяется у разных арендаторов, лишний сброс не страшен
+        inner.orders.retain(|_, order| order.customer_id != customer_id);
+        let CacheInner { orders, added, .. } = &mut *inner;
+        added.retain(|key| orders.contains_key(key));
+    }
+
+    fn clear(&self, listening: bool) {
+        let mut inner = self.inner();
+        inner.generation += 1;
+        inner.orders.clear();
+        inner.added.clear();
+        inner.listening = listening;
+    }
+
+    // Фоновая задача которая сбрасывает кэш по изменениям в базе
+    pub fn spawn_invalidation(&self, changes: &OrderChanges) {
+        if self.capacity == 0 {
+            return;
+        }
+        info!("Order cache enabled for {} orders", self.capacity);
+        let cache = self.clone();
+        let mut changes = changes.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(OrderChange::Order(order_uid)) => cache.invalidate(&order_uid),
+                    Ok(OrderChange::Customer(customer_id)) => cache.invalidate_customer(&customer_id),
+                    Ok(OrderChange::Outbox) => {}
+                    // уведомления могли потеряться
+                    Ok(OrderChange::Resync) => {
+                        debug!("Order cache cleared");
+                        cache.clear(true);
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        debug!("Order cache cleared");
+                        let listening = cache.inner().listening;
+                        cache.clear(listening);
+                    }
+                    Ok(OrderChange::Disconnected) => {
+                        debug!("Order cache cleared and paused until the listener reconnects");
+                        cache.clear(false);
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
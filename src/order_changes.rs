@@ -0,0 +1,147 @@
+use futures_util::{stream, StreamExt};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::env;
+use tokio::{
+    sync::broadcast,
+    time::{sleep, Duration},
+};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::{order_errors::OrderError, order_impl::db_timeout};
+
+// Каналы на которые подписан listener, уведомления шлют триггеры из init.sql
+const ORDER_CHANGES_CHANNEL: &str = "order_changes";
+const OUTBOX_CHANNEL: &str = "outbox";
+// Пауза перед переподключением растет от 1 до 30 секунд пока база недоступна
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+// Что изменилось в базе. Пишет любой экземпляр сервиса (или кто-то руками через psql),
+// узнают все экземпляры
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderChange {
+    // изменился заказ: orders, payment или items
+    Order(String),
+    // изменился покупатель, а с ним доставка во всех его заказах
+    Customer(String),
+    // в outbox появились новые события
+    Outbox,
+    // listener переподключился, пока его не было уведомления могли потеряться - перечитать все
+    Resync,
+    // listener потерял соединение, до Resync об изменениях с других экземпляров не узнать
+    Disconnected,
+}
+
+// Тело уведомления из триггера notify_order_change
+#[derive(Debug, Deserialize)]
+struct Payload {
+    table: String,
+    order_uid: Option<String>,
+    customer_id: Option<String>,
+}
+
+impl OrderChange {
+    fn from_notification(channel: &str, payload: &str) -> Option<Self> {
+        if channel == OUTBOX_CHANNEL {
+            return Some(OrderChange::Outbox);
+        }
+        let payload: Payload = match serde_json::from_str(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Skipping bad {channel} notification {payload}: {e}");
+                return None;
+            }
+        };
+        match (payload.table.as_str(), payload.order_uid, payload.customer_id) {
+            ("customers", _, Some(customer_id)) => Some(OrderChange::Customer(customer_id)),
+            (_, Some(order_uid), _) => Some(OrderChange::Order(order_uid)),
+            _ => None,
+        }
+    }
+}
+
+// Изменения для всего что держит состояние в памяти процесса (кэш заказов, outbox relay).
+// Подписчик который отстал получит Lagged и должен вести себя как на Resync
+#[derive(Clone)]
+pub struct OrderChanges {
+    sender: broadcast::Sender<OrderChange>,
+}
+
+impl Default for OrderChanges {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        Self { sender }
+    }
+}
+
+impl OrderChanges {
+    pub fn subscribe(&self) -> broadcast::Receiver<OrderChange> {
+        self.sender.subscribe()
+    }
+
+    fn send(&self, change: OrderChange) {
+        // ошибка только если подписчиков нет, это нормально
+        let _ = self.sender.send(change);
+    }
+}
+
+// Listener работает в фоне на своем соединении и переподключается сам.
+// После каждого подключения (и первого тоже) рассылает Resync, при обрыве - Disconnected
+pub fn spawn(changes: OrderChanges) {
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_MIN;
+        loop {
+            match listen(&changes).await {
+                // соединение было и оборвалось - переподключаемся сразу с минимальной паузой
+                Ok(()) => delay = RECONNECT_MIN,
+                Err(e) => error!("Order changes listener failed: {e}"),
+            }
+            changes.send(OrderChange::Disconnected);
+            warn!("Order changes listener reconnecting in {}s", delay.as_secs());
+            sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX);
+        }
+    });
+}
+
+// Возвращается когда соединение закрылось
+async fn listen(changes: &OrderChanges) -> Result<(), OrderError> {
+    let database_url = env::var("DATABASE_URL")
+        .map_err(|_| OrderError::Internal("DATABASE_URL is not set".to_string()))?;
+    let (client, mut connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+    let (notifications, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let connection = tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    let _ = notifications.send(notification);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Order changes listener connection error: {e}");
+                    return;
+                }
+            }
+        }
+    });
+
+    let listen = format!("LISTEN {ORDER_CHANGES_CHANNEL}; LISTEN {OUTBOX_CHANNEL}");
+    if let Err(e) = db_timeout("listen order changes", client.batch_execute(&listen)).await {
+        connection.abort();
+        return Err(e);
+    }
+    info!("Listening for order changes");
+    changes.send(OrderChange::Resync);
+
+    // канал закроется вместе с соединением
+    while let Some(notification) = received.recv().await {
+        debug!("Notification on {}: {}", notification.channel(), notification.payload());
+        if let Some(change) = OrderChange::from_notification(notification.channel(), notification.payload()) {
+            changes.send(change);
+        }
+    }
+    drop(client);
+    Ok(())
+}
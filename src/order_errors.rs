@@ -1,11 +1,13 @@
 use axum::{
-    extract::Json,
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::IntoResponse,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use std::fmt;
 use log::error;
+use metrics::counter;
+
+use crate::{negotiation::Format, redact, request_id};
 // Хотел сделать красивую обработку ошибок, чтобы привести все к общему виду ответа в случие ошибки по типу:
 // {
 //     "field": "order_uid",
@@ -18,16 +20,36 @@ use log::error;
 // я предпологаю что ее порождает serde_json::Error но я так и не смог ее поймать
 
 // Тут создаю enum которое содержит типы ошибок которые я хочу обработать
+#[derive(Debug)]
 pub enum OrderError {
     Deserialization(serde_json::Error),
+    // ошибки разбора тела в MessagePack/CBOR и ошибки чтения самого тела
+    Decode(String),
+    UnsupportedMediaType(String),
+    // тело запроса больше лимита
+    PayloadTooLarge(String),
     Timeout,
     Validation{msg: String, field: String},
+    // заказ или трек номер не найден
+    NotFound{msg: String, field: String},
+    // нет или неверные учетные данные
+    Unauthorized(String),
+    // учетные данные верные, но прав на это действие нет
+    Forbidden(String),
+    // запрос конфликтует с другим который еще выполняется (тот же Idempotency-Key)
+    Conflict{msg: String, field: String},
+    // запрос понятен, но выполнить его нельзя (Idempotency-Key уже использован с другим телом)
+    Unprocessable{msg: String, field: String},
+    // превышен лимит запросов клиента или сервис перегружен, retry_after - через сколько секунд повторить
+    TooManyRequests{msg: String, retry_after: u64},
     Database(tokio_postgres::Error),
+    // ошибки которые не относятся к запросу клиента (нет настроек, упала фоновая задача и т.п.)
+    Internal(String),
 }
 // Дальше я создаю трейты для преоброзования ошибок библиотек в мой тип ошибки OrderError
 impl From<tokio_postgres::Error> for OrderError {
     fn from(error: tokio_postgres::Error) -> Self {
-        error!("{}", error);
+        error!("{}", redact::db_error(&error));
         OrderError::Database(error)
     }
 }
@@ -44,17 +66,56 @@ impl fmt::Display for OrderError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             OrderError::Validation { msg, field: _ } => write!(f, "Validation error: {msg}"),
+            OrderError::NotFound { msg, field: _ } => write!(f, "Not found: {msg}"),
+            OrderError::Unauthorized(msg) => write!(f, "Unauthorized: {msg}"),
+            OrderError::Forbidden(msg) => write!(f, "Forbidden: {msg}"),
+            OrderError::Conflict { msg, field: _ } => write!(f, "Conflict: {msg}"),
+            OrderError::Unprocessable { msg, field: _ } => write!(f, "Unprocessable: {msg}"),
+            OrderError::TooManyRequests { msg, retry_after: _ } => write!(f, "Too many requests: {msg}"),
             OrderError::Deserialization(err) => write!(f, "Deserialization error: {err}"),
-            OrderError::Database(err) => write!(f, "Database error: {err}"),
+            OrderError::Decode(msg) => write!(f, "Deserialization error: {msg}"),
+            OrderError::UnsupportedMediaType(content_type) => write!(f, "Unsupported media type: {content_type}"),
+            OrderError::PayloadTooLarge(msg) => write!(f, "Payload too large: {msg}"),
+            OrderError::Database(err) => write!(f, "Database error: {}", redact::db_error(err)),
             OrderError::Timeout => write!(f, "Timeout error"),
+            OrderError::Internal(msg) => write!(f, "Internal error: {msg}"),
         }
     }
 }
-// Этот трейт использует IntoResponse для преоброзования ошибки в HTTP-ответы 
-// которые содержат статус код, сообщение и поле где произошла ошибка
-impl IntoResponse for OrderError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, message, field) = match self {
+impl OrderError {
+    // Ордера нет или он чужой для покупателя: GET /order/:order_uid отвечает 404,
+    // gRPC GetOrder - NOT_FOUND, оба берут ошибку отсюда
+    pub fn order_not_found() -> Self {
+        OrderError::NotFound {
+            msg: "Order not found".to_string(),
+            field: "order".to_string(),
+        }
+    }
+
+    // Вид ошибки для метрики order_errors_total
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OrderError::Deserialization(_) => "deserialization",
+            OrderError::Decode(_) => "decode",
+            OrderError::UnsupportedMediaType(_) => "unsupported_media_type",
+            OrderError::PayloadTooLarge(_) => "payload_too_large",
+            OrderError::Timeout => "timeout",
+            OrderError::Validation { .. } => "validation",
+            OrderError::NotFound { .. } => "not_found",
+            OrderError::Unauthorized(_) => "unauthorized",
+            OrderError::Forbidden(_) => "forbidden",
+            OrderError::Conflict { .. } => "conflict",
+            OrderError::Unprocessable { .. } => "unprocessable",
+            OrderError::TooManyRequests { .. } => "too_many_requests",
+            OrderError::Database(_) => "database",
+            OrderError::Internal(_) => "internal",
+        }
+    }
+
+    // Статус код, сообщение и поле ошибки, вынес отдельно от IntoResponse
+    // чтобы этим же можно было раскладывать ошибки по заказам в bulk запросе
+    pub fn parts(&self) -> (StatusCode, String, String) {
+        match self {
             // Это наверное выглядит странно, но мне не понравился вариант представления ошибки torio-postgres
             // и я решил ее сократить, но другого способа кроме как найти совподения в строке я не придумал.
             // Наверное это нужно было реализовать где-то в другом месте, ноя не сообразил где
@@ -63,6 +124,8 @@ impl IntoResponse for OrderError {
                     (StatusCode::CONFLICT, "Order with this UID already exists".to_string(), "order_uid".to_string())
                 } else if err.to_string().contains("chrt_id") {
                     (StatusCode::CONFLICT, "Item with this chrt_id already exists".to_string(), "chrt_id".to_string())
+                } else if err.to_string().contains("payment_pkey") {
+                    (StatusCode::CONFLICT, "Payment with this transaction already exists".to_string(), "transaction".to_string())
                 } else {
                     (StatusCode::INTERNAL_SERVER_ERROR, err.to_string(), String::new())
                 }
@@ -82,19 +145,93 @@ impl IntoResponse for OrderError {
                 // Тут тоже и самое
                 String::new(),
             ),
+            OrderError::Decode(msg) => (
+                StatusCode::BAD_REQUEST,
+                msg.clone(),
+                String::new(),
+            ),
+            OrderError::UnsupportedMediaType(content_type) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Unsupported Content-Type: {content_type}"),
+                String::new(),
+            ),
             OrderError::Validation { msg, field } => (
                 StatusCode::BAD_REQUEST,
-                msg.to_string(),
-                field.to_string(),
+                msg.clone(),
+                field.clone(),
+            ),
+            OrderError::PayloadTooLarge(msg) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                msg.clone(),
+                String::new(),
+            ),
+            OrderError::NotFound { msg, field } => (
+                StatusCode::NOT_FOUND,
+                msg.clone(),
+                field.clone(),
+            ),
+            OrderError::Unauthorized(msg) => (
+                StatusCode::UNAUTHORIZED,
+                msg.clone(),
+                String::new(),
+            ),
+            OrderError::Forbidden(msg) => (
+                StatusCode::FORBIDDEN,
+                msg.clone(),
+                String::new(),
+            ),
+            OrderError::Conflict { msg, field } => (
+                StatusCode::CONFLICT,
+                msg.clone(),
+                field.clone(),
+            ),
+            OrderError::Unprocessable { msg, field } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                msg.clone(),
+                field.clone(),
+            ),
+            OrderError::TooManyRequests { msg, retry_after: _ } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                msg.clone(),
+                String::new(),
             ),
-
             OrderError::Timeout => (
                 StatusCode::REQUEST_TIMEOUT,
                 "Timeout error".to_string(),
                 String::new(),
-            )
-        };
-        // тут отправляю готовый json с ошибкой в ответ
-        (status, Json(json!({ "success": false, "message": message, "field": field }))).into_response()
+            ),
+            OrderError::Internal(msg) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                msg.clone(),
+                String::new(),
+            ),
+        }
+    }
+}
+
+// Тело ответа с ошибкой, id запроса добавляется чтобы клиент мог найти свой запрос в логах
+pub fn error_body(message: &str, field: &str) -> Value {
+    let mut body = json!({ "success": false, "message": message, "field": field });
+    if let Some(request_id) = request_id::current() {
+        body["request_id"] = json!(request_id);
+    }
+    body
+}
+
+// чтобы OrderError можно было пробрасывать через ? в Box<dyn Error> (cli команды)
+impl std::error::Error for OrderError {}
+
+// Этот трейт использует IntoResponse для преоброзования ошибки в HTTP-ответы 
+// которые содержат статус код, сообщение и поле где произошла ошибка
+impl IntoResponse for OrderError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, message, field) = self.parts();
+        counter!("order_errors_total", "kind" => self.kind()).increment(1);
+        // тут отправляю готовый ответ с ошибкой, в том же формате что запросил клиент (JSON по умолчанию)
+        let mut response = Format::current().render(status, &error_body(&message, &field));
+        if let OrderError::TooManyRequests { retry_after, .. } = self {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,188 @@
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::sync::broadcast;
+
+use crate::{models::Order, tenant::Tenant};
+
+// Сколько событий держит канал для отстающих подписчиков, кто не успел - пропускает лишнее
+const EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderEventKind {
+    Created,
+    // в заказ добавили товар
+    ItemAdded { chrt_id: i64 },
+    // у товара поменялся статус
+    ItemStatusChanged { chrt_id: i64, status: i32 },
+}
+
+impl OrderEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrderEventKind::Created => "created",
+            OrderEventKind::ItemAdded { .. } => "item_added",
+            OrderEventKind::ItemStatusChanged { .. } => "item_status_changed",
+        }
+    }
+
+    // Событие из строки outbox, для неизвестного типа None
+    pub fn from_parts(event_type: &str, chrt_id: Option<i64>, status: Option<i32>) -> Option<Self> {
+        match (event_type, chrt_id, status) {
+            ("created", _, _) => Some(OrderEventKind::Created),
+            ("item_added", Some(chrt_id), _) => Some(OrderEventKind::ItemAdded { chrt_id }),
+            ("item_status_changed", Some(chrt_id), Some(status)) => {
+                Some(OrderEventKind::ItemStatusChanged { chrt_id, status })
+            }
+            _ => None,
+        }
+    }
+
+    // товар к которому относится событие
+    pub fn chrt_id(self) -> Option<i64> {
+        match self {
+            OrderEventKind::Created => None,
+            OrderEventKind::ItemAdded { chrt_id } | OrderEventKind::ItemStatusChanged { chrt_id, .. } => Some(chrt_id),
+        }
+    }
+
+    // новый статус товара, только для item_status_changed
+    pub fn status(self) -> Option<i32> {
+        match self {
+            OrderEventKind::ItemStatusChanged { status, .. } => Some(status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderEvent {
+    pub id: u64,
+    pub kind: OrderEventKind,
+    // арендатор заказа, подписчики получают события только своего
+    pub tenant: Tenant,
+    pub order: Arc<Order>,
+}
+
+impl OrderEvent {
+    // Событие в JSON для SSE и WebSocket: вид события, товар если событие про товар и заказ целиком
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "id": self.id,
+            "kind": self.kind.as_str(),
+            "order": self.order.as_ref(),
+        });
+        if let Some(chrt_id) = self.kind.chrt_id() {
+            value["chrt_id"] = json!(chrt_id);
+        }
+        if let OrderEventKind::ItemStatusChanged { status, .. } = self.kind {
+            value["status"] = json!(status);
+        }
+        value
+    }
+}
+
+// Фильтр подписки, пустые поля не фильтруют
+#[derive(Debug, Default, Deserialize)]
+pub struct OrderEventFilter {
+    pub delivery_service: Option<String>,
+    pub customer_id: Option<String>,
+    // не из query: ставится по арендатору подписчика
+    #[serde(skip)]
+    pub tenant: Option<Tenant>,
+}
+
+impl OrderEventFilter {
+    pub fn matches(&self, event: &OrderEvent) -> bool {
+        self.tenant.as_ref().is_none_or(|t| *t == event.tenant)
+            && self.delivery_service.as_ref().is_none_or(|s| *s == event.order.delivery_service)
+            && self.customer_id.as_ref().is_none_or(|c| *c == event.order.customer_id)
+    }
+}
+
+// Последние события в памяти, по ним подписчик может догнать то что пропустил (Last-Event-ID)
+struct EventLog {
+    next_id: u64,
+    events: VecDeque<OrderEvent>,
+    capacity: usize,
+}
+
+// Подписка: события из журнала после last_event_id и приемник для новых.
+// missed - часть событий уже вытеснена из журнала и догнать их не получится
+pub struct Subscription {
+    pub missed: bool,
+    pub backlog: Vec<OrderEvent>,
+    pub receiver: broadcast::Receiver<OrderEvent>,
+}
+
+// Шина событий по заказам внутри процесса, события в нее отправляет relay из таблицы outbox (src/outbox.rs),
+// подписчики (gRPC WatchOrders, SSE, WebSocket) получают новые события и могут дочитать недавние из журнала.
+// id события - id строки outbox, так id не повторяются между рестартами и растут по порядку событий
+#[derive(Clone)]
+pub struct OrderEvents {
+    sender: broadcast::Sender<OrderEvent>,
+    log: Arc<Mutex<EventLog>>,
+}
+
+impl OrderEvents {
+    pub fn new(log_capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        OrderEvents {
+            sender,
+            log: Arc::new(Mutex::new(EventLog {
+                next_id: 0,
+                events: VecDeque::with_capacity(log_capacity),
+                capacity: log_capacity,
+            })),
+        }
+    }
+
+    // С какого id relay начнет отправлять события после запуска. Журнал после рестарта пустой,
+    // так клиент со старым Last-Event-ID узнает что часть событий до рестарта он пропустил
+    pub fn resume_at(&self, next_id: u64) {
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        log.next_id = log.next_id.max(next_id);
+    }
+
+    // Событие с уже отправленным id пропускается: relay доставляет как минимум один раз
+    // и после сбоя может прислать то же событие повторно
+    pub fn publish(&self, event: OrderEvent) {
+        // отправка под той же блокировкой что и запись в журнал, иначе подписчик
+        // мог бы получить событие и из журнала и из канала, или не получить вовсе
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        if event.id < log.next_id {
+            debug!("Order event {} already published, skipping", event.id);
+            return;
+        }
+        log.next_id = event.id + 1;
+        let kind = event.kind;
+        if log.capacity > 0 {
+            if log.events.len() == log.capacity {
+                log.events.pop_front();
+            }
+            log.events.push_back(event.clone());
+        }
+        // ошибка только если нет ни одного подписчика, это нормально
+        let receivers = self.sender.send(event).unwrap_or(0);
+        debug!("Order event {} published to {receivers} subscribers", kind.as_str());
+    }
+
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Subscription {
+        let log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        let receiver = self.sender.subscribe();
+        let Some(last_event_id) = last_event_id else {
+            return Subscription { missed: false, backlog: Vec::new(), receiver };
+        };
+        // самое старое событие которое клиент мог бы получить следующим
+        let oldest = log.events.front().map_or(log.next_id, |event| event.id);
+        Subscription {
+            missed: last_event_id.saturating_add(1) < oldest,
+            backlog: log.events.iter().filter(|event| event.id > last_event_id).cloned().collect(),
+            receiver,
+        }
+    }
+}
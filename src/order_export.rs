@@ -0,0 +1,259 @@
+use axum::body::Bytes;
+use clap::ValueEnum;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::sync::mpsc;
+use tokio_postgres::{Client, Row};
+
+use crate::{
+    models::{Item, Order},
+    order_errors::OrderError,
+    order_impl::ORDER_SELECT,
+    tenant::{self, Tenant},
+};
+
+// Сколько строк забирать из курсора за раз
+const FETCH_SIZE: i32 = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Ndjson,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+// Те же фильтры что и у GET /orders, только limit/offset считаются по заказам, а не по строкам JOIN,
+// без limit выгружается все
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    pub format: ExportFormat,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// Строка CSV: доставка и оплата разворачиваются в колонки, на каждый товар своя строка
+#[derive(Serialize)]
+struct OrderCsvRow<'a> {
+    order_uid: &'a str,
+    track_number: &'a str,
+    entry: &'a str,
+    delivery_service: &'a str,
+    customer_id: &'a str,
+    shardkey: &'a str,
+    sm_id: i32,
+    date_created: &'a str,
+    oof_shard: &'a str,
+    delivery_name: &'a str,
+    delivery_phone: &'a str,
+    delivery_zip: &'a str,
+    delivery_city: &'a str,
+    delivery_address: &'a str,
+    delivery_region: &'a str,
+    delivery_email: &'a str,
+    payment_transaction: &'a str,
+    payment_request_id: &'a str,
+    payment_currency: &'a str,
+    payment_provider: &'a str,
+    payment_amount: i32,
+    payment_dt: i64,
+    payment_bank: &'a str,
+    payment_delivery_cost: i32,
+    payment_goods_total: i32,
+    payment_custom_fee: i32,
+    item_chrt_id: i64,
+    item_track_number: &'a str,
+    item_price: i32,
+    item_rid: &'a str,
+    item_name: &'a str,
+    item_sale: i32,
+    item_size: &'a str,
+    item_total_price: i32,
+    item_nm_id: i64,
+    item_brand: &'a str,
+    item_status: i32,
+}
+
+impl<'a> OrderCsvRow<'a> {
+    fn new(order: &'a Order, item: &'a Item) -> Self {
+        OrderCsvRow {
+            order_uid: &order.order_uid,
+            track_number: &order.track_number,
+            entry: &order.entry,
+            delivery_service: &order.delivery_service,
+            customer_id: &order.customer_id,
+            shardkey: &order.shardkey,
+            sm_id: order.sm_id,
+            date_created: &order.date_created,
+            oof_shard: &order.oof_shard,
+            delivery_name: &order.delivery.name,
+            delivery_phone: &order.delivery.phone,
+            delivery_zip: &order.delivery.zip,
+            delivery_city: &order.delivery.city,
+            delivery_address: &order.delivery.address,
+            delivery_region: &order.delivery.region,
+            delivery_email: &order.delivery.email,
+            payment_transaction: &order.payment.transaction,
+            payment_request_id: &order.payment.request_id,
+            payment_currency: &order.payment.currency,
+            payment_provider: &order.payment.provider,
+            payment_amount: order.payment.amount,
+            payment_dt: order.payment.payment_dt,
+            payment_bank: &order.payment.bank,
+            payment_delivery_cost: order.payment.delivery_cost,
+            payment_goods_total: order.payment.goods_total,
+            payment_custom_fee: order.payment.custom_fee,
+            item_chrt_id: item.chrt_id,
+            item_track_number: &item.track_number,
+            item_price: item.price,
+            item_rid: &item.rid,
+            item_name: &item.name,
+            item_sale: item.sale,
+            item_size: &item.size,
+            item_total_price: item.total_price,
+            item_nm_id: item.nm_id,
+            item_brand: &item.brand,
+            item_status: item.status,
+        }
+    }
+}
+
+// Собирает кусок выгрузки из пачки строк. Для NDJSON заказ пишется только когда начался следующий,
+// потому что товары одного заказа могут попасть в разные пачки из курсора
+struct ExportWriter {
+    format: ExportFormat,
+    pending: Option<Order>,
+    header_written: bool,
+    orders: usize,
+}
+
+impl ExportWriter {
+    fn new(format: ExportFormat) -> Self {
+        ExportWriter {
+            format,
+            pending: None,
+            header_written: false,
+            orders: 0,
+        }
+    }
+
+    fn write_rows(&mut self, rows: &[Row]) -> Result<Vec<u8>, OrderError> {
+        let mut buf = Vec::new();
+        match self.format {
+            ExportFormat::Ndjson => {
+                for row in rows {
+                    let order_uid: String = row.get("order_uid");
+                    match self.pending.as_mut() {
+                        Some(order) if order.order_uid == order_uid => {
+                            order.items.push(Item::from_row(row));
+                        }
+                        _ => {
+                            if let Some(order) = self.pending.replace(Order::from_row(row)) {
+                                self.write_order(&mut buf, &order)?;
+                            }
+                        }
+                    }
+                }
+            }
+            ExportFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(!self.header_written)
+                    .from_writer(&mut buf);
+                for row in rows {
+                    let order = Order::from_row(row);
+                    if self.pending.as_ref().map(|o| &o.order_uid) != Some(&order.order_uid) {
+                        self.orders += 1;
+                    }
+                    writer
+                        .serialize(OrderCsvRow::new(&order, &order.items[0]))
+                        .map_err(|e| OrderError::Internal(e.to_string()))?;
+                    self.header_written = true;
+                    self.pending = Some(order);
+                }
+                writer.flush().map_err(|e| OrderError::Internal(e.to_string()))?;
+            }
+        }
+        Ok(buf)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, OrderError> {
+        let mut buf = Vec::new();
+        if self.format == ExportFormat::Ndjson {
+            if let Some(order) = self.pending.take() {
+                self.write_order(&mut buf, &order)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    fn write_order(&mut self, buf: &mut Vec<u8>, order: &Order) -> Result<(), OrderError> {
+        serde_json::to_writer(&mut *buf, order)?;
+        buf.push(b'\n');
+        self.orders += 1;
+        Ok(())
+    }
+}
+
+// Выгрузка заказов через серверный курсор (portal): строки забираются пачками по FETCH_SIZE
+// и сразу уходят в канал, так что в памяти никогда не лежит вся выгрузка.
+// Канал ограниченный, если читатель не успевает - курсор ждет.
+// Нужно отдельное соединение, иначе курсор держал бы общий клиент сервера все время выгрузки
+pub async fn stream_orders(
+    mut client: Client,
+    tenant: &Tenant,
+    params: &ExportParams,
+    chunks: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<usize, OrderError> {
+    let query = format!(
+        "{ORDER_SELECT}
+        WHERE o.tenant_id = $3 AND o.order_uid IN (
+            SELECT order_uid FROM orders WHERE tenant_id = $3 ORDER BY order_uid LIMIT $1 OFFSET $2
+        )
+        ORDER BY o.order_uid, i.chrt_id"
+    );
+    let offset = params.offset.unwrap_or(0);
+
+    let tx = client.transaction().await?;
+    tenant::bind(&tx, tenant).await?;
+    let statement = tx.prepare(&query).await?;
+    let portal = tx.bind(&statement, &[&params.limit, &offset, &tenant.as_str()]).await?;
+
+    let mut writer = ExportWriter::new(params.format);
+    loop {
+        let rows = tx.query_portal(&portal, FETCH_SIZE).await?;
+        if rows.is_empty() {
+            break;
+        }
+        let chunk = writer.write_rows(&rows)?;
+        if chunks.send(Ok(Bytes::from(chunk))).await.is_err() {
+            info!("Export receiver closed, stopping");
+            return Ok(writer.orders);
+        }
+    }
+    let chunk = writer.finish()?;
+    if !chunk.is_empty() {
+        // если читатель уже ушел, дописывать некуда
+        let _ = chunks.send(Ok(Bytes::from(chunk))).await;
+    }
+    tx.commit().await?;
+
+    Ok(writer.orders)
+}
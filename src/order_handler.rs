@@ -1,27 +1,44 @@
-use log::{info, error, debug};
+use log::{info, error, debug, warn};
 use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Json},
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json,
+    },
     // Extension,
     extract::{State, Path, Query}
 };
 use serde_json::json;
-use std::sync::Arc;
-use tokio::{
-    sync::Mutex,
-    time::{timeout, Duration}
-};
-use tokio_postgres::{Client, Row};
+use std::{convert::Infallible, io, sync::Arc};
+use tokio::sync::{broadcast::error::RecvError, mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{field::Empty, instrument, Instrument, Span};
 // импортиру собственные модули
 use crate::{
+    auth::Principal,
+    config::{self, CustomerPolicy},
+    db::Db,
+    get_db,
     models::{
-        Order, OrderResponse, Pagination
+        BulkMode, BulkOrderResult, BulkParams, BulkResponse, BulkStatus, Item, ItemStatusUpdate, Order, OrderResponse, Pagination,
+        TrackingResponse, TrackingView
     },
-    order_errors::OrderError
+    monitoring,
+    order_cache::OrderCache,
+    order_bulk::{insert_valid, read_orders, validate_batch, BatchOutcome},
+    negotiation::Negotiated,
+    order_export::{stream_orders, ExportParams},
+    order_errors::OrderError,
+    order_events::{OrderEvent, OrderEventFilter, OrderEvents, Subscription},
+    order_impl::db_timeout,
+    request_id,
+    tenant::Tenant,
 };
 
 
 
+#[instrument(skip_all, fields(tenant = %tenant, order_uid = %payload.order_uid))]
 pub async fn create_order(
     // Получение клиента из базы
     // Если я правильно понял обсуждение https://github.com/tokio-rs/axum/discussions/1830
@@ -29,234 +46,345 @@ pub async fn create_order(
     // Mutex - позволяет только одному потоку получить доступ к данным в один момент времени.
     // Arc - для безопасного совместного использования клиента между несколькими потоками
     // Extension(client): Extension<Arc<Mutex<Client>>>,
-    State(client): State<Arc<Mutex<Client>>>,
-    Json(payload): Json<Order>,
+    State(db): State<Db>,
+    State(policy): State<CustomerPolicy>,
+    tenant: Tenant,
+    Negotiated(payload): Negotiated<Order>,
 ) -> Result<impl IntoResponse, OrderError> {
     info!("Deserialized delivery payload: {:?}", payload);
-    // Обработка ошибок валидации
-    if let Err(e) = payload.validate_fields() {
-        debug!("{}", e);
-        return Err(e);
-    }
     // создание клиента 
     // lock ловит блокировку Mutex если она уже захвачена другим потоком то текущий поток будет заблокирован
     // await тут мы ждем пока блокировка Mutex не будет захвачена
-    let mut client = client.lock().await;
+    let mut client = db.lock().await;
     info!("Received order creation request: {:?}", payload);
-    // создаем транзакцию
-    // используем tokio timeout для 
-    let transaction = timeout(
-        Duration::from_secs(5), // Устанавливаем таймаут на 5 секунд
-        client.transaction(),
-    )
-    .await
-    .map_err(|_| {
-        error!("Transaction start timed out");
-        OrderError::Timeout
-    })?
-    .map_err(|e| {
-        error!("Failed to start transaction: {}", e);
-        OrderError::Database(e)
-    })?;
+    // валидация, транзакция и комит живут в Order::create, их же использует gRPC сервис
+    payload.create(&mut client, db.statements(), &tenant, policy).await?;
 
-    // подготавливаю данные для комита в базу
-    payload.insert_customer(&transaction).await?;
+    info!("Order created successfully for tenant {}: {:?}", tenant, payload);
+    Ok((
+        StatusCode::CREATED,
+        Negotiated(json!({"success": true, "message": "Order created"})),
+    ))
+}
 
-    payload.insert_order(&transaction).await?;
+// Пачка заказов JSON массивом или NDJSON (Content-Type: application/x-ndjson),
+// результат отдается по каждому заказу отдельно
+#[instrument(skip_all, fields(tenant = %tenant, mode = ?params.mode, orders = Empty, created = Empty, failed = Empty))]
+pub async fn create_orders_bulk(
+    State(db): State<Db>,
+    State(policy): State<CustomerPolicy>,
+    tenant: Tenant,
+    Query(params): Query<BulkParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, OrderError> {
+    let ndjson = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-ndjson") || value.starts_with("application/ndjson"));
 
-    payload.insert_payment(&transaction).await?;
+    // тело читается потоком, DefaultBodyLimit на Body не действует, лимит проверяется при чтении
+    let orders = read_orders(body, ndjson, config::bulk_body_limit()).await?;
+    if orders.is_empty() {
+        return Err(OrderError::Validation {
+            msg: "No orders in request".to_string(),
+            field: "orders".to_string(),
+        });
+    }
+    info!("Received bulk order request: {} orders, mode {:?}", orders.len(), params.mode);
+    Span::current().record("orders", orders.len());
 
-    payload.insert_items(&transaction).await?;
-    // комитим
-    timeout(
-        Duration::from_secs(5),
-        transaction.commit(),
-    )
-    .await
-    .map_err(|_| {
-        error!("Commit timed out");
-        OrderError::Timeout
-    })?
-    .map_err(|e| {
-        error!("Failed to commit transaction: {}", e);
-        OrderError::Database(e)
-    })?;
+    // все заказы проверяются до транзакции, в atomic режиме с невалидными заказами база не трогается вообще
+    let (mut results, valid) = validate_batch(orders);
+    if params.mode == BulkMode::Atomic && !results.is_empty() {
+        results.extend(valid.iter().map(|(index, order)| BulkOrderResult::skipped(*index, &order.order_uid)));
+        results.sort_by_key(|result| result.index);
+        let failed = results.len();
+        info!("Bulk order request rejected: {failed} orders not created, nothing written");
+        Span::current().record("created", 0).record("failed", failed);
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(BulkResponse {
+                success: false,
+                mode: params.mode,
+                created: 0,
+                failed,
+                results,
+            }),
+        ));
+    }
 
-    info!("Order created successfully: {:?}", payload);
+    let mut client = db.lock().await;
+    let mut transaction = db_timeout("start transaction", client.transaction()).await?;
+    let BatchOutcome { results: mut inserted, created: created_orders } =
+        insert_valid(&mut transaction, db.statements(), &tenant, valid, policy, params.mode).await;
+    results.append(&mut inserted);
+    results.sort_by_key(|result| result.index);
+    let failed = results.iter().filter(|r| r.status != BulkStatus::Created).count();
+
+    let (status, created) = if params.mode == BulkMode::Atomic && failed > 0 {
+        // в режиме atomic откатываем все, даже то что вставилось
+        transaction.rollback().await?;
+        for result in &mut results {
+            if result.status == BulkStatus::Created {
+                result.status = BulkStatus::RolledBack;
+            }
+        }
+        (StatusCode::UNPROCESSABLE_ENTITY, 0)
+    } else {
+        db_timeout("commit transaction", transaction.commit()).await?;
+        monitoring::orders_created(
+            created_orders.len(),
+            created_orders.iter().map(|order| order.items.len()).sum(),
+        );
+        let status = if failed == 0 { StatusCode::CREATED } else { StatusCode::OK };
+        (status, results.len() - failed)
+    };
+
+    info!("Bulk order request finished: {} created, {} failed", created, failed);
+    Span::current().record("created", created).record("failed", failed);
     Ok((
-        StatusCode::CREATED,
-        Json(json!({"success": true, "message": "Order created"})),
+        status,
+        Json(BulkResponse {
+            success: failed == 0,
+            mode: params.mode,
+            created,
+            failed,
+            results,
+        }),
     ))
 }
 
+#[instrument(skip_all, fields(tenant = %tenant, order_uid = %order_uid))]
 pub async fn get_order_by_id(
     Path(order_uid): Path<String>,
-    State(client): State<Arc<Mutex<Client>>>,
+    State(db): State<Db>,
+    State(cache): State<OrderCache>,
+    principal: Principal,
+    tenant: Tenant,
     // Extension(client): Extension<Arc<Mutex<Client>>>
-) -> Result<Json<Order>, OrderError> {
-    let client = client.lock().await;
+) -> Result<Negotiated<Order>, OrderError> {
+    let client = db.lock().await;
     // Интересно есть ли какой то способ это записывать более красиво может какой-то orm типо алхимии в питоне
-    let query = "
-            SELECT 
-                o.order_uid, 
-                o.track_number, 
-                o.entry, 
-                o.delivery_service, 
-                o.customer_id, 
-                o.shardkey, 
-                o.sm_id, 
-                TO_CHAR(o.date_created, 'YYYY-MM-DD HH24:MI:SS') AS date_created, 
-                o.oof_shard,
-                d.name, 
-                d.phone, 
-                d.zip, 
-                d.city, 
-                d.address, 
-                d.region, 
-                d.email,
-                p.transaction, 
-                p.request_id, 
-                p.currency, 
-                p.provider, 
-                p.amount, 
-                CAST(EXTRACT(EPOCH FROM p.payment_dt) AS bigint) AS payment_unix_timestamp, 
-                p.bank, 
-                p.delivery_cost, 
-                p.goods_total, 
-                p.custom_fee,
-                i.chrt_id, 
-                i.track_number, 
-                i.price, 
-                i.rid, 
-                i.name, 
-                i.sale, 
-                i.size, 
-                i.total_price, 
-                i.nm_id, 
-                i.brand, 
-                i.status
-            FROM 
-                orders o
-            JOIN 
-                customers d ON o.customer_id = d.customer_id
-            JOIN 
-                payment p ON o.order_uid = p.order_uid
-            JOIN 
-                items i ON o.order_uid = i.order_uid
-        WHERE o.order_uid = $1
-    ";
+    // чужой ордер для покупателя выглядит так же как несуществующий
+    let Some(order) = cache
+        .find_by_id(&client, &tenant, &order_uid)
+        .await?
+        .filter(|order| principal.can_read(order))
+    else {
+        return Err(OrderError::order_not_found());
+    };
 
-    let rows: Vec<Row> = timeout(
-        Duration::from_secs(5),
-        client
-        .query(query, &[&order_uid])
-    )
-        .await
-        .map_err(|_| {
-            error!("query timed out");
-            OrderError::Timeout
-        })?
-        .map_err(|e| {
-            error!("Failed query: {}", e);
-            OrderError::Database(e)
-        })?;
+    Ok(Negotiated(order))
+}
 
+#[instrument(skip_all, fields(tenant = %tenant, track_number = %track_number))]
+pub async fn get_orders_by_track(
+    Path(track_number): Path<String>,
+    State(db): State<Db>,
+    tenant: Tenant,
+) -> Result<Json<OrderResponse>, OrderError> {
+    let client = db.lock().await;
+    let orders = Order::find_by_track(&client, &tenant, &track_number).await?;
+    info!("Found {} orders by track number {}", orders.len(), track_number);
+    if orders.is_empty() {
+        return Err(OrderError::NotFound {
+            msg: "Track number not found".to_string(),
+            field: "track_number".to_string(),
+        });
+    }
 
-    if rows.is_empty() {
-        return Err(OrderError::Validation {
-            msg: "Order not found".to_string(),
-            field: "order".to_string(),
+    Ok(Json(OrderResponse { orders }))
+}
+
+// Публичный трекинг, отдает только то что можно показать кому угодно у кого есть трек номер.
+// Арендатор из X-Tenant-ID, без него - арендатор по умолчанию
+#[instrument(skip_all, fields(tenant = %tenant, track_number = %track_number))]
+pub async fn get_tracking(
+    Path(track_number): Path<String>,
+    State(db): State<Db>,
+    tenant: Tenant,
+) -> Result<Json<TrackingResponse>, OrderError> {
+    let client = db.lock().await;
+    let orders = Order::find_by_track(&client, &tenant, &track_number).await?;
+
+    if orders.is_empty() {
+        return Err(OrderError::NotFound {
+            msg: "Track number not found".to_string(),
+            field: "track_number".to_string(),
         });
     }
 
-    let order = Order::from_row(&rows[0]);
+    let tracking = orders
+        .iter()
+        .map(|order| TrackingView::from_order(order, &track_number))
+        .collect();
 
-    Ok(Json(order))
+    Ok(Json(TrackingResponse { tracking }))
 }
 
+#[instrument(skip_all, fields(tenant = %tenant, limit = ?pagination.limit, offset = ?pagination.offset))]
 pub async fn get_orders(
-    State(client): State<Arc<Mutex<Client>>>,
+    State(db): State<Db>,
+    principal: Principal,
+    tenant: Tenant,
     Query(pagination): Query<Pagination>,
-) -> Result<Json<OrderResponse>, OrderError> {
+) -> Result<Negotiated<OrderResponse>, OrderError> {
     let limit = pagination.limit.unwrap_or(10);
     let offset = pagination.offset.unwrap_or(0);
 
-    let client = client.lock().await;
+    let client = db.lock().await;
+    let orders = Order::list_rows(&client, &tenant, limit, offset, principal.customer_id.as_deref()).await?;
 
-    let rows = timeout(
-        Duration::from_secs(5),
-        client
-        .query(
-            r#"
-            SELECT 
-                o.order_uid, 
-                o.track_number, 
-                o.entry, 
-                o.delivery_service, 
-                o.customer_id, 
-                o.shardkey, 
-                o.sm_id, 
-                TO_CHAR(o.date_created, 'YYYY-MM-DD HH24:MI:SS') AS date_created, 
-                o.oof_shard,
-                d.name, 
-                d.phone, 
-                d.zip, 
-                d.city, 
-                d.address, 
-                d.region, 
-                d.email,
-                p.transaction, 
-                p.request_id, 
-                p.currency, 
-                p.provider, 
-                p.amount, 
-                CAST(EXTRACT(EPOCH FROM p.payment_dt) AS bigint) AS payment_unix_timestamp, 
-                p.bank, 
-                p.delivery_cost, 
-                p.goods_total, 
-                p.custom_fee,
-                i.chrt_id, 
-                i.track_number, 
-                i.price, 
-                i.rid, 
-                i.name, 
-                i.sale, 
-                i.size, 
-                i.total_price, 
-                i.nm_id, 
-                i.brand, 
-                i.status
-            FROM 
-                orders o
-            JOIN 
-                customers d ON o.customer_id = d.customer_id
-            JOIN 
-                payment p ON o.order_uid = p.order_uid
-            JOIN 
-                items i ON o.order_uid = i.order_uid
-            LIMIT $1 OFFSET $2
-            "#,
-            &[&limit, &offset],
-        )
-    )
-        .await
-        .map_err(|_| {
-            error!("query timed out");
-            OrderError::Timeout
-        })?
-        .map_err(|e| {
-            error!("Failed to query: {}", e);
-            OrderError::Database(e)
-        })?;
+    Ok(Negotiated(OrderResponse { orders }))
+}
+
+// Смена статуса товара, подписчики получают событие item_status_changed
+#[instrument(skip_all, fields(tenant = %tenant, order_uid = %order_uid, chrt_id))]
+pub async fn update_item_status(
+    Path((order_uid, chrt_id)): Path<(String, i64)>,
+    State(db): State<Db>,
+    State(cache): State<OrderCache>,
+    tenant: Tenant,
+    Negotiated(update): Negotiated<ItemStatusUpdate>,
+) -> Result<impl IntoResponse, OrderError> {
+    let mut client = db.lock().await;
+    Order::update_item_status(&mut client, db.statements(), &tenant, &order_uid, chrt_id, update.status).await?;
+    // свой кэш сбрасываем сразу, остальные экземпляры узнают через order_changes
+    cache.invalidate(&order_uid);
+    info!("Item {} of order {} changed status to {}", chrt_id, order_uid, update.status);
+    Ok(Negotiated(json!({"success": true, "message": "Item status updated"})))
+}
+
+// Добавление товара в существующий заказ, подписчики получают событие item_added
+#[instrument(skip_all, fields(tenant = %tenant, order_uid = %order_uid, chrt_id = item.chrt_id))]
+pub async fn add_order_item(
+    Path(order_uid): Path<String>,
+    State(db): State<Db>,
+    State(cache): State<OrderCache>,
+    tenant: Tenant,
+    Negotiated(item): Negotiated<Item>,
+) -> Result<impl IntoResponse, OrderError> {
+    let mut client = db.lock().await;
+    Order::add_item(&mut client, db.statements(), &tenant, &order_uid, &item).await?;
+    cache.invalidate(&order_uid);
+    info!("Item {} added to order {}", item.chrt_id, order_uid);
+    Ok((
+        StatusCode::CREATED,
+        Negotiated(json!({"success": true, "message": "Item added"})),
+    ))
+}
+
+// Выгрузка всех заказов потоком в NDJSON или CSV, тело отдается по мере чтения из курсора
+#[instrument(skip_all, fields(tenant = %tenant, format = ?params.format))]
+pub async fn export_orders(
+    State(exports): State<Arc<Semaphore>>,
+    tenant: Tenant,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, OrderError> {
+    // разрешение держится пока выгрузка не закончится, вместе с ним закрывается и соединение
+    let permit = exports.try_acquire_owned().map_err(|_| OrderError::TooManyRequests {
+        msg: "Too many exports in progress, try again later".to_string(),
+        retry_after: 5,
+    })?;
+    let client = get_db().await?;
+    let format = params.format;
+    info!("Starting orders export for tenant {}: {:?}", tenant, params);
+
+    let (chunks, receiver) = mpsc::channel(16);
+    // выгрузка идет дольше хендлера, спан закрывается когда она закончится
+    request_id::spawn(
+        async move {
+            match stream_orders(client, &tenant, &params, &chunks).await {
+                Ok(orders) => info!("Exported {} orders", orders),
+                Err(e) => {
+                    error!("Orders export failed: {}", e);
+                    // заголовки уже ушли, поэтому просто обрываем тело, чтобы клиент не принял обрезанный файл за целый
+                    let _ = chunks.send(Err(io::Error::other(e.to_string()))).await;
+                }
+            }
+            drop(permit);
+        }
+        .in_current_span(),
+    );
 
-        let mut orders = Vec::new();
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"orders.{}\"", format.extension()),
+        ),
+    ];
+    Ok((headers, Body::from_stream(ReceiverStream::new(receiver))))
+}
+
+// Поток событий по заказам (SSE). После переподключения браузер присылает Last-Event-ID,
+// тогда сначала отдаются события из журнала которые клиент пропустил, потом новые
+#[instrument(skip_all, fields(tenant = %tenant))]
+pub async fn stream_order_events(
+    State(events): State<OrderEvents>,
+    tenant: Tenant,
+    Query(mut filter): Query<OrderEventFilter>,
+    headers: HeaderMap,
+) -> Sse<ReceiverStream<Result<Event, Infallible>>> {
+    filter.tenant = Some(tenant);
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    info!("New order events subscriber: {:?}, last event id {:?}", filter, last_event_id);
+
+    let subscription = events.subscribe(last_event_id);
+    let (sender, receiver) = mpsc::channel(16);
+    request_id::spawn(forward_order_events(subscription, filter, sender));
+    Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default())
+}
 
-        for row in rows {
-            let order = Order::from_row(&row);
-            orders.push(order);
+async fn forward_order_events(
+    subscription: Subscription,
+    filter: OrderEventFilter,
+    sender: mpsc::Sender<Result<Event, Infallible>>,
+) {
+    let Subscription { missed, backlog, mut receiver } = subscription;
+    // часть событий уже не догнать, клиенту стоит перечитать заказы через GET /orders
+    if missed && sender.send(Ok(Event::default().event("lagged").data("{}"))).await.is_err() {
+        return;
+    }
+    for event in backlog {
+        if filter.matches(&event) && sender.send(Ok(sse_event(&event))).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        // клиент может отключиться пока событий нет, тогда задача заканчивается сразу, а не на следующем send
+        let next = tokio::select! {
+            next = receiver.recv() => next,
+            () = sender.closed() => {
+                debug!("Order events subscriber disconnected");
+                return;
+            }
+        };
+        let event = match next {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Order events subscriber lagged behind, {skipped} events skipped");
+                if sender.send(Ok(Event::default().event("lagged").data("{}"))).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if filter.matches(&event) && sender.send(Ok(sse_event(&event))).await.is_err() {
+            debug!("Order events subscriber disconnected");
+            return;
         }
-    
-        let response = OrderResponse { orders };
+    }
+}
 
-    Ok(Json(response))
-}
\ No newline at end of file
+fn sse_event(event: &OrderEvent) -> Event {
+    Event::default()
+        .id(event.id.to_string())
+        .event(event.kind.as_str())
+        .data(event.to_json().to_string())
+}
@@ -1,10 +1,187 @@
-use tokio_postgres::Transaction;
-use crate::models::{Order, Delivery, Payment, Item};
+use tokio_postgres::{Client, Transaction};
+use tokio::time::{timeout, Duration};
+use crate::models::{Order, Delivery, Payment, Item, TrackingView, TrackingItem};
 use crate::order_errors::OrderError;
+use crate::config::CustomerPolicy;
+use crate::order_events::OrderEventKind;
+use crate::statements::StatementCache;
+use crate::tenant::{self, Tenant};
+use crate::{monitoring, redact, telemetry::{self, RowCount}};
 use serde_json::Value;
-use log::error;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::LazyLock;
+use log::{debug, error};
+use std::future::Future;
+use std::time::Instant;
+use tracing::Instrument;
 use tokio_postgres::Row;
 
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Общая часть запроса на чтение заказов, WHERE/LIMIT дописываются в хендлерах.
+// Доставка берется из снимка order_delivery если он есть, иначе из текущей записи покупателя.
+// Все JOIN идут и по tenant_id, фильтр по арендатору (o.tenant_id) обязателен в WHERE
+pub const ORDER_SELECT: &str = "
+            SELECT 
+                o.tenant_id,
+                o.order_uid, 
+                o.track_number, 
+                o.entry, 
+                o.delivery_service, 
+                o.customer_id, 
+                o.shardkey, 
+                o.sm_id, 
+                TO_CHAR(o.date_created, 'YYYY-MM-DD HH24:MI:SS') AS date_created, 
+                o.oof_shard,
+                COALESCE(od.name, d.name) AS name, 
+                COALESCE(od.phone, d.phone) AS phone, 
+                COALESCE(od.zip, d.zip) AS zip, 
+                COALESCE(od.city, d.city) AS city, 
+                COALESCE(od.address, d.address) AS address, 
+                COALESCE(od.region, d.region) AS region, 
+                COALESCE(od.email, d.email) AS email,
+                p.transaction, 
+                p.request_id, 
+                p.currency, 
+                p.provider, 
+                p.amount, 
+                CAST(EXTRACT(EPOCH FROM p.payment_dt) AS bigint) AS payment_unix_timestamp, 
+                p.bank, 
+                p.delivery_cost, 
+                p.goods_total, 
+                p.custom_fee,
+                i.chrt_id, 
+                i.track_number AS item_track_number, 
+                i.price, 
+                i.rid, 
+                i.name AS item_name, 
+                i.sale, 
+                i.size, 
+                i.total_price, 
+                i.nm_id, 
+                i.brand, 
+                i.status
+            FROM 
+                orders o
+            JOIN 
+                customers d ON o.tenant_id = d.tenant_id AND o.customer_id = d.customer_id
+            LEFT JOIN 
+                order_delivery od ON o.tenant_id = od.tenant_id AND o.order_uid = od.order_uid
+            JOIN 
+                payment p ON o.tenant_id = p.tenant_id AND o.order_uid = p.order_uid
+            JOIN 
+                items i ON o.tenant_id = i.tenant_id AND o.order_uid = i.order_uid
+";
+
+// Многострочные вставки: каждая колонка передается массивом и разворачивается через UNNEST
+const INSERT_CUSTOMERS_KEEP_FIRST: &str = "
+            INSERT INTO customers (tenant_id, customer_id, name, phone, zip, city, address, region, email)
+            SELECT $9::varchar, * FROM UNNEST(
+                $1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[],
+                $5::varchar[], $6::varchar[], $7::varchar[], $8::varchar[]
+            )
+            ON CONFLICT (tenant_id, customer_id) DO NOTHING";
+
+const INSERT_CUSTOMERS_OVERWRITE: &str = "
+            INSERT INTO customers (tenant_id, customer_id, name, phone, zip, city, address, region, email)
+            SELECT $9::varchar, * FROM UNNEST(
+                $1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[],
+                $5::varchar[], $6::varchar[], $7::varchar[], $8::varchar[]
+            )
+            ON CONFLICT (tenant_id, customer_id) DO UPDATE SET
+                name = EXCLUDED.name,
+                phone = EXCLUDED.phone,
+                zip = EXCLUDED.zip,
+                city = EXCLUDED.city,
+                address = EXCLUDED.address,
+                region = EXCLUDED.region,
+                email = EXCLUDED.email";
+
+const INSERT_DELIVERY_SNAPSHOTS: &str = "
+            INSERT INTO order_delivery (tenant_id, order_uid, name, phone, zip, city, address, region, email)
+            SELECT $9::varchar, * FROM UNNEST(
+                $1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[],
+                $5::varchar[], $6::varchar[], $7::varchar[], $8::varchar[]
+            )";
+
+const INSERT_ORDERS: &str = "
+            INSERT INTO orders (
+                tenant_id,
+                order_uid, 
+                track_number, 
+                entry, 
+                customer_id, 
+                delivery_service, 
+                shardkey, 
+                sm_id, 
+                date_created, 
+                oof_shard
+            ) 
+            SELECT
+                $10::varchar, order_uid, track_number, entry, customer_id, delivery_service, shardkey, sm_id,
+                to_timestamp(date_created, 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'),
+                oof_shard
+            FROM UNNEST(
+                $1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[], $5::varchar[],
+                $6::varchar[], $7::int[], $8::varchar[], $9::varchar[]
+            ) AS u(order_uid, track_number, entry, customer_id, delivery_service, shardkey, sm_id, date_created, oof_shard)";
+
+const INSERT_PAYMENTS: &str = "
+            INSERT INTO payment (
+                tenant_id,
+                transaction, 
+                order_uid, 
+                request_id, 
+                currency, 
+                provider, 
+                amount, 
+                payment_dt, 
+                bank, 
+                delivery_cost, 
+                goods_total, 
+                custom_fee
+            )
+            SELECT
+                $12::varchar, transaction, order_uid, request_id, currency, provider, amount,
+                to_timestamp(payment_dt),
+                bank, delivery_cost, goods_total, custom_fee
+            FROM UNNEST(
+                $1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[], $5::varchar[], $6::int[],
+                $7::float8[], $8::varchar[], $9::int[], $10::int[], $11::int[]
+            ) AS u(transaction, order_uid, request_id, currency, provider, amount, payment_dt, bank, delivery_cost, goods_total, custom_fee)";
+
+const INSERT_ITEMS: &str = "
+            INSERT INTO items (
+                tenant_id,
+                chrt_id, 
+                order_uid, 
+                track_number, 
+                price, 
+                rid, 
+                name, 
+                sale, 
+                size, 
+                total_price, 
+                nm_id, 
+                brand, 
+                status
+            ) 
+            SELECT $13::varchar, * FROM UNNEST(
+                $1::bigint[], $2::varchar[], $3::varchar[], $4::int[], $5::varchar[], $6::varchar[],
+                $7::int[], $8::varchar[], $9::int[], $10::bigint[], $11::varchar[], $12::int[]
+            )";
+
+// События в outbox вместе со снимком заказа (payload), пишутся в той же транзакции что и само изменение
+const INSERT_OUTBOX: &str = "
+            INSERT INTO outbox (tenant_id, order_uid, event_type, chrt_id, status, payload)
+            SELECT $2::varchar, u.order_uid, $3::varchar, $4::bigint, $5::int, u.payload::jsonb
+            FROM UNNEST($1::varchar[], $6::text[]) AS u(order_uid, payload)";
+
+// Заказы для снимков в outbox, собирается из ORDER_SELECT один раз чтобы его можно было подготовить
+static OUTBOX_ORDERS: LazyLock<String> = LazyLock::new(|| {
+    format!("{ORDER_SELECT} WHERE o.tenant_id = $1 AND o.order_uid = ANY($2) ORDER BY o.order_uid")
+});
+
 // сдесь я реализую основные трейты для Order
 impl Order {
     // Валидация полей json и обработка ошибки
@@ -27,19 +204,17 @@ impl Order {
                                 if s.is_empty() {
                                     return Err(OrderError::Validation {
                                         msg: format!("{k} is empty"),
-                                        field: k.to_string(),
+                                        field: k,
                                     });
                                 }
                             }
                         }
                     }
-                    Value::String(s) => {
-                        if s.is_empty() {
-                            return Err(OrderError::Validation {
-                                msg: format!("{key} is empty"),
-                                field: key.to_string(),
-                            });
-                        }
+                    Value::String(s) if s.is_empty() => {
+                        return Err(OrderError::Validation {
+                            msg: format!("{key} is empty"),
+                            field: key,
+                        });
                     }
                     _ => {}
                 }
@@ -47,140 +222,287 @@ impl Order {
         }
         Ok(())
     }
+    // Сохранение одного заказа, транзакцией управляет вызывающий код
+    pub async fn save(
+        &self,
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        policy: CustomerPolicy,
+    ) -> Result<(), OrderError> {
+        Order::save_many(tx, statements, tenant, std::slice::from_ref(self), policy).await
+    }
+
+    // Сохранение пачки заказов: на каждую таблицу один многострочный INSERT через UNNEST,
+    // так что число запросов не зависит ни от количества заказов ни от количества товаров.
+    // Все строки пишутся с tenant_id арендатора
+    pub async fn save_many(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        orders: &[Order],
+        policy: CustomerPolicy,
+    ) -> Result<(), OrderError> {
+        if orders.is_empty() {
+            return Ok(());
+        }
+        tenant::bind(tx, tenant).await?;
+
+        Order::insert_customers(tx, statements, tenant, orders, policy).await?;
+
+        Order::insert_orders(tx, statements, tenant, orders).await?;
+
+        if policy == CustomerPolicy::Snapshot {
+            Order::insert_delivery_snapshots(tx, statements, tenant, orders).await?;
+        }
+
+        Order::insert_payments(tx, statements, tenant, orders).await?;
+
+        Order::insert_items(tx, statements, tenant, orders).await?;
+
+        let order_uids: Vec<&str> = orders.iter().map(|o| o.order_uid.as_str()).collect();
+        Order::insert_outbox(tx, statements, tenant, &order_uids, OrderEventKind::Created).await?;
+        Ok(())
+    }
+
     // Танцы с бубно вокруг полей содержаших типы date, в итоге конвертацию провожу на уровне sql запроса
     // что наверное не есть хорошо
     // пытался и через NativeDateTime и DateTime<Utc> но совсем запутался
 
-    pub async fn insert_customer(&self, tx: &Transaction<'_>) -> Result<(), OrderError> {
-        tx.execute(
-            "
-            INSERT INTO customers (
-                customer_id, 
-                name, 
-                phone, 
-                zip, 
-                city, 
-                address, 
-                region, 
-                email
-            ) 
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-            ON CONFLICT (customer_id) DO NOTHING",
-            &[
-                &self.customer_id,
-                &self.delivery.name,
-                &self.delivery.phone,
-                &self.delivery.zip,
-                &self.delivery.city,
-                &self.delivery.address,
-                &self.delivery.region,
-                &self.delivery.email,
-            ],
-        ).await?;
+    // Вставка покупателей, что делать если customer_id уже есть - решает политика.
+    // DO UPDATE не может обновить одну строку дважды за запрос, поэтому повторы customer_id
+    // внутри пачки схлопываю: для keep_first остается первый заказ, иначе последний.
+    // BTreeMap заодно сортирует по customer_id, это нужно чтобы параллельные пачки
+    // блокировали строки в одном порядке и не ловили deadlock
+    pub async fn insert_customers(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        orders: &[Order],
+        policy: CustomerPolicy,
+    ) -> Result<(), OrderError> {
+        let mut latest: BTreeMap<&str, &Order> = BTreeMap::new();
+        for order in orders {
+            if policy == CustomerPolicy::KeepFirst {
+                latest.entry(order.customer_id.as_str()).or_insert(order);
+            } else {
+                latest.insert(order.customer_id.as_str(), order);
+            }
+        }
+        let customers: Vec<&Order> = latest.into_values().collect();
+
+        let query = match policy {
+            CustomerPolicy::KeepFirst => INSERT_CUSTOMERS_KEEP_FIRST,
+            CustomerPolicy::OverwriteLatest | CustomerPolicy::Snapshot => INSERT_CUSTOMERS_OVERWRITE,
+        };
+        let statement = statements.prepare(tx, query).await?;
+
+        let customer_ids: Vec<&str> = customers.iter().map(|o| o.customer_id.as_str()).collect();
+        let names: Vec<&str> = customers.iter().map(|o| o.delivery.name.as_str()).collect();
+        let phones: Vec<&str> = customers.iter().map(|o| o.delivery.phone.as_str()).collect();
+        let zips: Vec<&str> = customers.iter().map(|o| o.delivery.zip.as_str()).collect();
+        let cities: Vec<&str> = customers.iter().map(|o| o.delivery.city.as_str()).collect();
+        let addresses: Vec<&str> = customers.iter().map(|o| o.delivery.address.as_str()).collect();
+        let regions: Vec<&str> = customers.iter().map(|o| o.delivery.region.as_str()).collect();
+        let emails: Vec<&str> = customers.iter().map(|o| o.delivery.email.as_str()).collect();
+
+        monitoring::time_query("insert_customers", tx.execute(
+            &statement,
+            &[&customer_ids, &names, &phones, &zips, &cities, &addresses, &regions, &emails, &tenant.as_str()],
+        )).await?;
         Ok(())
     }
 
-    pub async fn insert_order(&self, tx: &Transaction<'_>) -> Result<(), OrderError> {
-        tx.execute(
-            "
-            INSERT INTO orders (
-                order_uid, 
-                track_number, 
-                entry, 
-                customer_id, 
-                delivery_service, 
-                shardkey, 
-                sm_id, 
-                date_created, 
-                oof_shard
-            ) 
-            VALUES ($1, $2, $3, $4, $5, $6, $7, to_timestamp($8, 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'), $9)",
+    // Снимок доставки для каждого заказа, вызывается только при политике Snapshot
+    // и только после insert_orders так как ссылается на orders
+    pub async fn insert_delivery_snapshots(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        orders: &[Order],
+    ) -> Result<(), OrderError> {
+        let statement = statements.prepare(tx, INSERT_DELIVERY_SNAPSHOTS).await?;
+
+        let order_uids: Vec<&str> = orders.iter().map(|o| o.order_uid.as_str()).collect();
+        let names: Vec<&str> = orders.iter().map(|o| o.delivery.name.as_str()).collect();
+        let phones: Vec<&str> = orders.iter().map(|o| o.delivery.phone.as_str()).collect();
+        let zips: Vec<&str> = orders.iter().map(|o| o.delivery.zip.as_str()).collect();
+        let cities: Vec<&str> = orders.iter().map(|o| o.delivery.city.as_str()).collect();
+        let addresses: Vec<&str> = orders.iter().map(|o| o.delivery.address.as_str()).collect();
+        let regions: Vec<&str> = orders.iter().map(|o| o.delivery.region.as_str()).collect();
+        let emails: Vec<&str> = orders.iter().map(|o| o.delivery.email.as_str()).collect();
+
+        monitoring::time_query("insert_delivery_snapshots", tx.execute(
+            &statement,
+            &[&order_uids, &names, &phones, &zips, &cities, &addresses, &regions, &emails, &tenant.as_str()],
+        )).await?;
+        Ok(())
+    }
+
+    pub async fn insert_orders(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        orders: &[Order],
+    ) -> Result<(), OrderError> {
+        let statement = statements.prepare(tx, INSERT_ORDERS).await?;
+
+        let order_uids: Vec<&str> = orders.iter().map(|o| o.order_uid.as_str()).collect();
+        let track_numbers: Vec<&str> = orders.iter().map(|o| o.track_number.as_str()).collect();
+        let entries: Vec<&str> = orders.iter().map(|o| o.entry.as_str()).collect();
+        let customer_ids: Vec<&str> = orders.iter().map(|o| o.customer_id.as_str()).collect();
+        let delivery_services: Vec<&str> = orders.iter().map(|o| o.delivery_service.as_str()).collect();
+        let shardkeys: Vec<&str> = orders.iter().map(|o| o.shardkey.as_str()).collect();
+        let sm_ids: Vec<i32> = orders.iter().map(|o| o.sm_id).collect();
+        let dates_created: Vec<&str> = orders.iter().map(|o| o.date_created.as_str()).collect();
+        let oof_shards: Vec<&str> = orders.iter().map(|o| o.oof_shard.as_str()).collect();
+
+        monitoring::time_query("insert_orders", tx.execute(
+            &statement,
             &[
-                &self.order_uid,
-                &self.track_number,
-                &self.entry,
-                &self.customer_id,
-                &self.delivery_service,
-                &self.shardkey,
-                &self.sm_id,
-                &self.date_created,
-                &self.oof_shard,
+                &order_uids,
+                &track_numbers,
+                &entries,
+                &customer_ids,
+                &delivery_services,
+                &shardkeys,
+                &sm_ids,
+                &dates_created,
+                &oof_shards,
+                &tenant.as_str(),
             ],
-        ).await?;
+        )).await?;
         Ok(())
     }
 
-    pub async fn insert_payment(&self, tx: &Transaction<'_>) -> Result<(), OrderError> {
+    pub async fn insert_payments(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        orders: &[Order],
+    ) -> Result<(), OrderError> {
+        let statement = statements.prepare(tx, INSERT_PAYMENTS).await?;
+
+        let transactions: Vec<&str> = orders.iter().map(|o| o.payment.transaction.as_str()).collect();
+        let order_uids: Vec<&str> = orders.iter().map(|o| o.order_uid.as_str()).collect();
+        let request_ids: Vec<&str> = orders.iter().map(|o| o.payment.request_id.as_str()).collect();
+        let currencies: Vec<&str> = orders.iter().map(|o| o.payment.currency.as_str()).collect();
+        let providers: Vec<&str> = orders.iter().map(|o| o.payment.provider.as_str()).collect();
+        let amounts: Vec<i32> = orders.iter().map(|o| o.payment.amount).collect();
         // let payment_dt_str = self.payment.payment_dt.to_f32();
-        tx.execute(
-            "
-            INSERT INTO payment (
-                transaction, 
-                order_uid, 
-                request_id, 
-                currency, 
-                provider, 
-                amount, 
-                payment_dt, 
-                bank, 
-                delivery_cost, 
-                goods_total, 
-                custom_fee
-            )
-            VALUES ($1, $2, $3, $4, $5, $6, to_timestamp($7), $8, $9, $10, $11)",
+        // to_timestamp принимает double, секунды до 2^53 влезают без потерь
+        #[allow(clippy::cast_precision_loss)]
+        let payment_dts: Vec<f64> = orders.iter().map(|o| o.payment.payment_dt as f64).collect();
+        let banks: Vec<&str> = orders.iter().map(|o| o.payment.bank.as_str()).collect();
+        let delivery_costs: Vec<i32> = orders.iter().map(|o| o.payment.delivery_cost).collect();
+        let goods_totals: Vec<i32> = orders.iter().map(|o| o.payment.goods_total).collect();
+        let custom_fees: Vec<i32> = orders.iter().map(|o| o.payment.custom_fee).collect();
+
+        monitoring::time_query("insert_payments", tx.execute(
+            &statement,
             &[
-                &self.payment.transaction,
-                &self.order_uid,
-                &self.payment.request_id,
-                &self.payment.currency,
-                &self.payment.provider,
-                &self.payment.amount,
+                &transactions,
+                &order_uids,
+                &request_ids,
+                &currencies,
+                &providers,
+                &amounts,
                 // payment_dt_str
-                &(self.payment.payment_dt as f64),
-                &self.payment.bank,
-                &self.payment.delivery_cost,
-                &self.payment.goods_total,
-                &self.payment.custom_fee,
+                &payment_dts,
+                &banks,
+                &delivery_costs,
+                &goods_totals,
+                &custom_fees,
+                &tenant.as_str(),
             ],
-        ).await?;
+        )).await?;
         Ok(())
     }
 
-    pub async fn insert_items(&self, tx: &Transaction<'_>) -> Result<(), OrderError> {
-        for item in &self.items {
-            tx.execute(
-                "
-                INSERT INTO items (
-                    chrt_id, 
-                    order_uid, 
-                    track_number, 
-                    price, 
-                    rid, 
-                    name, 
-                    sale, 
-                    size, 
-                    total_price, 
-                    nm_id, 
-                    brand, 
-                    status
-                ) 
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
-                &[
-                    &item.chrt_id,
-                    &self.order_uid,
-                    &item.track_number,
-                    &item.price,
-                    &item.rid,
-                    &item.name,
-                    &item.sale,
-                    &item.size,
-                    &item.total_price,
-                    &item.nm_id,
-                    &item.brand,
-                    &item.status,
-                ],
-            ).await?;
+    // Все товары всех заказов одним запросом, раньше тут был INSERT на каждый товар
+    pub async fn insert_items(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        orders: &[Order],
+    ) -> Result<(), OrderError> {
+        let items: Vec<(&str, &Item)> = orders
+            .iter()
+            .flat_map(|o| o.items.iter().map(move |item| (o.order_uid.as_str(), item)))
+            .collect();
+        if items.is_empty() {
+            return Ok(());
         }
+
+        let statement = statements.prepare(tx, INSERT_ITEMS).await?;
+
+        let chrt_ids: Vec<i64> = items.iter().map(|(_, i)| i.chrt_id).collect();
+        let order_uids: Vec<&str> = items.iter().map(|(uid, _)| *uid).collect();
+        let track_numbers: Vec<&str> = items.iter().map(|(_, i)| i.track_number.as_str()).collect();
+        let prices: Vec<i32> = items.iter().map(|(_, i)| i.price).collect();
+        let rids: Vec<&str> = items.iter().map(|(_, i)| i.rid.as_str()).collect();
+        let names: Vec<&str> = items.iter().map(|(_, i)| i.name.as_str()).collect();
+        let sales: Vec<i32> = items.iter().map(|(_, i)| i.sale).collect();
+        let sizes: Vec<&str> = items.iter().map(|(_, i)| i.size.as_str()).collect();
+        let total_prices: Vec<i32> = items.iter().map(|(_, i)| i.total_price).collect();
+        let nm_ids: Vec<i64> = items.iter().map(|(_, i)| i.nm_id).collect();
+        let brands: Vec<&str> = items.iter().map(|(_, i)| i.brand.as_str()).collect();
+        let statuses: Vec<i32> = items.iter().map(|(_, i)| i.status).collect();
+
+        monitoring::time_query("insert_items", tx.execute(
+            &statement,
+            &[
+                &chrt_ids,
+                &order_uids,
+                &track_numbers,
+                &prices,
+                &rids,
+                &names,
+                &sales,
+                &sizes,
+                &total_prices,
+                &nm_ids,
+                &brands,
+                &statuses,
+                &tenant.as_str(),
+            ],
+        )).await?;
+        Ok(())
+    }
+
+    // Запись в outbox вместо публикации после комита: если транзакция откатится - события не будет,
+    // если процесс упадет после комита - событие все равно отправит relay (src/outbox.rs).
+    // Заказ перечитывается тут же в транзакции и пишется в payload, так событие несет заказ таким
+    // каким он стал после изменения, а не каким он будет когда relay дойдет до события
+    pub async fn insert_outbox(
+        tx: &Transaction<'_>,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        order_uids: &[&str],
+        kind: OrderEventKind,
+    ) -> Result<(), OrderError> {
+        let statement = statements.prepare(tx, OUTBOX_ORDERS.as_str()).await?;
+        let rows = monitoring::time_query("query_outbox_orders", tx.query(&statement, &[&tenant.as_str(), &order_uids])).await?;
+        let mut snapshots: HashMap<String, String> = HashMap::with_capacity(order_uids.len());
+        for order in Order::from_rows(&rows) {
+            let payload = serde_json::to_string(&order).map_err(OrderError::Deserialization)?;
+            snapshots.insert(order.order_uid, payload);
+        }
+        // порядок событий как в order_uids, id в outbox раздаются в этом же порядке
+        let (order_uids, payloads): (Vec<&str>, Vec<String>) = order_uids
+            .iter()
+            .filter_map(|order_uid| snapshots.remove(*order_uid).map(|payload| (*order_uid, payload)))
+            .unzip();
+
+        let statement = statements.prepare(tx, INSERT_OUTBOX).await?;
+        monitoring::time_query(
+            "insert_outbox",
+            tx.execute(
+                &statement,
+                &[&order_uids, &tenant.as_str(), &kind.as_str(), &kind.chrt_id(), &kind.status(), &payloads],
+            ),
+        )
+        .await?;
         Ok(())
     }
 }
@@ -203,6 +525,249 @@ impl Order {
             oof_shard: row.get("oof_shard"),
         }
     }
+
+    // JOIN с items отдает по строке на каждый товар, тут собираю их обратно в заказы.
+    // Строки одного заказа должны идти подряд, поэтому запросы сортируются по order_uid
+    pub fn from_rows(rows: &[Row]) -> Vec<Self> {
+        let mut orders: Vec<Order> = Vec::new();
+        for row in rows {
+            match orders.last_mut() {
+                Some(order) if order.order_uid == row.get::<_, String>("order_uid") => {
+                    order.items.push(Item::from_row(row));
+                }
+                _ => orders.push(Order::from_row(row)),
+            }
+        }
+        orders
+    }
+}
+
+// Таймаут на запрос к базе, чтобы не повторять одно и то же в каждом месте.
+// Заодно метрика и спан запроса, ошибки из лога попадают в этот спан
+pub async fn db_timeout<T: RowCount>(
+    action: &str,
+    fut: impl Future<Output = Result<T, tokio_postgres::Error>>,
+) -> Result<T, OrderError> {
+    let span = telemetry::query_span(action);
+    let started = Instant::now();
+    let result = async {
+        timeout(DB_TIMEOUT, fut)
+            .await
+            .map_err(|_| {
+                error!("{action} timed out");
+                OrderError::Timeout
+            })?
+            .map_err(|e| {
+                error!("Failed to {action}: {}", redact::db_error(&e));
+                OrderError::Database(e)
+            })
+    }
+    .instrument(span.clone())
+    .await;
+    monitoring::record_query(action, started, result.is_ok());
+    telemetry::finish_query(&span, &result);
+    result
+}
+
+// Создание и чтение заказов через клиент, это общий код для HTTP хендлеров и gRPC сервиса
+impl Order {
+    // Валидация и сохранение заказа в отдельной транзакции
+    pub async fn create(
+        &self,
+        client: &mut Client,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        policy: CustomerPolicy,
+    ) -> Result<(), OrderError> {
+        if let Err(e) = self.validate_fields() {
+            debug!("{}", e);
+            return Err(e);
+        }
+
+        let transaction = db_timeout("start transaction", client.transaction()).await?;
+        self.save(&transaction, statements, tenant, policy).await?;
+        db_timeout("commit transaction", transaction.commit()).await?;
+        monitoring::orders_created(1, self.items.len());
+        Ok(())
+    }
+
+    // Заказ другого арендатора выглядит так же как несуществующий
+    pub async fn find_by_id(client: &Client, tenant: &Tenant, order_uid: &str) -> Result<Option<Order>, OrderError> {
+        tenant::bind(client, tenant).await?;
+        let query = format!("{ORDER_SELECT} WHERE o.tenant_id = $1 AND o.order_uid = $2");
+        let rows = db_timeout("query order", client.query(&query, &[&tenant.as_str(), &order_uid])).await?;
+        Ok(Order::from_rows(&rows).into_iter().next())
+    }
+
+    // Страница заказов, limit/offset считаются по заказам, а не по строкам JOIN с товарами
+    // customer_id - только ордера этого покупателя
+    pub async fn list(
+        client: &Client,
+        tenant: &Tenant,
+        limit: i64,
+        offset: i64,
+        customer_id: Option<&str>,
+    ) -> Result<Vec<Order>, OrderError> {
+        tenant::bind(client, tenant).await?;
+        let query = format!(
+            "{ORDER_SELECT}
+            WHERE o.tenant_id = $4 AND o.order_uid IN (
+                SELECT order_uid FROM orders
+                WHERE tenant_id = $4 AND ($3::varchar IS NULL OR customer_id = $3)
+                ORDER BY order_uid LIMIT $1 OFFSET $2
+            )
+            ORDER BY o.order_uid"
+        );
+        let rows = db_timeout(
+            "query orders",
+            client.query(&query, &[&limit, &offset, &customer_id, &tenant.as_str()]),
+        )
+        .await?;
+        Ok(Order::from_rows(&rows))
+    }
+
+    // Страница для GET /orders как было всегда: limit/offset по строкам JOIN с товарами,
+    // на каждую строку отдельный заказ с одним товаром. gRPC ListOrders считает по заказам через list.
+    // Без ORDER BY postgres может отдавать строки в разном порядке и страницы будут пересекаться
+    pub async fn list_rows(
+        client: &Client,
+        tenant: &Tenant,
+        limit: i64,
+        offset: i64,
+        customer_id: Option<&str>,
+    ) -> Result<Vec<Order>, OrderError> {
+        tenant::bind(client, tenant).await?;
+        let query = format!(
+            "{ORDER_SELECT}
+            WHERE o.tenant_id = $4 AND ($3::varchar IS NULL OR o.customer_id = $3)
+            ORDER BY o.order_uid, i.chrt_id
+            LIMIT $1 OFFSET $2"
+        );
+        let rows = db_timeout(
+            "query orders",
+            client.query(&query, &[&limit, &offset, &customer_id, &tenant.as_str()]),
+        )
+        .await?;
+        Ok(rows.iter().map(Order::from_row).collect())
+    }
+
+    // Поиск заказов по трек номеру, совпадать может как трек самого заказа так и трек любого из товаров
+    pub async fn find_by_track(client: &Client, tenant: &Tenant, track_number: &str) -> Result<Vec<Order>, OrderError> {
+        tenant::bind(client, tenant).await?;
+        let query = format!(
+            "{ORDER_SELECT}
+            WHERE o.tenant_id = $2 AND o.order_uid IN (
+                SELECT order_uid FROM orders WHERE tenant_id = $2 AND track_number = $1
+                UNION
+                SELECT order_uid FROM items WHERE tenant_id = $2 AND track_number = $1
+            )
+            ORDER BY o.order_uid"
+        );
+        let rows = db_timeout(
+            "query orders by track",
+            client.query(&query, &[&track_number, &tenant.as_str()]),
+        )
+        .await?;
+        Ok(Order::from_rows(&rows))
+    }
+
+    // Смена статуса товара в заказе, событие в outbox пишется в той же транзакции
+    pub async fn update_item_status(
+        client: &mut Client,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        order_uid: &str,
+        chrt_id: i64,
+        status: i32,
+    ) -> Result<(), OrderError> {
+        let tx = db_timeout("start transaction", client.transaction()).await?;
+        tenant::bind(&tx, tenant).await?;
+        let updated = db_timeout(
+            "update item status",
+            tx.execute(
+                "UPDATE items SET status = $3 WHERE tenant_id = $4 AND order_uid = $1 AND chrt_id = $2",
+                &[&order_uid, &chrt_id, &status, &tenant.as_str()],
+            ),
+        )
+        .await?;
+        if updated == 0 {
+            return Err(OrderError::NotFound {
+                msg: "Item not found".to_string(),
+                field: "chrt_id".to_string(),
+            });
+        }
+        let kind = OrderEventKind::ItemStatusChanged { chrt_id, status };
+        Order::insert_outbox(&tx, statements, tenant, &[order_uid], kind).await?;
+        db_timeout("commit transaction", tx.commit()).await?;
+        monitoring::item_status_changed();
+        Ok(())
+    }
+
+    // Добавление товара в существующий заказ, если заказа нет у этого арендатора - ничего не вставится.
+    // Событие в outbox пишется в той же транзакции
+    pub async fn add_item(
+        client: &mut Client,
+        statements: &StatementCache,
+        tenant: &Tenant,
+        order_uid: &str,
+        item: &Item,
+    ) -> Result<(), OrderError> {
+        item.validate_fields()?;
+        let tx = db_timeout("start transaction", client.transaction()).await?;
+        tenant::bind(&tx, tenant).await?;
+        let inserted = db_timeout(
+            "insert item",
+            tx.execute(
+                "INSERT INTO items (
+                    tenant_id, order_uid, chrt_id, track_number, price, rid, name, sale, size, total_price, nm_id,
+                    brand, status
+                )
+                SELECT $13::varchar, $1::varchar, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12
+                WHERE EXISTS (SELECT 1 FROM orders WHERE tenant_id = $13::varchar AND order_uid = $1::varchar)",
+                &[
+                    &order_uid, &item.chrt_id, &item.track_number, &item.price, &item.rid, &item.name,
+                    &item.sale, &item.size, &item.total_price, &item.nm_id, &item.brand, &item.status,
+                    &tenant.as_str(),
+                ],
+            ),
+        )
+        .await?;
+        if inserted == 0 {
+            return Err(OrderError::NotFound {
+                msg: "Order not found".to_string(),
+                field: "order".to_string(),
+            });
+        }
+        let kind = OrderEventKind::ItemAdded { chrt_id: item.chrt_id };
+        Order::insert_outbox(&tx, statements, tenant, &[order_uid], kind).await?;
+        db_timeout("commit transaction", tx.commit()).await?;
+        monitoring::item_added();
+        Ok(())
+    }
+}
+
+impl TrackingView {
+    // Публичное представление заказа без персональных данных.
+    // Если трек номер нашелся у конкретного товара - показываем только такие товары
+    pub fn from_order(order: &Order, track_number: &str) -> Self {
+        let items = order
+            .items
+            .iter()
+            .filter(|item| order.track_number == track_number || item.track_number == track_number)
+            .map(|item| TrackingItem {
+                track_number: item.track_number.clone(),
+                name: item.name.clone(),
+                brand: item.brand.clone(),
+                size: item.size.clone(),
+                status: item.status,
+            })
+            .collect();
+        TrackingView {
+            track_number: track_number.to_string(),
+            delivery_service: order.delivery_service.clone(),
+            items,
+        }
+    }
 }
 
 impl Delivery {
@@ -237,13 +802,33 @@ impl Payment {
 }
 
 impl Item {
+    // Проверка товара который добавляют в уже существующий заказ, как в validate_fields у Order
+    pub fn validate_fields(&self) -> Result<(), OrderError> {
+        let fields = [
+            ("track_number", &self.track_number),
+            ("rid", &self.rid),
+            ("name", &self.name),
+            ("size", &self.size),
+            ("brand", &self.brand),
+        ];
+        for (field, value) in fields {
+            if value.is_empty() {
+                return Err(OrderError::Validation {
+                    msg: format!("{field} is empty"),
+                    field: field.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
     pub fn from_row(row: &Row) -> Self {
         Item {
             chrt_id: row.get("chrt_id"),
-            track_number: row.get("track_number"),
+            track_number: row.get("item_track_number"),
             price: row.get("price"),
             rid: row.get("rid"),
-            name: row.get("name"),
+            name: row.get("item_name"),
             sale: row.get("sale"),
             size: row.get("size"),
             total_price: row.get("total_price"),
@@ -0,0 +1,354 @@
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::{header, HeaderMap},
+    response::Response,
+};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeSet;
+use subtle::ConstantTimeEq;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::{interval_at, Duration, Instant},
+};
+
+use crate::{
+    api_keys::Scope,
+    auth::{self, Auth, BEARER_PROTOCOL_PREFIX},
+    config,
+    order_errors::OrderError,
+    order_events::{OrderEvent, OrderEvents},
+    tenant::{Tenant, TENANT_HEADER},
+};
+
+// Как часто слать ping и сколько ждать хоть какого-то ответа от клиента до отключения
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(90);
+// Очередь исходящих сообщений одного клиента, если она заполнилась - клиент не успевает читать
+const OUTGOING_QUEUE: usize = 64;
+// Сколько order_uid и трек номеров вместе может слушать одно соединение
+const MAX_SUBSCRIPTIONS: usize = 100;
+
+// Код закрытия 1008 (policy violation) для клиентов которые перестали отвечать
+const CLOSE_POLICY: u16 = 1008;
+
+// Браузер не может выставить заголовок Authorization для WebSocket, зато может передать подпротоколы:
+// new WebSocket(url, ["orders", "bearer." + token]). Сервер выбирает "orders", токен обратно не отдается
+const PROTOCOL: &str = "orders";
+
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    // браузер не может выставить заголовок X-Tenant-ID для WebSocket, поэтому арендатора можно передать в query
+    tenant: Option<String>,
+}
+
+// Сообщения от клиента
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        order_uids: Vec<String>,
+        #[serde(default)]
+        track_numbers: Vec<String>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        order_uids: Vec<String>,
+        #[serde(default)]
+        track_numbers: Vec<String>,
+    },
+    Ping,
+}
+
+// На что подписано соединение: конкретные заказы и трек номера (заказа или любого товара)
+// у арендатора соединения
+struct Subscriptions {
+    tenant: Tenant,
+    order_uids: BTreeSet<String>,
+    track_numbers: BTreeSet<String>,
+}
+
+impl Subscriptions {
+    fn new(tenant: Tenant) -> Self {
+        Subscriptions {
+            tenant,
+            order_uids: BTreeSet::new(),
+            track_numbers: BTreeSet::new(),
+        }
+    }
+
+    fn matches(&self, event: &OrderEvent) -> bool {
+        let order = &event.order;
+        if event.tenant != self.tenant {
+            return false;
+        }
+        self.order_uids.contains(&order.order_uid)
+            || self.track_numbers.contains(&order.track_number)
+            || order.items.iter().any(|item| self.track_numbers.contains(&item.track_number))
+    }
+
+    fn len(&self) -> usize {
+        self.order_uids.len() + self.track_numbers.len()
+    }
+
+    fn to_message(&self) -> Message {
+        text(&json!({
+            "type": "subscribed",
+            "order_uids": self.order_uids,
+            "track_numbers": self.track_numbers,
+        }))
+    }
+}
+
+// Подключение к /ws. Учетные данные проверяются до апгрейда соединения, так что без них клиент
+// получает обычный 401/403: API ключ или JWT с правом orders:read, как у /orders/stream.
+// При API_AUTH=off /ws закрывает только WS_AUTH_TOKEN, если он задан.
+// Ключ и токен только в заголовках: query строка попадает в логи прокси
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(events): State<OrderEvents>,
+    State(auth): State<Auth>,
+    Query(params): Query<WsParams>,
+    headers: HeaderMap,
+) -> Result<Response, OrderError> {
+    let principal = auth.authenticate_ws(&headers).await?;
+    auth::check(&principal, Scope::OrdersRead, true)?;
+    if let Some(expected) = config::ws_auth_token().filter(|_| !config::api_auth()) {
+        let valid = request_token(&headers)
+            .is_some_and(|token| bool::from(token.as_bytes().ct_eq(expected.as_bytes())));
+        if !valid {
+            return Err(OrderError::Unauthorized("Invalid or missing token".to_string()));
+        }
+    }
+    let requested = headers
+        .get(TENANT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or(params.tenant.as_deref())
+        .map(str::trim)
+        .filter(|value| !value.is_empty());
+    // арендатор из ключа или токена, параметр и заголовок только для не привязанных к арендатору
+    let tenant = Tenant::resolve_requested(Some(&principal), requested)?;
+    Ok(ws.protocols([PROTOCOL]).on_upgrade(move |socket| session(socket, events, tenant)))
+}
+
+// Токен из Authorization: Bearer или из подпротокола bearer.<token>
+fn request_token(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    bearer.or_else(|| auth::protocol_value(headers, BEARER_PROTOCOL_PREFIX))
+}
+
+async fn session(socket: WebSocket, events: OrderEvents, tenant: Tenant) {
+    info!("WebSocket client connected (tenant {tenant})");
+    let (sink, mut stream) = socket.split();
+    // запись в сокет идет в отдельной задаче через ограниченную очередь,
+    // чтобы медленный клиент не держал чтение событий и heartbeat
+    let (outgoing, queue) = mpsc::channel(OUTGOING_QUEUE);
+    let writer = tokio::spawn(write_messages(sink, queue));
+
+    let mut receiver = events.subscribe(None).receiver;
+    let mut subscriptions = Subscriptions::new(tenant);
+    let mut heartbeat = interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    let reason = loop {
+        let message = tokio::select! {
+            incoming = stream.next() => {
+                let Some(Ok(incoming)) = incoming else {
+                    break "client disconnected";
+                };
+                last_seen = Instant::now();
+                match incoming {
+                    Message::Text(body) => handle_client_message(&body, &mut subscriptions),
+                    Message::Close(_) => break "client closed connection",
+                    // ping от клиента axum отвечает сам, pong просто продлевает last_seen
+                    _ => continue,
+                }
+            }
+            event = receiver.recv() => match event {
+                Ok(event) if subscriptions.matches(&event) => {
+                    let mut value = event.to_json();
+                    value["type"] = json!("event");
+                    text(&value)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client lagged behind, {skipped} events skipped");
+                    text(&json!({ "type": "lagged", "skipped": skipped }))
+                }
+                Err(RecvError::Closed) => break "event bus closed",
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    let close = Message::Close(Some(CloseFrame {
+                        code: CLOSE_POLICY,
+                        reason: "heartbeat timeout".into(),
+                    }));
+                    let _ = outgoing.try_send(close);
+                    break "heartbeat timeout";
+                }
+                Message::Ping(Vec::new())
+            }
+        };
+
+        match outgoing.try_send(message) {
+            Ok(()) => {}
+            // клиент не успевает читать: держать для него события бесконечно нельзя, отключаем
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                writer.abort();
+                break "slow consumer";
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => break "write failed",
+        }
+    };
+
+    drop(outgoing);
+    // writer сам завершится когда дошлет очередь
+    let _ = writer.await;
+    info!("WebSocket client disconnected: {reason}");
+}
+
+async fn write_messages(mut sink: SplitSink<WebSocket, Message>, mut queue: mpsc::Receiver<Message>) {
+    while let Some(message) = queue.recv().await {
+        if let Err(e) = sink.send(message).await {
+            debug!("WebSocket write failed: {e}");
+            return;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+fn handle_client_message(body: &str, subscriptions: &mut Subscriptions) -> Message {
+    let message = match serde_json::from_str::<ClientMessage>(body) {
+        Ok(message) => message,
+        Err(e) => return error_message(&format!("Invalid message: {e}")),
+    };
+    match message {
+        ClientMessage::Subscribe { order_uids, track_numbers } => {
+            let new_uids: BTreeSet<&String> = order_uids
+                .iter()
+                .filter(|uid| !subscriptions.order_uids.contains(*uid))
+                .collect();
+            let new_tracks: BTreeSet<&String> = track_numbers
+                .iter()
+                .filter(|track| !subscriptions.track_numbers.contains(*track))
+                .collect();
+            if subscriptions.len() + new_uids.len() + new_tracks.len() > MAX_SUBSCRIPTIONS {
+                return error_message(&format!("Too many subscriptions, max {MAX_SUBSCRIPTIONS}"));
+            }
+            subscriptions.order_uids.extend(order_uids);
+            subscriptions.track_numbers.extend(track_numbers);
+            subscriptions.to_message()
+        }
+        ClientMessage::Unsubscribe { order_uids, track_numbers } => {
+            for uid in &order_uids {
+                subscriptions.order_uids.remove(uid);
+            }
+            for track in &track_numbers {
+                subscriptions.track_numbers.remove(track);
+            }
+            subscriptions.to_message()
+        }
+        ClientMessage::Ping => text(&json!({ "type": "pong" })),
+    }
+}
+
+fn error_message(message: &str) -> Message {
+    text(&json!({ "type": "error", "message": message }))
+}
+
+fn text(value: &serde_json::Value) -> Message {
+    Message::Text(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::Order, order_events::OrderEventKind};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn tenant(id: &str) -> Tenant {
+        Tenant::new(id).unwrap()
+    }
+
+    fn event(tenant: &str, order: Order) -> OrderEvent {
+        OrderEvent { id: 1, kind: OrderEventKind::Created, tenant: self::tenant(tenant), order: Arc::new(order) }
+    }
+
+    fn send(subscriptions: &mut Subscriptions, message: &Value) -> Value {
+        match handle_client_message(&message.to_string(), subscriptions) {
+            Message::Text(body) => serde_json::from_str(&body).unwrap(),
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+
+    #[test]
+    fn subscriptions_are_limited() {
+        let mut subscriptions = Subscriptions::new(tenant("t1"));
+        let uids: Vec<String> = (0..MAX_SUBSCRIPTIONS - 1).map(|i| format!("uid-{i}")).collect();
+        let reply = send(&mut subscriptions, &json!({"type": "subscribe", "order_uids": uids}));
+        assert_eq!(reply["type"], "subscribed");
+        assert_eq!(subscriptions.len(), MAX_SUBSCRIPTIONS - 1);
+
+        // повторная подписка на то же самое места не занимает
+        let reply = send(&mut subscriptions, &json!({"type": "subscribe", "order_uids": ["uid-0"], "track_numbers": ["T1"]}));
+        assert_eq!(reply["type"], "subscribed");
+        assert_eq!(subscriptions.len(), MAX_SUBSCRIPTIONS);
+
+        let reply = send(&mut subscriptions, &json!({"type": "subscribe", "track_numbers": ["T2"]}));
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], format!("Too many subscriptions, max {MAX_SUBSCRIPTIONS}"));
+        assert!(!subscriptions.track_numbers.contains("T2"));
+        assert_eq!(subscriptions.len(), MAX_SUBSCRIPTIONS);
+    }
+
+    #[test]
+    fn unsubscribe_removes_only_listed() {
+        let mut subscriptions = Subscriptions::new(tenant("t1"));
+        send(&mut subscriptions, &json!({"type": "subscribe", "order_uids": ["a", "b"], "track_numbers": ["T1"]}));
+        let reply = send(&mut subscriptions, &json!({"type": "unsubscribe", "order_uids": ["a", "missing"]}));
+        assert_eq!(reply, json!({"type": "subscribed", "order_uids": ["b"], "track_numbers": ["T1"]}));
+
+        let mut order = Order::sample();
+        order.order_uid = "a".to_string();
+        assert!(!subscriptions.matches(&event("t1", order)));
+    }
+
+    #[test]
+    fn other_tenants_events_are_skipped() {
+        let mut subscriptions = Subscriptions::new(tenant("t1"));
+        let order = Order::sample();
+        send(&mut subscriptions, &json!({"type": "subscribe", "order_uids": [order.order_uid]}));
+        assert!(subscriptions.matches(&event("t1", order.clone())));
+        assert!(!subscriptions.matches(&event("t2", order)));
+    }
+
+    #[test]
+    fn item_track_numbers_match() {
+        let mut subscriptions = Subscriptions::new(tenant("t1"));
+        send(&mut subscriptions, &json!({"type": "subscribe", "track_numbers": ["ITEMTRACK"]}));
+        let mut order = Order::sample();
+        assert!(!subscriptions.matches(&event("t1", order.clone())));
+        // трек самого заказа другой, совпадает трек товара
+        order.items[0].track_number = "ITEMTRACK".to_string();
+        assert!(subscriptions.matches(&event("t1", order.clone())));
+        assert!(!subscriptions.matches(&event("t2", order)));
+    }
+
+    #[test]
+    fn bad_messages_get_error_reply() {
+        let mut subscriptions = Subscriptions::new(tenant("t1"));
+        assert_eq!(send(&mut subscriptions, &json!({"type": "ping"})), json!({"type": "pong"}));
+        assert_eq!(send(&mut subscriptions, &json!({"type": "unknown"}))["type"], "error");
+        assert_eq!(subscriptions.len(), 0);
+    }
+}
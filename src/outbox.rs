@@ -0,0 +1,386 @@
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use std::sync::Arc;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, sleep, Duration, Instant, Interval},
+};
+use tokio_postgres::{Client, Row};
+
+use crate::{
+    config, connect_retry,
+    db::Db,
+    models::Order,
+    order_changes::{OrderChange, OrderChanges},
+    order_errors::OrderError,
+    order_events::{OrderEvent, OrderEventKind, OrderEvents},
+    order_impl::db_timeout,
+    tenant::{self, Tenant},
+    webhooks::{WebhookQueue, WebhookSink},
+};
+
+// Опрос outbox на случай если уведомление потерялось (например listener переподключался)
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Сколько событий отправлять за один проход
+const RELAY_BATCH: i64 = 100;
+// Отправленные события хранятся неделю, чистка раз в час
+const RETENTION_DAYS: i32 = 7;
+const CLEANUP_INTERVAL: Duration = Duration::from_hours(1);
+
+// Куда relay отправляет события. Событие считается отправленным только когда его приняли все
+// получатели, если кто-то вернул ошибку - событие повторится целиком позже (как минимум один раз),
+// поэтому получатель должен спокойно переживать повторы по id события.
+// Брокер сообщений подключается так же - своей реализацией этого трейта
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn publish(&self, event: &OrderEvent) -> Result<(), OrderError>;
+}
+
+// Просто пишет события в лог
+pub struct LogSink;
+
+#[async_trait]
+impl EventSink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn publish(&self, event: &OrderEvent) -> Result<(), OrderError> {
+        info!("Order event {} {} for order {}", event.id, event.kind.as_str(), event.order.order_uid);
+        Ok(())
+    }
+}
+
+// Получатели из OUTBOX_SINKS, в шину внутри процесса события отправляет tail.
+// Кривой список - ошибка запуска: иначе relay помечал бы события отправленными мимо нужного получателя
+pub fn sinks(db: &Db, webhooks: &WebhookQueue) -> Result<Vec<Box<dyn EventSink>>, OrderError> {
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+    for name in config::outbox_sinks() {
+        match name.as_str() {
+            "log" => sinks.push(Box::new(LogSink)),
+            "webhook" => sinks.push(Box::new(WebhookSink::new(db.clone(), webhooks.clone()))),
+            #[cfg(feature = "kafka")]
+            "kafka" => sinks.push(Box::new(crate::kafka::KafkaSink::from_env()?)),
+            #[cfg(not(feature = "kafka"))]
+            "kafka" => {
+                return Err(OrderError::Internal(
+                    "Outbox sink kafka needs the service built with --features kafka".to_string(),
+                ))
+            }
+            other => return Err(OrderError::Internal(format!("Unknown outbox sink: {other}"))),
+        }
+    }
+    Ok(sinks)
+}
+
+// Relay отправляет события внешним получателям и проставляет published_at, tail публикует их
+// в шину этого процесса. Обе задачи на своих соединениях, будит их listener (order_changes.rs)
+// по LISTEN outbox, и раз в POLL_INTERVAL они на всякий случай проверяют таблицу сами.
+// Если запущено несколько экземпляров сервиса, relay-и делят события между собой (см. relay_batch),
+// одно событие повторно уходит только после сбоя. Tail у каждого экземпляра свой, так что подписчики
+// SSE/WebSocket/gRPC видят события записанные любым экземпляром
+pub fn spawn(events: OrderEvents, sinks: Vec<Box<dyn EventSink>>, changes: &OrderChanges) {
+    let names: Vec<&str> = sinks.iter().map(|sink| sink.name()).collect();
+    info!("Outbox relay sinks: {}", if names.is_empty() { "none".to_string() } else { names.join(", ") });
+
+    let mut relay_changes = changes.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let mut client = connect_retry("Outbox relay").await;
+            if let Err(e) = relay(&mut client, &mut relay_changes, &sinks).await {
+                error!("Outbox relay stopped: {e}");
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    });
+
+    let mut tail_changes = changes.subscribe();
+    tokio::spawn(async move {
+        // позиция переживает переподключения, иначе события за время обрыва потерялись бы
+        let mut last_id = None;
+        loop {
+            let client = connect_retry("Outbox tail").await;
+            if let Err(e) = tail(&client, &mut tail_changes, &events, &mut last_id).await {
+                error!("Outbox tail stopped: {e}");
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+// Ждет новых событий в outbox или очередного опроса. Отставший подписчик мог пропустить уведомление
+async fn wait_outbox(changes: &mut broadcast::Receiver<OrderChange>, poll: &mut Interval) {
+    loop {
+        tokio::select! {
+            _ = poll.tick() => return,
+            change = changes.recv() => match change {
+                Ok(OrderChange::Order(_) | OrderChange::Customer(_) | OrderChange::Disconnected) => {}
+                Ok(OrderChange::Outbox | OrderChange::Resync) | Err(RecvError::Lagged(_)) => return,
+                Err(RecvError::Closed) => {
+                    poll.tick().await;
+                    return;
+                }
+            },
+        }
+    }
+}
+
+// Возвращается только с ошибкой, после нее relay переподключается
+async fn relay(
+    client: &mut Client,
+    changes: &mut broadcast::Receiver<OrderChange>,
+    sinks: &[Box<dyn EventSink>],
+) -> Result<(), OrderError> {
+    // relay и tail отправляют события всех арендаторов
+    tenant::bind_all(client).await?;
+    info!("Outbox relay started");
+    let mut poll = interval(POLL_INTERVAL);
+    let mut last_cleanup: Option<Instant> = None;
+    loop {
+        wait_outbox(changes, &mut poll).await;
+        // отправляем пока есть что отправлять
+        while relay_batch(client, sinks).await? > 0 {}
+
+        if last_cleanup.is_none_or(|at| at.elapsed() >= CLEANUP_INTERVAL) {
+            let deleted = db_timeout(
+                "clean up outbox",
+                client.execute(
+                    "DELETE FROM outbox WHERE published_at < now() - make_interval(days => $1)",
+                    &[&RETENTION_DAYS],
+                ),
+            )
+            .await?;
+            if deleted > 0 {
+                debug!("Removed {deleted} published outbox events");
+            }
+            last_cleanup = Some(Instant::now());
+        }
+    }
+}
+
+// Одна пачка событий по порядку id, возвращает сколько событий забрано. Строки забираются
+// FOR UPDATE SKIP LOCKED в транзакции которая держится пока события отправляются: relay другого
+// экземпляра их пропустит и возьмет следующие. Событие заказа у которого есть более раннее
+// неотправленное событие ждет следующего прохода, так события одного заказа уходят по порядку
+// даже когда их разбирают разные экземпляры
+async fn relay_batch(client: &mut Client, sinks: &[Box<dyn EventSink>]) -> Result<usize, OrderError> {
+    let tx = db_timeout("start transaction", client.transaction()).await?;
+    let rows = db_timeout(
+        "claim outbox",
+        tx.query(
+            "SELECT o.id, o.tenant_id, o.order_uid, o.event_type, o.chrt_id, o.status, o.payload::text AS payload
+            FROM outbox o
+            WHERE o.published_at IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM outbox e
+                    WHERE e.published_at IS NULL AND e.tenant_id = o.tenant_id AND e.order_uid = o.order_uid
+                        AND e.id < o.id
+                )
+            ORDER BY o.id
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED",
+            &[&RELAY_BATCH],
+        ),
+    )
+    .await?;
+
+    let mut published = Vec::with_capacity(rows.len());
+    let mut failure = None;
+    'events: for (id, event) in row_events(&rows) {
+        if let Some(event) = event {
+            for sink in sinks {
+                if let Err(e) = sink.publish(&event).await {
+                    // остальные события подождут, иначе нарушится порядок
+                    error!("Outbox sink {} failed on event {id}: {e}", sink.name());
+                    failure = Some(e);
+                    break 'events;
+                }
+            }
+        }
+        published.push(id);
+    }
+    // то что успели отправить до ошибки помечаем в любом случае, иначе оно ушло бы еще раз
+    db_timeout(
+        "mark outbox events published",
+        tx.execute("UPDATE outbox SET published_at = now() WHERE id = ANY($1)", &[&published]),
+    )
+    .await?;
+    db_timeout("commit outbox", tx.commit()).await?;
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(rows.len()),
+    }
+}
+
+// Возвращается только с ошибкой. Первый запуск начинает с конца outbox:
+// в шину попадают только события после старта процесса
+async fn tail(
+    client: &Client,
+    changes: &mut broadcast::Receiver<OrderChange>,
+    events: &OrderEvents,
+    last_id: &mut Option<i64>,
+) -> Result<(), OrderError> {
+    tenant::bind_all(client).await?;
+    let mut position = if let Some(id) = *last_id {
+        id
+    } else {
+        let row = db_timeout(
+            "query outbox position",
+            client.query_one("SELECT COALESCE(MAX(id), 0) AS last_id FROM outbox", &[]),
+        )
+        .await?;
+        row.get("last_id")
+    };
+    *last_id = Some(position);
+    events.resume_at(u64::try_from(position + 1).unwrap_or_default());
+    info!("Outbox tail started after event {position}");
+
+    let mut poll = interval(POLL_INTERVAL);
+    let mut gap = None;
+    loop {
+        wait_outbox(changes, &mut poll).await;
+        loop {
+            let (read, next) = tail_batch(client, events, position, &mut gap).await?;
+            position = next;
+            *last_id = Some(position);
+            if read < RELAY_BATCH {
+                break;
+            }
+        }
+    }
+}
+
+// Пропуск в id outbox который ждет tail: первый пропущенный id и xmax снимка в котором его увидели
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Gap {
+    id: i64,
+    xmax: i64,
+}
+
+// Читает события после position по порядку id. id раздаются при вставке, а видны строки после комита,
+// поэтому на пропуске в id останавливаемся: его могла занять транзакция которая еще не закомитилась.
+// Такая транзакция уже шла когда пропуск заметили, то есть ее xid меньше xmax того снимка.
+// Когда xmin снимка (самая старая незавершенная транзакция) дорастет до этого xmax - все кто мог занять
+// пропущенный id завершились и видны в том же снимке, так что пропуск который так и не заполнился -
+// откат, идем дальше. Возвращает сколько строк прочитано и новую позицию
+async fn tail_batch(
+    client: &Client,
+    events: &OrderEvents,
+    position: i64,
+    gap: &mut Option<Gap>,
+) -> Result<(i64, i64), OrderError> {
+    let rows = db_timeout(
+        "query outbox tail",
+        client.query(
+            "SELECT id, tenant_id, order_uid, event_type, chrt_id, status, payload::text AS payload,
+                pg_snapshot_xmin(pg_current_snapshot())::text::bigint AS snapshot_xmin,
+                pg_snapshot_xmax(pg_current_snapshot())::text::bigint AS snapshot_xmax
+            FROM outbox
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2",
+            &[&position, &RELAY_BATCH],
+        ),
+    )
+    .await?;
+
+    let ids: Vec<i64> = rows.iter().map(|row| row.get("id")).collect();
+    let snapshot = rows.first().map(|row| (row.get("snapshot_xmin"), row.get("snapshot_xmax")));
+    let ready = snapshot.map_or(0, |(xmin, xmax)| ready_rows(position, &ids, xmin, xmax, gap));
+
+    let mut next = position;
+    for (id, event) in row_events(&rows[..ready]) {
+        if let Some(event) = event {
+            events.publish(event);
+        }
+        next = id;
+    }
+    let read = if ready == rows.len() { i64::try_from(rows.len()).unwrap_or_default() } else { 0 };
+    Ok((read, next))
+}
+
+// Сколько строк подряд от position можно отдать в шину. На новом пропуске запоминает его в gap
+fn ready_rows(position: i64, ids: &[i64], snapshot_xmin: i64, snapshot_xmax: i64, gap: &mut Option<Gap>) -> usize {
+    let mut expected = position + 1;
+    for (ready, &id) in ids.iter().enumerate() {
+        if id != expected {
+            match *gap {
+                Some(Gap { id: gap_id, xmax }) if gap_id == expected && snapshot_xmin >= xmax => {
+                    debug!("Outbox ids {expected}..{} were rolled back, skipping", id - 1);
+                }
+                Some(Gap { id: gap_id, .. }) if gap_id == expected => return ready,
+                _ => {
+                    *gap = Some(Gap { id: expected, xmax: snapshot_xmax });
+                    return ready;
+                }
+            }
+        }
+        expected = id + 1;
+    }
+    ids.len()
+}
+
+// События для строк outbox, заказ в событии - снимок из payload на момент события.
+// Событие которое нельзя собрать пропускается
+fn row_events(rows: &[Row]) -> Vec<(i64, Option<OrderEvent>)> {
+    let mut events = Vec::with_capacity(rows.len());
+    for row in rows {
+        let id: i64 = row.get("id");
+        let event_type: String = row.get("event_type");
+        let kind = OrderEventKind::from_parts(&event_type, row.get("chrt_id"), row.get("status"));
+        let order = serde_json::from_str::<Order>(row.get("payload"));
+        let tenant = Tenant::new(row.get("tenant_id"));
+        let event = match (kind, order, tenant) {
+            (Some(kind), Ok(order), Ok(tenant)) => Some(OrderEvent {
+                id: u64::try_from(id).unwrap_or_default(),
+                kind,
+                tenant,
+                order: Arc::new(order),
+            }),
+            (None, _, _) => {
+                warn!("Skipping outbox event {id} with unknown type {event_type}");
+                None
+            }
+            (_, _, Err(e)) => {
+                warn!("Skipping outbox event {id}: {e}");
+                None
+            }
+            (_, Err(e), _) => {
+                let order_uid: String = row.get("order_uid");
+                warn!("Skipping outbox event {id}: broken payload of order {order_uid}: {e}");
+                None
+            }
+        };
+        events.push((id, event));
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_on_a_gap_until_older_transactions_finish() {
+        let mut gap = None;
+        // 3 еще не видна: отдаем только 1 и 2, пропуск запоминается с xmax снимка
+        assert_eq!(ready_rows(0, &[1, 2, 4, 5], 100, 105, &mut gap), 2);
+        assert_eq!(gap, Some(Gap { id: 3, xmax: 105 }));
+        // транзакции до 105 еще идут - ждем дальше
+        assert_eq!(ready_rows(2, &[4, 5], 103, 110, &mut gap), 0);
+        assert_eq!(gap, Some(Gap { id: 3, xmax: 105 }));
+        // все кто мог занять 3 завершились, а ее так и нет - откат
+        assert_eq!(ready_rows(2, &[4, 5], 105, 112, &mut gap), 2);
+    }
+
+    #[test]
+    fn filled_gap_is_read_in_order() {
+        let mut gap = Some(Gap { id: 3, xmax: 105 });
+        assert_eq!(ready_rows(2, &[3, 4, 5], 101, 106, &mut gap), 3);
+        // следующий пропуск ждет своих транзакций, старый xmax к нему не относится
+        assert_eq!(ready_rows(5, &[7], 120, 125, &mut gap), 0);
+        assert_eq!(gap, Some(Gap { id: 6, xmax: 125 }));
+    }
+}
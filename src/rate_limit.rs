@@ -0,0 +1,280 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Semaphore, time::interval};
+
+use crate::{auth::Principal, config, order_errors::OrderError};
+
+// Сколько клиентов помнить, больше не бывает: новый клиент сверх лимита вытесняет десятую часть тех
+// кто дольше всех не приходил. Вытесняется сразу пачка, так в среднем это не O(n) на каждый запрос
+const MAX_CLIENTS: usize = 10_000;
+const EVICT_BATCH: usize = MAX_CLIENTS / 10;
+// Как часто фоновая задача забывает клиентов с уже полной корзиной
+const SWEEP_INTERVAL: Duration = Duration::from_mins(1);
+
+// Корзина токенов клиента: каждый запрос берет токен, токены капают со скоростью rate до burst
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct TokenBuckets {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl TokenBuckets {
+    fn new(rate: u32, burst: u32) -> Self {
+        TokenBuckets {
+            rate: f64::from(rate),
+            // меньше одного токена корзина не пропустит ни одного запроса
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+    }
+
+    // Берет токен клиента, если токенов нет - через сколько секунд появится следующий
+    fn acquire(&self, key: &str) -> Result<(), u64> {
+        self.acquire_at(key, Instant::now())
+    }
+
+    fn acquire_at(&self, key: &str, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(key) {
+            evict_oldest(&mut buckets, EVICT_BATCH);
+        }
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert(Bucket { tokens: self.burst, updated: now });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate);
+        Err(wait.as_secs() + u64::from(wait.subsec_nanos() > 0))
+    }
+
+    // Забывает клиентов у которых корзина уже полная, новая корзина для них ничем не отличается
+    fn sweep(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        buckets.retain(|_, bucket| {
+            self.refill(bucket, now);
+            bucket.tokens < self.burst
+        });
+    }
+
+    fn check(&self, kind: &str, key: &str) -> Result<(), OrderError> {
+        self.acquire(key).map_err(|retry_after| {
+            debug!("Rate limit for {kind} requests exceeded by {key}");
+            OrderError::TooManyRequests {
+                msg: format!("Too many {kind} requests, retry in {retry_after}s"),
+                retry_after,
+            }
+        })
+    }
+}
+
+// Убирает count клиентов которые дольше всех не приходили
+fn evict_oldest(buckets: &mut HashMap<String, Bucket>, count: usize) {
+    let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+    if updated.is_empty() {
+        return;
+    }
+    let count = count.clamp(1, updated.len());
+    let cutoff = *updated.select_nth_unstable(count - 1).1;
+    let mut left = count;
+    buckets.retain(|_, bucket| {
+        if left > 0 && bucket.updated <= cutoff {
+            left -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+// Лимиты запросов HTTP и gRPC, часть AppState. None - лимит выключен
+#[derive(Clone)]
+pub struct RateLimits {
+    // на IP до проверки учетных данных
+    ip: Option<Arc<TokenBuckets>>,
+    read: Option<Arc<TokenBuckets>>,
+    write: Option<Arc<TokenBuckets>>,
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let buckets = |(rate, burst): (u32, u32)| (rate > 0).then(|| Arc::new(TokenBuckets::new(rate, burst)));
+        let max_in_flight = config::max_in_flight();
+        RateLimits {
+            ip: buckets(config::rate_limit_ip()),
+            read: buckets(config::rate_limit_read()),
+            write: buckets(config::rate_limit_write()),
+            in_flight: (max_in_flight > 0).then(|| Arc::new(Semaphore::new(max_in_flight as usize))),
+        }
+    }
+
+    // Фоновая чистка корзин, чтобы в памяти не копились клиенты которые больше не приходят
+    pub fn spawn_sweep(&self) {
+        let limits = self.clone();
+        tokio::spawn(async move {
+            let mut tick = interval(SWEEP_INTERVAL);
+            loop {
+                tick.tick().await;
+                let now = Instant::now();
+                for buckets in [&limits.ip, &limits.read, &limits.write].into_iter().flatten() {
+                    buckets.sweep(now);
+                }
+            }
+        });
+    }
+
+    // Лимит на IP, проверяется до учетных данных
+    pub fn check_ip(&self, ip: Option<IpAddr>) -> Result<(), OrderError> {
+        match &self.ip {
+            Some(buckets) => buckets.check("any", &ip_key(ip)),
+            None => Ok(()),
+        }
+    }
+
+    // Лимит клиента на чтение или запись, проверяется после учетных данных
+    pub fn check_client(&self, write: bool, principal: Option<&Principal>, ip: Option<IpAddr>) -> Result<(), OrderError> {
+        let (buckets, kind) = if write { (&self.write, "write") } else { (&self.read, "read") };
+        match buckets {
+            Some(buckets) => buckets.check(kind, &client_key(principal, ip)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Снаружи всех маршрутов, до auth: запросы без ключа или с неверным ключом тоже ограничены,
+// иначе каждый из них ищет ключ в базе под общим клиентом и подбор ключей ничем не сдерживается
+pub async fn per_ip(State(limits): State<RateLimits>, req: Request, next: Next) -> Result<Response, OrderError> {
+    limits.check_ip(request_ip(&req))?;
+    Ok(next.run(req).await)
+}
+
+// Middleware для групп маршрутов в main.rs, ставится под auth middleware чтобы знать чей это запрос
+pub async fn read(State(limits): State<RateLimits>, req: Request, next: Next) -> Result<Response, OrderError> {
+    limit(&limits, false, req, next).await
+}
+
+pub async fn write(State(limits): State<RateLimits>, req: Request, next: Next) -> Result<Response, OrderError> {
+    limit(&limits, true, req, next).await
+}
+
+async fn limit(limits: &RateLimits, write: bool, req: Request, next: Next) -> Result<Response, OrderError> {
+    limits.check_client(write, req.extensions().get::<Principal>(), request_ip(&req))?;
+    Ok(next.run(req).await)
+}
+
+fn request_ip(req: &Request) -> Option<IpAddr> {
+    req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip())
+}
+
+// Лимит считается на API ключ (по id) или пользователя из JWT (по iss и sub), без учетных данных
+// (публичные маршруты, API_AUTH=off) - на IP клиента. За прокси это будет IP прокси
+fn client_key(principal: Option<&Principal>, ip: Option<IpAddr>) -> String {
+    match principal.filter(|_| config::api_auth()) {
+        Some(principal) => principal.client_key.clone(),
+        None => ip_key(ip),
+    }
+}
+
+fn ip_key(ip: Option<IpAddr>) -> String {
+    ip.map_or_else(|| "ip:unknown".to_string(), |ip| format!("ip:{ip}"))
+}
+
+// Общий лимит одновременных запросов на весь роутер: лишние сразу получают 429, а не ждут в очереди
+// к клиенту базы. Для потоков (SSE, выгрузка, /ws) место занято только пока не отдан ответ
+pub async fn in_flight(State(limits): State<RateLimits>, req: Request, next: Next) -> Result<Response, OrderError> {
+    let Some(semaphore) = &limits.in_flight else {
+        return Ok(next.run(req).await);
+    };
+    let Ok(_permit) = semaphore.try_acquire() else {
+        warn!("Too many requests in flight, rejecting {} {}", req.method(), req.uri().path());
+        return Err(OrderError::TooManyRequests {
+            msg: "Server is overloaded, try again later".to_string(),
+            retry_after: 1,
+        });
+    };
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn len(buckets: &TokenBuckets) -> usize {
+        buckets.buckets.lock().unwrap().len()
+    }
+
+    #[test]
+    fn tokens_refill_with_rate_up_to_burst() {
+        let buckets = TokenBuckets::new(2, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(buckets.acquire_at("a", start).is_ok());
+        }
+        // пустая корзина, токен накапает через полсекунды
+        assert_eq!(buckets.acquire_at("a", start), Err(1));
+        assert!(buckets.acquire_at("b", start).is_ok(), "other client has its own bucket");
+        assert!(buckets.acquire_at("a", start + Duration::from_millis(500)).is_ok());
+        assert!(buckets.acquire_at("a", start + Duration::from_millis(500)).is_err());
+        // за долгий простой больше burst не накапливается
+        let later = start + Duration::from_mins(1);
+        for _ in 0..3 {
+            assert!(buckets.acquire_at("a", later).is_ok());
+        }
+        assert!(buckets.acquire_at("a", later).is_err());
+    }
+
+    #[test]
+    fn new_clients_over_the_cap_evict_the_oldest() {
+        let buckets = TokenBuckets::new(1, 1);
+        let start = Instant::now();
+        for i in 0..MAX_CLIENTS {
+            let now = start + Duration::from_millis(u64::try_from(i).unwrap());
+            assert!(buckets.acquire_at(&format!("c{i}"), now).is_ok());
+        }
+        assert_eq!(len(&buckets), MAX_CLIENTS);
+        // все корзины пустые, вытеснение не ждет пока они наполнятся
+        let now = start + Duration::from_millis(u64::try_from(MAX_CLIENTS).unwrap());
+        assert!(buckets.acquire_at("new", now).is_ok());
+        assert_eq!(len(&buckets), MAX_CLIENTS - EVICT_BATCH + 1);
+        let buckets_map = buckets.buckets.lock().unwrap();
+        assert!(!buckets_map.contains_key("c0"));
+        assert!(!buckets_map.contains_key(&format!("c{}", EVICT_BATCH - 1)));
+        assert!(buckets_map.contains_key(&format!("c{EVICT_BATCH}")));
+    }
+
+    #[test]
+    fn sweep_forgets_full_buckets_only() {
+        let buckets = TokenBuckets::new(1, 2);
+        let start = Instant::now();
+        assert!(buckets.acquire_at("idle", start).is_ok());
+        let later = start + Duration::from_secs(10);
+        assert!(buckets.acquire_at("busy", later).is_ok());
+        buckets.sweep(later);
+        let buckets_map = buckets.buckets.lock().unwrap();
+        assert!(!buckets_map.contains_key("idle"));
+        assert!(buckets_map.contains_key("busy"));
+    }
+}
@@ -0,0 +1,275 @@
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt, str::FromStr, sync::OnceLock};
+
+use crate::{config, order_errors::OrderError};
+
+static REDACTION: OnceLock<Redaction> = OnceLock::new();
+
+// Поля доставки и оплаты которые можно скрывать в логах, и как они скрываются по умолчанию
+const DEFAULT_RULES: &[(&str, Rule)] = &[
+    ("delivery.name", Rule::Full),
+    ("delivery.phone", Rule::Partial),
+    ("delivery.zip", Rule::Full),
+    ("delivery.city", Rule::Keep),
+    ("delivery.address", Rule::Full),
+    ("delivery.region", Rule::Keep),
+    ("delivery.email", Rule::Hash),
+    ("payment.transaction", Rule::Hash),
+    ("payment.request_id", Rule::Hash),
+    ("payment.currency", Rule::Keep),
+    ("payment.provider", Rule::Keep),
+    ("payment.bank", Rule::Keep),
+];
+
+// Как поле выглядит в логе
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    // как есть
+    Keep,
+    // ***
+    Full,
+    // только последние 4 символа: ***0000
+    Partial,
+    // начало sha256, по нему можно найти записи с тем же значением не видя его
+    Hash,
+}
+
+impl Rule {
+    fn apply(self, value: &str) -> String {
+        // пустое поле скрывать незачем, а так видно что его не прислали
+        if value.is_empty() {
+            return String::new();
+        }
+        match self {
+            Rule::Keep => value.to_string(),
+            Rule::Full => "***".to_string(),
+            Rule::Partial => {
+                let count = value.chars().count();
+                if count <= 4 {
+                    "***".to_string()
+                } else {
+                    format!("***{}", value.chars().skip(count - 4).collect::<String>())
+                }
+            }
+            Rule::Hash => format!("sha256:{}", &hex::encode(Sha256::digest(value.as_bytes()))[..12]),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "keep" => Ok(Rule::Keep),
+            "full" => Ok(Rule::Full),
+            "partial" => Ok(Rule::Partial),
+            "hash" => Ok(Rule::Hash),
+            other => Err(format!("Unknown redaction rule: {other}, expected keep, full, partial or hash")),
+        }
+    }
+}
+
+// Правила скрытия персональных данных в логах. unsafe_debug - все как есть, только для локальной разработки
+#[derive(Debug)]
+pub struct Redaction {
+    rules: HashMap<&'static str, Rule>,
+    unsafe_debug: bool,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction { rules: DEFAULT_RULES.iter().copied().collect(), unsafe_debug: false }
+    }
+}
+
+impl Redaction {
+    // Правила по умолчанию, поверх них LOG_REDACT вида "delivery.city=full,payment.transaction=partial".
+    // LOG_UNSAFE_DEBUG=on действует только при APP_ENV=dev
+    pub fn from_env() -> Result<Self, OrderError> {
+        Redaction::parse(&config::log_redact(), config::log_unsafe_debug(), config::dev_mode())
+    }
+
+    fn parse(rules: &str, unsafe_debug: bool, dev_mode: bool) -> Result<Self, OrderError> {
+        let mut redaction = Redaction::default();
+        for rule in rules.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let invalid = |msg: String| OrderError::Validation { msg, field: "LOG_REDACT".to_string() };
+            let (field, value) = rule
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("Redaction rule must look like field=rule, got {rule}")))?;
+            let field = DEFAULT_RULES
+                .iter()
+                .map(|(known, _)| *known)
+                .find(|known| *known == field.trim())
+                .ok_or_else(|| invalid(format!("Unknown field for redaction: {}", field.trim())))?;
+            redaction.rules.insert(field, value.parse().map_err(invalid)?);
+        }
+        redaction.unsafe_debug = unsafe_debug && dev_mode;
+        Ok(redaction)
+    }
+
+    fn field(&self, field: &str, value: &str) -> String {
+        if self.unsafe_debug {
+            return value.to_string();
+        }
+        self.rules.get(field).copied().unwrap_or(Rule::Full).apply(value)
+    }
+
+    fn db_error(&self, error: &tokio_postgres::Error) -> String {
+        match error.as_db_error() {
+            Some(db) if !self.unsafe_debug => format!("db error: {}: {} ({})", db.severity(), db.message(), db.code().code()),
+            _ => error.to_string(),
+        }
+    }
+}
+
+// Ставится один раз при запуске, до этого (и в cli командах без настройки) действуют правила по умолчанию
+pub fn init(redaction: Redaction) {
+    if config::log_unsafe_debug() && !redaction.unsafe_debug {
+        warn!("LOG_UNSAFE_DEBUG=on is ignored outside of APP_ENV=dev, personal data stays redacted");
+    }
+    if redaction.unsafe_debug {
+        warn!("LOG_UNSAFE_DEBUG=on: personal data is written to logs as is");
+    }
+    let _ = REDACTION.set(redaction);
+}
+
+fn current() -> &'static Redaction {
+    REDACTION.get_or_init(Redaction::default)
+}
+
+// Значение поля для Debug, field - имя из DEFAULT_RULES
+pub fn field(field: &str, value: &str) -> String {
+    current().field(field, value)
+}
+
+// Ошибка базы для лога. Display у tokio_postgres добавляет DETAIL сервера, а в нем значения строки:
+// "Key (tenant_id, transaction)=(...) already exists", "Failing row contains (...)" с именем и адресом.
+// Пишутся только сообщение и код ошибки
+pub fn db_error(error: &tokio_postgres::Error) -> String {
+    current().db_error(error)
+}
+
+// tokio_postgres на debug пишет параметры запросов как есть, а в них те же имена, телефоны и адреса.
+// Для таких записей возвращает сообщение без параметров
+pub fn log_message(target: &str, message: &fmt::Arguments) -> Option<String> {
+    if !target.starts_with("tokio_postgres") || current().unsafe_debug {
+        return None;
+    }
+    let message = message.to_string();
+    let (query, _) = message.split_once(" with parameters: ")?;
+    Some(format!("{query} with parameters: [redacted]"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Delivery, Payment};
+
+    fn delivery() -> Delivery {
+        Delivery {
+            name: "Test Testov".to_string(),
+            phone: "+9720000000".to_string(),
+            zip: "2639809".to_string(),
+            city: "Kiryat Mozkin".to_string(),
+            address: "Ploshad Mira 15".to_string(),
+            region: "Kraiot".to_string(),
+            email: "test@gmail.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(Rule::Keep.apply("Kraiot"), "Kraiot");
+        assert_eq!(Rule::Full.apply("Test Testov"), "***");
+        assert_eq!(Rule::Partial.apply("+9720000000"), "***0000");
+        // короткое значение целиком
+        assert_eq!(Rule::Partial.apply("1234"), "***");
+        assert_eq!(Rule::Hash.apply("test@gmail.com"), "sha256:87924606b413");
+        assert_eq!(Rule::Hash.apply("test@gmail.com"), Rule::Hash.apply("test@gmail.com"));
+        assert_ne!(Rule::Hash.apply("test@gmail.com"), Rule::Hash.apply("other@gmail.com"));
+        // пустое поле остается пустым при любом правиле
+        for rule in [Rule::Keep, Rule::Full, Rule::Partial, Rule::Hash] {
+            assert_eq!(rule.apply(""), "");
+        }
+    }
+
+    #[test]
+    fn log_redact_overrides_defaults() {
+        let redaction = Redaction::parse(" delivery.city=full, delivery.phone = HASH ,", false, false).unwrap();
+        assert_eq!(redaction.field("delivery.city", "Kiryat Mozkin"), "***");
+        assert!(redaction.field("delivery.phone", "+9720000000").starts_with("sha256:"));
+        // остальные по умолчанию
+        assert_eq!(redaction.field("delivery.name", "Test Testov"), "***");
+        assert_eq!(redaction.field("payment.bank", "alpha"), "alpha");
+        // поле без правила скрывается целиком
+        assert_eq!(redaction.field("delivery.unknown", "value"), "***");
+    }
+
+    #[test]
+    fn log_redact_errors() {
+        for (rules, message) in [
+            ("delivery.city", "must look like field=rule"),
+            ("delivery.passport=full", "Unknown field for redaction: delivery.passport"),
+            ("delivery.city=blur", "Unknown redaction rule: blur"),
+        ] {
+            match Redaction::parse(rules, false, false) {
+                Err(OrderError::Validation { msg, field }) => {
+                    assert!(msg.contains(message), "{rules}: {msg}");
+                    assert_eq!(field, "LOG_REDACT");
+                }
+                other => panic!("{rules}: expected a validation error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unsafe_debug_only_in_dev() {
+        let redaction = Redaction::parse("", true, false).unwrap();
+        assert!(!redaction.unsafe_debug);
+        assert_eq!(redaction.field("delivery.name", "Test Testov"), "***");
+        assert!(!Redaction::parse("", false, true).unwrap().unsafe_debug);
+
+        let redaction = Redaction::parse("", true, true).unwrap();
+        assert_eq!(redaction.field("delivery.name", "Test Testov"), "Test Testov");
+        assert_eq!(redaction.field("delivery.email", "test@gmail.com"), "test@gmail.com");
+    }
+
+    #[test]
+    fn debug_hides_delivery_and_payment() {
+        let delivery = format!("{:?}", delivery());
+        for hidden in ["Test Testov", "+9720000000", "2639809", "Ploshad Mira 15", "test@gmail.com"] {
+            assert!(!delivery.contains(hidden), "{hidden} in {delivery}");
+        }
+        assert!(delivery.contains(r#"phone: "***0000""#), "{delivery}");
+        assert!(delivery.contains(r#"city: "Kiryat Mozkin""#), "{delivery}");
+        assert!(delivery.contains(r#"email: "sha256:"#), "{delivery}");
+
+        let payment = Payment {
+            transaction: "b563feb7b2b84b6test".to_string(),
+            request_id: "req-42".to_string(),
+            currency: "USD".to_string(),
+            provider: "wbpay".to_string(),
+            amount: 1817,
+            payment_dt: 1_637_907_727,
+            bank: "alpha".to_string(),
+            delivery_cost: 1500,
+            goods_total: 317,
+            custom_fee: 0,
+        };
+        let payment = format!("{payment:?}");
+        assert!(!payment.contains("b563feb7b2b84b6test"), "{payment}");
+        assert!(!payment.contains("req-42"), "{payment}");
+        assert!(payment.contains(r#"currency: "USD""#), "{payment}");
+        assert!(payment.contains("amount: 1817"), "{payment}");
+    }
+
+    #[test]
+    fn postgres_parameters_are_hidden() {
+        let message = log_message("tokio_postgres::query", &format_args!("executing statement s1 with parameters: [\"Test Testov\"]"));
+        assert_eq!(message.as_deref(), Some("executing statement s1 with parameters: [redacted]"));
+        assert_eq!(log_message("axum_study_project_1::order_handler", &format_args!("with parameters: [1]")), None);
+        assert_eq!(log_message("tokio_postgres::query", &format_args!("preparing query s1")), None);
+    }
+}
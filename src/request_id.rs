@@ -0,0 +1,79 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    // id запроса который сейчас обрабатывается, его добавляют к себе записи лога и ответы с ошибкой
+    pub(crate) static REQUEST_ID: String;
+}
+
+// id текущего запроса, вне обработки запроса (фоновые задачи, cli) None
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+// Берет X-Request-Id клиента (или прокси перед сервисом), без него или с кривым генерирует свой.
+// Все что логируется пока обрабатывается запрос помечается этим id, он же возвращается в X-Request-Id ответа.
+// Задачи запущенные из хендлера получают id только через request_id::spawn
+pub async fn request_id(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| valid(id))
+        .map_or_else(generate, str::to_string);
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+// tokio::spawn для задач запроса: id текущего запроса task_local и в новую задачу сам не переходит
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match current() {
+        Some(id) => tokio::spawn(REQUEST_ID.scope(id, future)),
+        None => tokio::spawn(future),
+    }
+}
+
+// id попадает в логи как есть, поэтому только печатные ascii символы без пробелов
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn generate() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spawned_task_keeps_request_id() {
+        let id = REQUEST_ID
+            .scope("r1".to_string(), async { spawn(async { current() }).await.unwrap() })
+            .await;
+        assert_eq!(id.as_deref(), Some("r1"));
+        assert_eq!(spawn(async { current() }).await.unwrap(), None);
+        // обычный tokio::spawn id теряет
+        let lost = REQUEST_ID
+            .scope("r1".to_string(), async { tokio::spawn(async { current() }).await.unwrap() })
+            .await;
+        assert_eq!(lost, None);
+    }
+}
@@ -0,0 +1,35 @@
+use axum::extract::FromRef;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::{
+    auth::Auth, config::CustomerPolicy, db::Db, graphql::OrdersSchema, order_cache::OrderCache, order_changes::OrderChanges,
+    order_events::OrderEvents, rate_limit::RateLimits, webhooks::WebhookQueue,
+};
+
+// Общее состояние приложения, FromRef позволяет хендлерам
+// доставать из него только то что им нужно через State<...>
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    // общий клиент базы и подготовленные на нем запросы
+    pub db: Db,
+    pub customer_policy: CustomerPolicy,
+    // события по заказам для подписчиков (gRPC WatchOrders)
+    pub events: OrderEvents,
+    pub graphql: OrdersSchema,
+    // будит воркер доставки вебхуков
+    pub webhooks: WebhookQueue,
+    // кэш заказов для чтения по order_uid
+    pub orders_cache: OrderCache,
+    // изменения в базе от всех экземпляров сервиса (LISTEN order_changes)
+    pub changes: OrderChanges,
+    // проверка API ключей и JWT
+    pub auth: Auth,
+    // лимиты запросов по клиентам и на весь сервис
+    pub rate_limits: RateLimits,
+    // отдает собранные метрики на /metrics
+    pub metrics: PrometheusHandle,
+    // разрешения на выгрузки, по одному на каждое отдельное соединение с базой
+    pub exports: Arc<Semaphore>,
+}
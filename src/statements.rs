@@ -0,0 +1,36 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+use tokio_postgres::{Statement, Transaction};
+
+use crate::order_errors::OrderError;
+
+// Кэш подготовленных запросов, чтобы не гонять PREPARE на каждую вставку.
+// Statement живет только в рамках соединения на котором его подготовили,
+// поэтому кэш создается на каждое соединение отдельно и хранится рядом с клиентом (db.rs)
+#[derive(Default)]
+pub struct StatementCache {
+    statements: Mutex<HashMap<&'static str, Statement>>,
+}
+
+impl StatementCache {
+    pub async fn prepare(&self, tx: &Transaction<'_>, query: &'static str) -> Result<Statement, OrderError> {
+        if let Some(statement) = self.statements().get(query).cloned() {
+            return Ok(statement);
+        }
+
+        let statement = tx.prepare(query).await?;
+        self.statements().insert(query, statement.clone());
+        Ok(statement)
+    }
+
+    // соединение сменилось, подготовленные на старом запросы на новом не существуют
+    pub fn clear(&self) {
+        self.statements().clear();
+    }
+
+    fn statements(&self) -> MutexGuard<'_, HashMap<&'static str, Statement>> {
+        self.statements.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
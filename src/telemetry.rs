@@ -0,0 +1,241 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use log::{info, warn};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource};
+use std::{fmt::{self, Write}, str::FromStr};
+use tokio_postgres::{Row, Transaction};
+use tracing::{field::{Empty, Field, Visit}, info_span, Event, Instrument, Level, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::Targets, layer::{Context, SubscriberExt}, Layer};
+
+use crate::{config, redact, request_id};
+
+// Куда отправлять спаны
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceExporter {
+    // трейсинг выключен, спаны создаются но никуда не попадают
+    None,
+    // OTLP по gRPC, адрес коллектора в OTEL_EXPORTER_OTLP_ENDPOINT (по умолчанию http://localhost:4317)
+    Otlp,
+    // JSON в stdout, для локальной отладки без коллектора
+    Stdout,
+}
+
+impl FromStr for TraceExporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" | "off" | "" => Ok(TraceExporter::None),
+            "otlp" => Ok(TraceExporter::Otlp),
+            "stdout" | "console" => Ok(TraceExporter::Stdout),
+            other => Err(format!("Unknown traces exporter: {other}, expected none, otlp or stdout")),
+        }
+    }
+}
+
+// Трейсинг из OTEL_TRACES_EXPORTER. Спаны пишутся через tracing, слой tracing-opentelemetry отдает их
+// экспортеру. Провайдер надо закрыть через shutdown, иначе последние спаны из пачки не уйдут
+pub fn init() -> Result<Option<TracerProvider>, Box<dyn std::error::Error>> {
+    let exporter: TraceExporter = config::traces_exporter().parse()?;
+    let builder = TracerProvider::builder().with_resource(Resource::new_with_defaults([KeyValue::new(
+        "service.name",
+        config::service_name(),
+    )]));
+    let provider = match exporter {
+        TraceExporter::None => {
+            info!("Tracing is off (OTEL_TRACES_EXPORTER=none)");
+            return Ok(None);
+        }
+        // адрес и заголовки коллектора exporter сам берет из OTEL_EXPORTER_OTLP_*
+        TraceExporter::Otlp => {
+            let otlp = opentelemetry_otlp::SpanExporter::builder().with_tonic().build()?;
+            builder.with_batch_exporter(otlp, runtime::Tokio).build()
+        }
+        // stdout без пачек, спан печатается сразу как закончился
+        TraceExporter::Stdout => builder.with_simple_exporter(opentelemetry_stdout::SpanExporter::default()).build(),
+    };
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+
+    // в трейсы идут только свои спаны и события, спаны hyper, h2 и tonic туда не нужны
+    let spans = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        // место в коде у всех спанов запросов к базе и у всех логов было бы одно - этот файл
+        .with_location(false)
+        .with_threads(false)
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE));
+    let exporter_errors = ExporterErrors.with_filter(Targets::new().with_target("opentelemetry", Level::WARN));
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(spans).with(exporter_errors))?;
+    info!("Tracing is on, exporter: {exporter:?}");
+    Ok(Some(provider))
+}
+
+pub fn shutdown(provider: Option<TracerProvider>) {
+    if let Some(Err(e)) = provider.map(|provider| provider.shutdown()) {
+        warn!("Failed to flush traces: {e}");
+    }
+}
+
+// Ошибки экспорта (нет коллектора и т.п.) opentelemetry пишет событиями tracing, а не в log,
+// этот слой переносит их в наш лог
+struct ExporterErrors;
+
+impl<S: Subscriber> Layer<S> for ExporterErrors {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = EventFields(String::new());
+        event.record(&mut fields);
+        warn!("{}:{}", event.metadata().target(), fields.0);
+    }
+}
+
+struct EventFields(String);
+
+impl Visit for EventFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = write!(self.0, " {}={value:?}", field.name());
+    }
+}
+
+// Запись лога в текущий спан событием, так логи видны в трейсе рядом со спаном который их написал.
+// Вне спана (фоновые задачи, выключенный трейсинг) ничего не делает
+pub fn log_to_span(record: &log::Record) {
+    if Span::current().is_none() {
+        return;
+    }
+    let message = redact::log_message(record.target(), record.args()).unwrap_or_else(|| record.args().to_string());
+    let target = record.target();
+    match record.level() {
+        log::Level::Error => tracing::error!(log.target = target, "{message}"),
+        log::Level::Warn => tracing::warn!(log.target = target, "{message}"),
+        log::Level::Info => tracing::info!(log.target = target, "{message}"),
+        log::Level::Debug => tracing::debug!(log.target = target, "{message}"),
+        log::Level::Trace => tracing::trace!(log.target = target, "{message}"),
+    }
+}
+
+// Заголовки запроса для W3C propagator
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+// Спан на HTTP запрос, родитель берется из traceparent клиента, так запрос продолжает его трейс.
+// Спаны хендлеров и запросов к базе вложены в него
+pub async fn trace_request(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let span = info_span!(
+        "http_request",
+        otel.name = format!("{method} {route}"),
+        otel.kind = "server",
+        http.request.method = %method,
+        http.route = %route,
+        http.response.status_code = Empty,
+        request_id = request_id::current(),
+        otel.status_code = Empty,
+    );
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+    span.set_parent(parent);
+
+    let response = next.run(req).instrument(span.clone()).await;
+    span.record("http.response.status_code", i64::from(response.status().as_u16()));
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    response
+}
+
+// Сколько строк вернул или изменил запрос, для спанов запросов к базе
+pub trait RowCount {
+    fn row_count(&self) -> Option<u64>;
+}
+
+impl RowCount for u64 {
+    fn row_count(&self) -> Option<u64> {
+        Some(*self)
+    }
+}
+
+impl RowCount for Vec<Row> {
+    fn row_count(&self) -> Option<u64> {
+        u64::try_from(self.len()).ok()
+    }
+}
+
+impl RowCount for Row {
+    fn row_count(&self) -> Option<u64> {
+        Some(1)
+    }
+}
+
+impl RowCount for Option<Row> {
+    fn row_count(&self) -> Option<u64> {
+        Some(u64::from(self.is_some()))
+    }
+}
+
+impl RowCount for () {
+    fn row_count(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl RowCount for Transaction<'_> {
+    fn row_count(&self) -> Option<u64> {
+        None
+    }
+}
+
+// Спан запроса к базе, statement - имя запроса как в метриках (insert_orders, query_order и т.п.).
+// Только внутри другого спана: фоновые опросы outbox и вебхуков иначе давали бы по трейсу каждые пару секунд
+pub fn query_span(statement: &str) -> Span {
+    if Span::current().is_none() {
+        return Span::none();
+    }
+    let statement = statement.replace(' ', "_");
+    info_span!(
+        "db_query",
+        otel.name = %statement,
+        otel.kind = "client",
+        db.system = "postgresql",
+        db.statement.name = %statement,
+        db.rows = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    )
+}
+
+pub fn finish_query<T: RowCount, E: fmt::Display>(span: &Span, result: &Result<T, E>) {
+    match result {
+        Ok(value) => {
+            if let Some(rows) = value.row_count() {
+                span.record("db.rows", i64::try_from(rows).unwrap_or(i64::MAX));
+            }
+        }
+        Err(e) => {
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_message", e.to_string());
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap},
+};
+use log::warn;
+use std::fmt;
+use tokio_postgres::{Client, GenericClient};
+
+use crate::{auth::Principal, config, order_errors::OrderError, order_impl::db_timeout};
+
+pub const TENANT_HEADER: &str = "x-tenant-id";
+const MAX_TENANT_LEN: usize = 64;
+// app.tenant_id для фоновых задач которые работают со всеми арендаторами
+const ALL_TENANTS: &str = "*";
+
+// Арендатор (маркетплейс) от имени которого идет запрос, все запросы к заказам фильтруются по нему
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tenant(String);
+
+impl Tenant {
+    pub fn new(id: &str) -> Result<Self, OrderError> {
+        let id = id.trim();
+        let valid = !id.is_empty()
+            && id.len() <= MAX_TENANT_LEN
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(OrderError::Validation {
+                msg: format!("Tenant id must be 1 to {MAX_TENANT_LEN} letters, digits, '-' or '_'"),
+                field: "tenant_id".to_string(),
+            });
+        }
+        Ok(Tenant(id.to_string()))
+    }
+
+    // Арендатор из аргумента cli команды, без аргумента - по умолчанию
+    pub fn from_arg(tenant: Option<&str>) -> Result<Self, OrderError> {
+        tenant.map_or_else(|| Ok(Tenant::default()), Tenant::new)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // Арендатор из ключа или токена главнее заголовка, чужой в X-Tenant-ID - 403.
+    // Заголовок учитывается только если учетные данные ни к кому не привязаны (ключ платформы, API_AUTH=off,
+    // публичные маршруты). Покупатель без арендатора в токене относится к арендатору по умолчанию
+    pub fn resolve(principal: Option<&Principal>, headers: &HeaderMap) -> Result<Self, OrderError> {
+        let requested = headers
+            .get(TENANT_HEADER)
+            .map(|value| value.to_str().unwrap_or_default().trim())
+            .filter(|value| !value.is_empty());
+        Tenant::resolve_requested(principal, requested)
+    }
+
+    pub fn resolve_requested(principal: Option<&Principal>, requested: Option<&str>) -> Result<Self, OrderError> {
+        let bound = principal.and_then(|principal| match &principal.tenant_id {
+            Some(tenant_id) => Some(tenant_id.clone()),
+            None if principal.customer_id.is_some() => Some(config::default_tenant()),
+            None => None,
+        });
+        match (bound, requested) {
+            (Some(bound), Some(requested)) if bound != requested => Err(OrderError::Forbidden(format!(
+                "{} can't access tenant {requested}",
+                principal.map_or("-", |principal| principal.subject.as_str())
+            ))),
+            (Some(tenant), _) => Tenant::new(&tenant),
+            (None, Some(requested)) => Tenant::new(requested),
+            (None, None) => Ok(Tenant::default()),
+        }
+    }
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Tenant(config::default_tenant())
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// За auth middleware арендатор берется из проверенного Principal, на публичных маршрутах - из заголовка
+#[async_trait]
+impl<S> FromRequestParts<S> for Tenant
+where
+    S: Send + Sync,
+{
+    type Rejection = OrderError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Tenant::resolve(parts.extensions.get::<Principal>(), &parts.headers)
+    }
+}
+
+// При TENANT_RLS=on ставит app.tenant_id для политик из rls.sql, без этого строки заказов не видны.
+// Вызывается перед каждым запросом к таблицам заказов, соединение общее и в нем мог остаться чужой арендатор
+pub async fn bind(client: &impl GenericClient, tenant: &Tenant) -> Result<(), OrderError> {
+    set_tenant(client, tenant.as_str()).await
+}
+
+// Для фоновых задач на своем соединении, им видны заказы всех арендаторов
+pub async fn bind_all(client: &Client) -> Result<(), OrderError> {
+    set_tenant(client, ALL_TENANTS).await
+}
+
+async fn set_tenant(client: &impl GenericClient, tenant: &str) -> Result<(), OrderError> {
+    if config::tenant_rls() {
+        db_timeout(
+            "set tenant",
+            client.execute("SELECT set_config('app.tenant_id', $1, false)", &[&tenant]),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// Суперпользователь не проверяет политики, тогда TENANT_RLS ничего не дает
+pub async fn check_rls(client: &Client) -> Result<(), OrderError> {
+    if !config::tenant_rls() {
+        return Ok(());
+    }
+    let row = db_timeout(
+        "query database role",
+        client.query_one(
+            "SELECT rolsuper OR rolbypassrls AS bypass FROM pg_roles WHERE rolname = current_user",
+            &[],
+        ),
+    )
+    .await?;
+    if row.get::<_, bool>("bypass") {
+        warn!("TENANT_RLS=on, but the database role bypasses row level security");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::Scope;
+
+    fn principal(tenant_id: Option<&str>, customer_id: Option<&str>) -> Principal {
+        Principal {
+            subject: "test".to_string(),
+            client_key: "test".to_string(),
+            scopes: vec![Scope::OrdersRead],
+            customer_id: customer_id.map(str::to_string),
+            tenant_id: tenant_id.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn bound_principal_wins_over_requested_tenant() {
+        let bound = principal(Some("a"), None);
+        assert_eq!(Tenant::resolve_requested(Some(&bound), None).unwrap().as_str(), "a");
+        assert_eq!(Tenant::resolve_requested(Some(&bound), Some("a")).unwrap().as_str(), "a");
+        assert!(matches!(
+            Tenant::resolve_requested(Some(&bound), Some("b")),
+            Err(OrderError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn customer_without_tenant_belongs_to_default_tenant() {
+        let customer = principal(None, Some("c1"));
+        assert_eq!(Tenant::resolve_requested(Some(&customer), None).unwrap(), Tenant::default());
+        assert!(matches!(
+            Tenant::resolve_requested(Some(&customer), Some("other")),
+            Err(OrderError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn unbound_credentials_use_requested_or_default_tenant() {
+        let platform = principal(None, None);
+        assert_eq!(Tenant::resolve_requested(Some(&platform), Some("b")).unwrap().as_str(), "b");
+        assert_eq!(Tenant::resolve_requested(None, Some("b")).unwrap().as_str(), "b");
+        assert_eq!(Tenant::resolve_requested(None, None).unwrap(), Tenant::default());
+        assert!(matches!(
+            Tenant::resolve_requested(None, Some("bad tenant")),
+            Err(OrderError::Validation { .. })
+        ));
+    }
+}
@@ -0,0 +1,79 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use log::info;
+use serde_json::json;
+
+use crate::{
+    db::Db,
+    models::Pagination,
+    negotiation::Negotiated,
+    order_errors::OrderError,
+    tenant::Tenant,
+    webhooks::{NewWebhook, Webhook, WebhookDelivery, WebhookQueue},
+};
+
+// Подписка на события заказов, секрет для проверки подписи возвращается только здесь
+pub async fn create_webhook(
+    State(db): State<Db>,
+    tenant: Tenant,
+    Negotiated(new): Negotiated<NewWebhook>,
+) -> Result<impl IntoResponse, OrderError> {
+    new.validate().await?;
+    let client = db.lock().await;
+    let webhook = Webhook::create(&client, &tenant, new).await?;
+    info!("Webhook {} created for {} (tenant {})", webhook.id, webhook.url, tenant);
+    Ok((StatusCode::CREATED, Negotiated(webhook)))
+}
+
+pub async fn list_webhooks(
+    State(db): State<Db>,
+    tenant: Tenant,
+) -> Result<Negotiated<Vec<Webhook>>, OrderError> {
+    let client = db.lock().await;
+    Ok(Negotiated(Webhook::list(&client, &tenant).await?))
+}
+
+pub async fn delete_webhook(
+    Path(id): Path<i64>,
+    State(db): State<Db>,
+    tenant: Tenant,
+) -> Result<impl IntoResponse, OrderError> {
+    let client = db.lock().await;
+    Webhook::delete(&client, &tenant, id).await?;
+    info!("Webhook {} deleted", id);
+    Ok(Negotiated(json!({"success": true, "message": "Webhook deleted"})))
+}
+
+// Журнал доставок подписки, новые сверху
+pub async fn list_webhook_deliveries(
+    Path(id): Path<i64>,
+    State(db): State<Db>,
+    tenant: Tenant,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<WebhookDelivery>>, OrderError> {
+    let limit = pagination.limit.unwrap_or(10);
+    let offset = pagination.offset.unwrap_or(0);
+
+    let client = db.lock().await;
+    Ok(Negotiated(WebhookDelivery::list(&client, &tenant, id, limit, offset).await?))
+}
+
+// Ручная повторная отправка, сама отправка идет в фоне поэтому 202
+pub async fn redeliver_webhook(
+    Path(id): Path<i64>,
+    State(db): State<Db>,
+    State(queue): State<WebhookQueue>,
+    tenant: Tenant,
+) -> Result<impl IntoResponse, OrderError> {
+    let client = db.lock().await;
+    WebhookDelivery::redeliver(&client, &tenant, id).await?;
+    queue.wake();
+    info!("Webhook delivery {} queued for redelivery", id);
+    Ok((
+        StatusCode::ACCEPTED,
+        Negotiated(json!({"success": true, "message": "Delivery queued"})),
+    ))
+}
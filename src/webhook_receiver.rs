@@ -0,0 +1,74 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use log::{error, info, warn};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::net::TcpListener;
+
+use crate::{
+    cli::WebhookReceiverArgs,
+    webhooks::{verify, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+};
+
+#[derive(Clone)]
+struct Receiver {
+    secret: Option<Arc<str>>,
+    fail_first: usize,
+    received: Arc<AtomicUsize>,
+}
+
+// Локальный приемник вебхуков для проверки: печатает доставки, проверяет подпись
+// и может отвечать 500 на первые запросы чтобы посмотреть на повторы
+pub async fn run(args: WebhookReceiverArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if args.secret.is_none() {
+        warn!("--secret is not set, signatures are not checked");
+    }
+    let app = router(args.secret.as_deref(), args.fail_first);
+    let listener = TcpListener::bind(&args.addr).await?;
+    info!("Webhook receiver listening on {}", args.addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+// Отдельно от run, тесты доставки вебхуков поднимают этот же приемник
+pub fn router(secret: Option<&str>, fail_first: usize) -> Router {
+    let receiver = Receiver {
+        secret: secret.map(Arc::from),
+        fail_first,
+        received: Arc::new(AtomicUsize::new(0)),
+    };
+    Router::new().route("/*path", post(receive)).route("/", post(receive)).with_state(receiver)
+}
+
+async fn receive(State(receiver): State<Receiver>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let number = receiver.received.fetch_add(1, Ordering::SeqCst) + 1;
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+    let body = String::from_utf8_lossy(&body);
+    info!(
+        "#{number} webhook {} delivery {} event {}: {body}",
+        header("x-webhook-id"),
+        header("x-webhook-delivery"),
+        header("x-webhook-event"),
+    );
+
+    if let Some(secret) = &receiver.secret {
+        let timestamp = header(TIMESTAMP_HEADER).parse().unwrap_or_default();
+        if !verify(secret, timestamp, &body, &header(SIGNATURE_HEADER)) {
+            error!("#{number} signature mismatch");
+            return StatusCode::UNAUTHORIZED;
+        }
+        info!("#{number} signature ok");
+    }
+    if number <= receiver.fail_first {
+        warn!("#{number} failing on purpose ({number} of {})", receiver.fail_first);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    StatusCode::OK
+}
@@ -0,0 +1,79 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+
+use crate::{config, order_errors::OrderError};
+
+// Секреты вебхуков хранятся в базе зашифрованными AES-256-GCM ключом WEBHOOK_SECRET_KEY:
+// "v1:" и hex от nonce и шифротекста. Из одного дампа базы подписи уже не подделать
+const PREFIX: &str = "v1:";
+const NONCE_LEN: usize = 12;
+
+// Ключ из настроек, без него секрет не зашифровать и не расшифровать
+pub fn key() -> Result<[u8; 32], OrderError> {
+    config::webhook_secret_key()
+        .map_err(OrderError::Internal)?
+        .ok_or_else(|| OrderError::Internal("WEBHOOK_SECRET_KEY is not set".to_string()))
+}
+
+pub fn seal(key: &[u8; 32], secret: &str) -> Result<String, OrderError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(&nonce, secret.as_bytes())
+        .map_err(|_| OrderError::Internal("Failed to encrypt webhook secret".to_string()))?;
+    Ok(format!("{PREFIX}{}{}", hex::encode(nonce), hex::encode(sealed)))
+}
+
+// Строки без префикса записаны до шифрования, их воркер вебхуков шифрует при запуске
+pub fn open(key: &[u8; 32], stored: &str) -> Result<String, OrderError> {
+    let Some(sealed) = stored.strip_prefix(PREFIX) else {
+        return Ok(stored.to_string());
+    };
+    let broken = || OrderError::Internal("Failed to decrypt webhook secret, wrong WEBHOOK_SECRET_KEY?".to_string());
+    let sealed = hex::decode(sealed).map_err(|_| broken())?;
+    if sealed.len() < NONCE_LEN {
+        return Err(broken());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let secret = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| broken())?;
+    String::from_utf8(secret).map_err(|_| broken())
+}
+
+// Секрет из строки в базе для подписи, ключ нужен только для зашифрованных
+pub fn reveal(stored: &str) -> Result<String, OrderError> {
+    if !stored.starts_with(PREFIX) {
+        return Ok(stored.to_string());
+    }
+    open(&key()?, stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn sealed_secret_opens_with_the_same_key_only() {
+        let sealed = seal(&KEY, "webhook-secret-123").unwrap();
+        assert!(sealed.starts_with(PREFIX));
+        assert!(!sealed.contains("webhook-secret-123"));
+        assert_eq!(open(&KEY, &sealed).unwrap(), "webhook-secret-123");
+        assert!(open(&[8; 32], &sealed).is_err());
+    }
+
+    #[test]
+    fn every_seal_uses_a_new_nonce() {
+        assert_ne!(seal(&KEY, "same").unwrap(), seal(&KEY, "same").unwrap());
+    }
+
+    #[test]
+    fn plaintext_rows_are_read_as_is() {
+        assert_eq!(open(&KEY, "old-plaintext-secret").unwrap(), "old-plaintext-secret");
+        assert!(open(&KEY, "v1:zz").is_err());
+        assert!(open(&KEY, "v1:00").is_err());
+    }
+}
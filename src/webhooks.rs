@@ -0,0 +1,751 @@
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Url,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::lookup_host,
+    sync::Notify,
+    task::JoinSet,
+    time::{interval, Duration},
+};
+use tokio_postgres::{Client, Row};
+
+use crate::{
+    config,
+    connect_retry,
+    db::Db,
+    order_errors::OrderError,
+    order_events::OrderEvent,
+    order_impl::db_timeout,
+    outbox::EventSink,
+    tenant::Tenant,
+    webhook_secrets,
+};
+
+// События на которые можно подписаться, как kind в OrderEvent
+pub const EVENT_TYPES: [&str; 3] = ["created", "item_added", "item_status_changed"];
+
+// После стольких неудачных попыток доставка помечается failed, дальше только ручной redeliver
+const MAX_ATTEMPTS: i32 = 8;
+// Пауза перед повтором: 10s, 20s, 40s ... но не больше часа
+const BASE_BACKOFF_SECS: i64 = 10;
+const MAX_BACKOFF_SECS: i64 = 3600;
+// Как часто проверять доставки которым пора повториться
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Сколько доставок забирать за раз, отправляются параллельно
+const DELIVERY_BATCH: i64 = 20;
+// На сколько доставка "занимается" воркером, чтобы другой экземпляр сервиса не отправил ее параллельно
+const LEASE_SECS: f64 = 60.0;
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+
+#[derive(Debug, Deserialize)]
+pub struct NewWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub event_types: Vec<String>,
+    // если не передать - сгенерируется
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub created_at: String,
+    // секрет отдается только один раз, при создании
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event_id: i64,
+    pub event_type: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: String,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+}
+
+// Будильник для воркера доставок: новые доставки и redeliver не ждут следующего опроса
+#[derive(Clone, Default)]
+pub struct WebhookQueue {
+    wakeup: Arc<Notify>,
+}
+
+impl WebhookQueue {
+    pub fn wake(&self) {
+        self.wakeup.notify_one();
+    }
+}
+
+impl NewWebhook {
+    // Проверка до блокировки клиента базы: тут идет запрос в DNS
+    pub async fn validate(&self) -> Result<(), OrderError> {
+        let url = Url::parse(&self.url).map_err(|e| OrderError::Validation {
+            msg: format!("Invalid url: {e}"),
+            field: "url".to_string(),
+        })?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(OrderError::Validation {
+                msg: "url must be http or https".to_string(),
+                field: "url".to_string(),
+            });
+        }
+        if !config::webhook_allow_private() {
+            check_public_url(&url).await.map_err(|msg| OrderError::Validation {
+                msg,
+                field: "url".to_string(),
+            })?;
+        }
+        if let Some(unknown) = self.event_types.iter().find(|t| !EVENT_TYPES.contains(&t.as_str())) {
+            return Err(OrderError::Validation {
+                msg: format!("Unknown event type: {unknown}"),
+                field: "event_types".to_string(),
+            });
+        }
+        if self.secret.as_ref().is_some_and(|secret| secret.len() < 16) {
+            return Err(OrderError::Validation {
+                msg: "secret must be at least 16 characters".to_string(),
+                field: "secret".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Webhook {
+    // Подписка арендатора, приходят события только по его заказам. new уже проверен через validate
+    pub async fn create(client: &Client, tenant: &Tenant, new: NewWebhook) -> Result<Webhook, OrderError> {
+        let key = webhook_secrets::key()?;
+        let secret = new.secret.unwrap_or_else(|| {
+            rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect()
+        });
+        let sealed = webhook_secrets::seal(&key, &secret)?;
+        let row = db_timeout(
+            "create webhook",
+            client.query_one(
+                "INSERT INTO webhooks (tenant_id, url, event_types, secret) VALUES ($4, $1, $2, $3)
+                RETURNING id, url, event_types, TO_CHAR(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at",
+                &[&new.url, &new.event_types, &sealed, &tenant.as_str()],
+            ),
+        )
+        .await?;
+        let mut webhook = Webhook::from_row(&row);
+        webhook.secret = Some(secret);
+        Ok(webhook)
+    }
+
+    pub async fn list(client: &Client, tenant: &Tenant) -> Result<Vec<Webhook>, OrderError> {
+        let rows = db_timeout(
+            "query webhooks",
+            client.query(
+                "SELECT id, url, event_types, TO_CHAR(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at
+                FROM webhooks WHERE tenant_id = $1 ORDER BY id",
+                &[&tenant.as_str()],
+            ),
+        )
+        .await?;
+        Ok(rows.iter().map(Webhook::from_row).collect())
+    }
+
+    // Удаление подписки вместе с журналом ее доставок
+    pub async fn delete(client: &Client, tenant: &Tenant, id: i64) -> Result<(), OrderError> {
+        let deleted = db_timeout(
+            "delete webhook",
+            client.execute("DELETE FROM webhooks WHERE id = $1 AND tenant_id = $2", &[&id, &tenant.as_str()]),
+        )
+        .await?;
+        if deleted == 0 {
+            return Err(webhook_not_found());
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &Row) -> Self {
+        Webhook {
+            id: row.get("id"),
+            url: row.get("url"),
+            event_types: row.get("event_types"),
+            created_at: row.get("created_at"),
+            secret: None,
+        }
+    }
+}
+
+impl WebhookDelivery {
+    pub async fn list(
+        client: &Client,
+        tenant: &Tenant,
+        webhook_id: i64,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<WebhookDelivery>, OrderError> {
+        let rows = db_timeout(
+            "query webhook deliveries",
+            client.query(
+                "SELECT
+                    id, webhook_id, event_id, event_type, status, attempts,
+                    TO_CHAR(next_attempt_at, 'YYYY-MM-DD HH24:MI:SS') AS next_attempt_at,
+                    last_status_code, last_error,
+                    TO_CHAR(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                    TO_CHAR(delivered_at, 'YYYY-MM-DD HH24:MI:SS') AS delivered_at
+                FROM webhook_deliveries
+                WHERE webhook_id = (SELECT id FROM webhooks WHERE id = $1 AND tenant_id = $4)
+                ORDER BY id DESC
+                LIMIT $2 OFFSET $3",
+                &[&webhook_id, &limit, &offset, &tenant.as_str()],
+            ),
+        )
+        .await?;
+        Ok(rows.iter().map(WebhookDelivery::from_row).collect())
+    }
+
+    // Ручная повторная отправка: доставка снова в очереди, счетчик попыток сбрасывается
+    pub async fn redeliver(client: &Client, tenant: &Tenant, id: i64) -> Result<(), OrderError> {
+        let updated = db_timeout(
+            "redeliver webhook",
+            client.execute(
+                "UPDATE webhook_deliveries
+                SET status = 'pending', attempts = 0, next_attempt_at = now(), delivered_at = NULL
+                WHERE id = $1 AND webhook_id IN (SELECT id FROM webhooks WHERE tenant_id = $2)",
+                &[&id, &tenant.as_str()],
+            ),
+        )
+        .await?;
+        if updated == 0 {
+            return Err(OrderError::NotFound {
+                msg: "Delivery not found".to_string(),
+                field: "delivery".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &Row) -> Self {
+        WebhookDelivery {
+            id: row.get("id"),
+            webhook_id: row.get("webhook_id"),
+            event_id: row.get("event_id"),
+            event_type: row.get("event_type"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            next_attempt_at: row.get("next_attempt_at"),
+            last_status_code: row.get("last_status_code"),
+            last_error: row.get("last_error"),
+            created_at: row.get("created_at"),
+            delivered_at: row.get("delivered_at"),
+        }
+    }
+}
+
+// Адреса куда вебхуки не ходят: localhost, внутренняя сеть, link-local (там же metadata облака
+// 169.254.169.254), CGNAT, сеть для бенчмарков 198.18.0.0/15, multicast и прочие служебные диапазоны.
+// IPv6 с зашитым IPv4 проверяется по этому IPv4
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && (64..128).contains(&second))
+                || (first == 198 && (second & 0xfe) == 18)
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let embedded = embedded_ipv4(ip);
+            if !embedded.is_empty() {
+                return embedded.into_iter().all(|ip| is_public_ip(IpAddr::V4(ip)));
+            }
+            let segments = ip.segments();
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 - unique local, fe80::/10 - link-local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // 64:ff9b:1::/48 - NAT64 внутри своей сети
+                || segments[..3] == [0x64, 0xff9b, 1])
+        }
+    }
+}
+
+// IPv4 зашитые в IPv6: mapped ::ffff:0:0/96, NAT64 64:ff9b::/96, 6to4 2002::/16 и Teredo 2001::/32
+// (в нем адрес сервера и инвертированный адрес клиента). Через такой адрес можно попасть на любой IPv4
+fn embedded_ipv4(ip: Ipv6Addr) -> Vec<Ipv4Addr> {
+    let ipv4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low] | [0x64, 0xff9b, 0, 0, 0, 0, high, low] | [0x2002, high, low, ..] => {
+            vec![ipv4(high, low)]
+        }
+        [0x2001, 0, server_high, server_low, _, _, client_high, client_low] => {
+            vec![ipv4(server_high, server_low), ipv4(!client_high, !client_low)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Хост url должен резолвиться только в публичные адреса
+async fn check_public_url(url: &Url) -> Result<(), String> {
+    let host = url.host_str().ok_or_else(|| "url has no host".to_string())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Failed to resolve {host}: {e}"))?
+        .collect();
+    if addresses.is_empty() || addresses.iter().any(|address| !is_public_ip(address.ip())) {
+        return Err(format!("url must point to a public address, {host} resolves to a private one"));
+    }
+    Ok(())
+}
+
+// DNS для HTTP клиента вебхуков: отбрасывает непубличные адреса при каждом подключении,
+// так хост прошедший проверку при создании не может потом начать указывать во внутреннюю сеть
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| is_public_ip(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public addresses", name.as_str()).into());
+            }
+            let addresses: Addrs = Box::new(addresses.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+// HTTP клиент для доставок. Редиректы не выполняются: ответ 3xx считается неудачной попыткой,
+// иначе публичный адрес мог бы перенаправить запрос во внутреннюю сеть
+fn http_client(allow_private: bool) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).redirect(redirect::Policy::none());
+    if allow_private {
+        return builder.build();
+    }
+    builder.dns_resolver(Arc::new(PublicResolver)).build()
+}
+
+pub fn webhook_not_found() -> OrderError {
+    OrderError::NotFound {
+        msg: "Webhook not found".to_string(),
+        field: "webhook".to_string(),
+    }
+}
+
+// Подпись тела: HMAC-SHA256 от "{timestamp}.{body}" в hex. Время входит в подпись,
+// чтобы получатель мог отбрасывать старые запросы и перехваченное тело нельзя было переотправить позже
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    format!("sha256={}", hex::encode(signer(secret, timestamp, body).finalize().into_bytes()))
+}
+
+// Проверка подписи в постоянное время, для приемника вебхуков
+pub fn verify(secret: &str, timestamp: i64, body: &str, signature: &str) -> bool {
+    let Some(signature) = signature.strip_prefix("sha256=").and_then(|hex| hex::decode(hex).ok()) else {
+        return false;
+    };
+    signer(secret, timestamp, body).verify_slice(&signature).is_ok()
+}
+
+fn signer(secret: &str, timestamp: i64, body: &str) -> Hmac<Sha256> {
+    // HMAC принимает ключ любой длины, ошибки тут не бывает
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body.as_bytes());
+    mac
+}
+
+// Журнал доставок пополняет relay через WebhookSink, а фоновая задача отправляет из журнала
+// то что пора отправить, на своем соединении с базой
+pub fn spawn(queue: WebhookQueue) {
+    tokio::spawn(async move { deliver(queue).await });
+}
+
+// Получатель событий для outbox relay: доставка на каждую подписку которая слушает этот тип событий.
+// Повтор того же события ничего не добавит, в журнале уникальная пара (webhook_id, event_id)
+pub struct WebhookSink {
+    db: Db,
+    queue: WebhookQueue,
+}
+
+impl WebhookSink {
+    pub fn new(db: Db, queue: WebhookQueue) -> Self {
+        WebhookSink { db, queue }
+    }
+}
+
+#[async_trait]
+impl EventSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn publish(&self, event: &OrderEvent) -> Result<(), OrderError> {
+        let event_id = i64::try_from(event.id).unwrap_or(i64::MAX);
+        let event_type = event.kind.as_str();
+        let payload = event.to_json().to_string();
+        let client = self.db.lock().await;
+        let queued = db_timeout(
+            "queue webhook deliveries",
+            client.execute(
+                "INSERT INTO webhook_deliveries (webhook_id, event_id, event_type, payload)
+                SELECT id, $1, $2::varchar, $3::text::jsonb
+                FROM webhooks
+                WHERE tenant_id = $4 AND (cardinality(event_types) = 0 OR $2::varchar = ANY(event_types))
+                ON CONFLICT (webhook_id, event_id) DO NOTHING",
+                &[&event_id, &event_type, &payload, &event.tenant.as_str()],
+            ),
+        )
+        .await?;
+        if queued > 0 {
+            debug!("Queued {queued} webhook deliveries for event {}", event.id);
+            self.queue.wake();
+        }
+        Ok(())
+    }
+}
+
+async fn deliver(queue: WebhookQueue) {
+    let allow_private = config::webhook_allow_private();
+    let http = match http_client(allow_private) {
+        Ok(http) => http,
+        Err(e) => {
+            error!("Failed to build webhook HTTP client: {e}");
+            return;
+        }
+    };
+    let mut client = connect_retry("Webhook worker").await;
+    if let Err(e) = seal_plaintext_secrets(&client).await {
+        error!("Failed to encrypt webhook secrets: {e}");
+    }
+    let mut poll = interval(POLL_INTERVAL);
+    info!("Webhook worker started");
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {}
+            () = queue.wakeup.notified() => {}
+        }
+        if client.is_closed() {
+            client = connect_retry("Webhook worker").await;
+        }
+        // забираем пока есть что отправлять, чтобы очередь не ждала следующего опроса
+        loop {
+            match deliver_due(&client, &http, allow_private).await {
+                Ok(sent) if sent < DELIVERY_BATCH => break,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Webhook delivery failed: {e}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Секреты записанные до шифрования шифруются один раз при запуске воркера
+async fn seal_plaintext_secrets(client: &Client) -> Result<(), OrderError> {
+    let Ok(key) = webhook_secrets::key() else {
+        return Ok(());
+    };
+    let rows = db_timeout(
+        "query plaintext webhook secrets",
+        client.query("SELECT id, secret FROM webhooks WHERE secret NOT LIKE 'v1:%'", &[]),
+    )
+    .await?;
+    for row in &rows {
+        let id: i64 = row.get("id");
+        let sealed = webhook_secrets::seal(&key, row.get("secret"))?;
+        db_timeout(
+            "encrypt webhook secret",
+            client.execute(
+                "UPDATE webhooks SET secret = $2 WHERE id = $1 AND secret NOT LIKE 'v1:%'",
+                &[&id, &sealed],
+            ),
+        )
+        .await?;
+    }
+    if !rows.is_empty() {
+        info!("Encrypted {} webhook secrets stored in plaintext", rows.len());
+    }
+    Ok(())
+}
+
+struct DueDelivery {
+    id: i64,
+    webhook_id: i64,
+    event_type: String,
+    payload: String,
+    attempts: i32,
+    url: String,
+    // как в базе, расшифровывается перед отправкой
+    secret: String,
+}
+
+// Одна пачка доставок: строки "занимаются" сдвигом next_attempt_at (SKIP LOCKED - чтобы
+// несколько экземпляров сервиса не взяли одно и то же), отправляются параллельно,
+// потом результат каждой записывается в журнал. Если процесс упадет посреди отправки,
+// доставка повторится после LEASE_SECS - получатель должен быть готов к повторам (по x-webhook-delivery)
+async fn deliver_due(client: &Client, http: &reqwest::Client, allow_private: bool) -> Result<i64, OrderError> {
+    let rows = db_timeout(
+        "claim webhook deliveries",
+        client.query(
+            "UPDATE webhook_deliveries d
+            SET next_attempt_at = now() + make_interval(secs => $2)
+            FROM webhooks w
+            WHERE w.id = d.webhook_id
+                AND d.id IN (
+                    SELECT id FROM webhook_deliveries
+                    WHERE status = 'pending' AND next_attempt_at <= now()
+                    ORDER BY next_attempt_at
+                    LIMIT $1
+                    FOR UPDATE SKIP LOCKED
+                )
+            RETURNING d.id, d.webhook_id, d.event_type, d.payload::text AS payload, d.attempts, w.url, w.secret",
+            &[&DELIVERY_BATCH, &LEASE_SECS],
+        ),
+    )
+    .await?;
+    let claimed = i64::try_from(rows.len()).unwrap_or(i64::MAX);
+
+    let mut sends = JoinSet::new();
+    for row in &rows {
+        let delivery = DueDelivery {
+            id: row.get("id"),
+            webhook_id: row.get("webhook_id"),
+            event_type: row.get("event_type"),
+            payload: row.get("payload"),
+            attempts: row.get("attempts"),
+            url: row.get("url"),
+            secret: row.get("secret"),
+        };
+        let http = http.clone();
+        sends.spawn(async move {
+            let result = send(&http, &delivery, allow_private).await;
+            (delivery, result)
+        });
+    }
+
+    while let Some(joined) = sends.join_next().await {
+        let Ok((delivery, result)) = joined else {
+            continue;
+        };
+        record_attempt(client, &delivery, result).await?;
+    }
+    Ok(claimed)
+}
+
+// Ok(статус) - получатель ответил 2xx, Err((статус если был, причина)) - надо повторить
+async fn send(http: &reqwest::Client, delivery: &DueDelivery, allow_private: bool) -> Result<i32, (Option<i32>, String)> {
+    // адрес цифрами в url мимо DNS, поэтому его проверяю тут
+    let url = Url::parse(&delivery.url).map_err(|e| (None, format!("Invalid url: {e}")))?;
+    let literal_ip = url
+        .host_str()
+        .and_then(|host| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok());
+    if !allow_private && literal_ip.is_some_and(|ip| !is_public_ip(ip)) {
+        return Err((None, "url points to a private address".to_string()));
+    }
+    let secret = webhook_secrets::reveal(&delivery.secret).map_err(|e| (None, e.to_string()))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| i64::try_from(elapsed.as_secs()).unwrap_or_default())
+        .unwrap_or_default();
+    let response = http
+        .post(&delivery.url)
+        .header("content-type", "application/json")
+        .header("x-webhook-id", delivery.webhook_id.to_string())
+        .header("x-webhook-delivery", delivery.id.to_string())
+        .header("x-webhook-event", &delivery.event_type)
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign(&secret, timestamp, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+    let status = i32::from(response.status().as_u16());
+    if response.status().is_success() {
+        Ok(status)
+    } else {
+        Err((Some(status), format!("Unexpected status {status}")))
+    }
+}
+
+async fn record_attempt(
+    client: &Client,
+    delivery: &DueDelivery,
+    result: Result<i32, (Option<i32>, String)>,
+) -> Result<(), OrderError> {
+    let attempts = delivery.attempts + 1;
+    match result {
+        Ok(status) => {
+            debug!("Webhook delivery {} sent to {}", delivery.id, delivery.url);
+            db_timeout(
+                "record webhook delivery",
+                client.execute(
+                    "UPDATE webhook_deliveries
+                    SET status = 'delivered', attempts = $2, last_status_code = $3, last_error = NULL, delivered_at = now()
+                    WHERE id = $1",
+                    &[&delivery.id, &attempts, &status],
+                ),
+            )
+            .await?;
+        }
+        Err((status, reason)) => {
+            let failed = attempts >= MAX_ATTEMPTS;
+            let backoff = backoff_secs(attempts);
+            warn!(
+                "Webhook delivery {} to {} failed (attempt {attempts}): {reason}{}",
+                delivery.id,
+                delivery.url,
+                if failed { ", giving up" } else { "" }
+            );
+            db_timeout(
+                "record webhook delivery",
+                client.execute(
+                    "UPDATE webhook_deliveries
+                    SET status = CASE WHEN $5 THEN 'failed' ELSE 'pending' END,
+                        attempts = $2, last_status_code = $3, last_error = $4,
+                        next_attempt_at = now() + make_interval(secs => $6)
+                    WHERE id = $1",
+                    &[&delivery.id, &attempts, &status, &reason, &failed, &backoff],
+                ),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn backoff_secs(attempts: i32) -> f64 {
+    let exponent = u32::try_from(attempts - 1).unwrap_or_default().min(16);
+    (BASE_BACKOFF_SECS * 2_i64.pow(exponent)).min(MAX_BACKOFF_SECS) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook_receiver;
+    use tokio::net::TcpListener;
+
+    // приемник из webhook-receiver на случайном порту, первые fail_first запросов получают 500
+    async fn receiver(secret: &str, fail_first: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = webhook_receiver::router(Some(secret), fail_first);
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        format!("http://{address}/hook")
+    }
+
+    fn delivery(url: &str, secret: &str) -> DueDelivery {
+        DueDelivery {
+            id: 1,
+            webhook_id: 1,
+            event_type: EVENT_TYPES[0].to_string(),
+            payload: r#"{"order_uid":"test"}"#.to_string(),
+            attempts: 0,
+            url: url.to_string(),
+            secret: secret.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn delivery_is_signed_and_retried_until_receiver_accepts() {
+        let secret = "receiver-secret";
+        let url = receiver(secret, 2).await;
+        let http = http_client(true).unwrap();
+        let delivery = delivery(&url, secret);
+
+        assert_eq!(send(&http, &delivery, true).await.unwrap_err().0, Some(500));
+        assert_eq!(send(&http, &delivery, true).await.unwrap_err().0, Some(500));
+        assert_eq!(send(&http, &delivery, true).await, Ok(200));
+    }
+
+    #[tokio::test]
+    async fn receiver_rejects_wrong_signature() {
+        let url = receiver("receiver-secret", 0).await;
+        let http = http_client(true).unwrap();
+        let result = send(&http, &delivery(&url, "other-secret"), true).await;
+        assert_eq!(result.unwrap_err().0, Some(401));
+    }
+
+    #[tokio::test]
+    async fn private_targets_are_not_sent_to() {
+        let url = receiver("receiver-secret", 0).await;
+        let http = http_client(false).unwrap();
+        assert_eq!(send(&http, &delivery(&url, "receiver-secret"), false).await.unwrap_err().0, None);
+        // имя хоста резолвится в loopback, такой адрес отбрасывает PublicResolver
+        let named = url.replace("127.0.0.1", "localhost");
+        assert_eq!(send(&http, &delivery(&named, "receiver-secret"), false).await.unwrap_err().0, None);
+    }
+
+    #[tokio::test]
+    async fn validate_rejects_private_hosts() {
+        for url in ["http://127.0.0.1/hook", "http://localhost:8080/hook", "http://[::1]/hook", "http://10.1.2.3/hook"] {
+            let new = NewWebhook { url: url.to_string(), event_types: Vec::new(), secret: None };
+            assert!(new.validate().await.is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        let backoffs: Vec<f64> = (1..=MAX_ATTEMPTS).map(backoff_secs).collect();
+        assert_eq!(backoffs, [10.0, 20.0, 40.0, 80.0, 160.0, 320.0, 640.0, 1280.0]);
+        assert!((backoff_secs(20) - 3600.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn private_and_special_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1", "10.0.0.1", "172.16.5.4", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "255.255.255.255", "224.0.0.1", "::1", "::", "fc00::1", "fd12::1", "fe80::1", "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1", "198.18.0.1", "198.19.255.255",
+            // NAT64 на 127.0.0.1 и 10.0.0.1, NAT64 внутри своей сети
+            "64:ff9b::7f00:1", "64:ff9b::a00:1", "64:ff9b:1::808:808",
+            // 6to4 на 127.0.0.1 и 192.168.1.1
+            "2002:7f00:1::1", "2002:c0a8:101::1",
+            // Teredo с клиентом 127.0.0.1 и с сервером 10.0.0.1
+            "2001:0:4136:e378:8000:63bf:80ff:fffe", "2001:0:a00:1:8000:63bf:f7f7:f7f7",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "8.8.8.8", "1.1.1.1", "198.20.0.1", "2606:4700:4700::1111", "::ffff:8.8.8.8", "64:ff9b::808:808",
+            "2002:808:808::1", "2001:0:4136:e378:8000:63bf:f7f7:f7f7",
+        ] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,280 @@
+// Интеграционные тесты Kafka получателя outbox: поднимается одноузловой брокер в контейнере
+// (или используется KAFKA_TEST_BROKERS), запускается сам сервис с OUTBOX_SINKS=kafka
+// и проверяется что записи доходят до топика.
+// Нужны Docker и база из DATABASE_URL (make up), запуск: make test-kafka
+#![cfg(feature = "kafka-tests")]
+
+use apache_avro::{from_avro_datum, from_value, Schema};
+use rdkafka::{
+    admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
+    client::DefaultClientContext,
+    config::ClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    message::{BorrowedMessage, Headers},
+    Message,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    env,
+    net::TcpListener,
+    process::{Child, Command},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use testcontainers_modules::{
+    kafka::apache,
+    testcontainers::{runners::AsyncRunner, ContainerAsync},
+};
+use tokio::time::{sleep, timeout, Instant};
+
+const PARTITIONS: i32 = 3;
+const WAIT: Duration = Duration::from_secs(30);
+
+// Брокер живет пока жив контейнер, поэтому он возвращается вместе с адресом
+async fn broker() -> (String, Option<ContainerAsync<apache::Kafka>>) {
+    if let Ok(brokers) = env::var("KAFKA_TEST_BROKERS") {
+        return (brokers, None);
+    }
+    let node = apache::Kafka::default().start().await.expect("failed to start kafka container");
+    let port = node.get_host_port_ipv4(apache::KAFKA_PORT).await.expect("kafka port");
+    (format!("127.0.0.1:{port}"), Some(node))
+}
+
+// Сервис на свободных портах, убивается при выходе из теста
+struct Service {
+    child: Child,
+    address: String,
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+async fn start_service(brokers: &str, topic: &str, format: &str) -> Service {
+    let address = format!("127.0.0.1:{}", free_port());
+    let child = Command::new(env!("CARGO_BIN_EXE_axum_study_project-1"))
+        .env("SERVER_ADDRESS", &address)
+        .env("GRPC_ADDRESS", format!("127.0.0.1:{}", free_port()))
+        .env("OUTBOX_SINKS", "kafka")
+        .env("API_AUTH", "off")
+        .env("KAFKA_BROKERS", brokers)
+        .env("KAFKA_TOPIC", topic)
+        .env("KAFKA_FORMAT", format)
+        .spawn()
+        .expect("failed to start the service");
+    let service = Service { child, address };
+
+    let http = reqwest::Client::new();
+    let deadline = Instant::now() + WAIT;
+    while http.get(format!("http://{}/orders?limit=1", service.address)).send().await.is_err() {
+        assert!(Instant::now() < deadline, "service did not start");
+        sleep(Duration::from_millis(200)).await;
+    }
+    service
+}
+
+async fn create_topic(brokers: &str, topic: &str) {
+    let admin: AdminClient<DefaultClientContext> =
+        ClientConfig::new().set("bootstrap.servers", brokers).create().unwrap();
+    let results = admin
+        .create_topics(
+            &[NewTopic::new(topic, PARTITIONS, TopicReplication::Fixed(1))],
+            &AdminOptions::new(),
+        )
+        .await
+        .unwrap();
+    for result in results {
+        result.expect("failed to create topic");
+    }
+}
+
+// Уникальные order_uid, transaction и chrt_id чтобы тесты не мешали друг другу и прошлым запускам
+fn order(shardkey: &str) -> Value {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let order_uid = format!("kafka-test-{nanos}");
+    json!({
+        "order_uid": order_uid,
+        "track_number": "WBILMTESTTRACK",
+        "entry": "WBIL",
+        "delivery": {
+            "name": "Test Testov", "phone": "+9720000000", "zip": "2639809", "city": "Kiryat Mozkin",
+            "address": "Ploshad Mira 15", "region": "Kraiot", "email": "test@gmail.com"
+        },
+        "payment": {
+            "transaction": order_uid, "request_id": "", "currency": "USD", "provider": "wbpay",
+            "amount": 1817, "payment_dt": 1_637_907_727, "bank": "alpha", "delivery_cost": 1500,
+            "goods_total": 317, "custom_fee": 0
+        },
+        "items": [{
+            "chrt_id": i64::try_from(nanos % 1_000_000_000_000).unwrap(), "track_number": "WBILMTESTTRACK",
+            "price": 453, "rid": "ab4219087a764ae0btest4", "name": "Mascaras", "sale": 30, "size": "0",
+            "total_price": 317, "nm_id": 2_389_212, "brand": "Vivienne Sabo", "status": 202
+        }],
+        "delivery_service": "meest",
+        "customer_id": "kafka-test",
+        "shardkey": shardkey,
+        "sm_id": 99,
+        "date_created": "2021-11-26T06:22:19Z",
+        "oof_shard": "1"
+    })
+}
+
+// Создает заказ и меняет статус товара: в топике должны появиться OrderCreated и OrderUpdated
+async fn create_and_update(service: &Service, order: &Value) {
+    let http = reqwest::Client::new();
+    let response = http
+        .post(format!("http://{}/order", service.address))
+        .header("content-type", "application/json")
+        .body(order.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201, "{}", response.text().await.unwrap());
+
+    let response = http
+        .put(format!(
+            "http://{}/order/{}/items/{}/status",
+            service.address, order["order_uid"].as_str().unwrap(), order["items"][0]["chrt_id"]
+        ))
+        .header("content-type", "application/json")
+        .body(json!({"status": 205}).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200, "{}", response.text().await.unwrap());
+}
+
+struct Received {
+    partition: i32,
+    event_type: String,
+    content_type: String,
+    payload: Vec<u8>,
+}
+
+fn header(message: &BorrowedMessage<'_>, key: &str) -> String {
+    message
+        .headers()
+        .and_then(|headers| headers.iter().find(|header| header.key == key))
+        .and_then(|header| header.value)
+        .map(|value| String::from_utf8_lossy(value).to_string())
+        .unwrap_or_default()
+}
+
+// Читает топик с начала и собирает записи заказа
+async fn receive(brokers: &str, topic: &str, order_uid: &str, count: usize) -> Vec<Received> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", format!("{topic}-test"))
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .unwrap();
+    consumer.subscribe(&[topic]).unwrap();
+
+    let mut received = Vec::new();
+    timeout(WAIT, async {
+        while received.len() < count {
+            let message = consumer.recv().await.unwrap();
+            if message.key() != Some(order_uid.as_bytes()) {
+                continue;
+            }
+            received.push(Received {
+                partition: message.partition(),
+                event_type: header(&message, "event-type"),
+                content_type: header(&message, "content-type"),
+                payload: message.payload().unwrap_or_default().to_vec(),
+            });
+        }
+    })
+    .await
+    .expect("records did not arrive in time");
+    received
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    event_id: i64,
+    #[serde(rename = "type")]
+    record_type: String,
+    change: String,
+    chrt_id: Option<i64>,
+    status: Option<i32>,
+    order: RecordOrder,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordOrder {
+    order_uid: String,
+    shardkey: String,
+    items: Vec<Value>,
+}
+
+fn check_records(records: &[Record], received: &[Received], order: &Value) {
+    let order_uid = order["order_uid"].as_str().unwrap();
+    let (created, updated) = (&records[0], &records[1]);
+    assert_eq!(created.record_type, "OrderCreated");
+    assert_eq!(created.change, "created");
+    assert_eq!(created.order.order_uid, order_uid);
+    assert_eq!(created.order.shardkey, "7");
+    assert_eq!(created.order.items.len(), 1);
+    assert_eq!(updated.record_type, "OrderUpdated");
+    assert_eq!(updated.change, "item_status_changed");
+    assert_eq!(updated.chrt_id, order["items"][0]["chrt_id"].as_i64());
+    assert_eq!(updated.status, Some(205));
+    assert!(updated.event_id > created.event_id);
+
+    // shardkey 7 при 3 партициях - партиция 1, у всех событий заказа одна и та же
+    assert!(received.iter().all(|record| record.partition == 7 % PARTITIONS));
+    assert_eq!(received[0].event_type, "OrderCreated");
+    assert_eq!(received[1].event_type, "OrderUpdated");
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and DATABASE_URL, run with make test-kafka"]
+async fn publishes_json_records() {
+    let (brokers, _node) = broker().await;
+    let topic = format!("orders-json-{}", free_port());
+    create_topic(&brokers, &topic).await;
+    let service = start_service(&brokers, &topic, "json").await;
+
+    let order = order("7");
+    create_and_update(&service, &order).await;
+    let received = receive(&brokers, &topic, order["order_uid"].as_str().unwrap(), 2).await;
+
+    assert!(received.iter().all(|record| record.content_type == "application/json"));
+    let records: Vec<Record> = received
+        .iter()
+        .map(|record| serde_json::from_slice(&record.payload).unwrap())
+        .collect();
+    check_records(&records, &received, &order);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and DATABASE_URL, run with make test-kafka"]
+async fn publishes_avro_records() {
+    let (brokers, _node) = broker().await;
+    let topic = format!("orders-avro-{}", free_port());
+    create_topic(&brokers, &topic).await;
+    let service = start_service(&brokers, &topic, "avro").await;
+
+    let order = order("7");
+    create_and_update(&service, &order).await;
+    let received = receive(&brokers, &topic, order["order_uid"].as_str().unwrap(), 2).await;
+
+    assert!(received.iter().all(|record| record.content_type == "avro/binary"));
+    let schema = Schema::parse_str(include_str!("../schemas/order_event.avsc")).unwrap();
+    let records: Vec<Record> = received
+        .iter()
+        .map(|record| {
+            let value = from_avro_datum(&schema, &mut record.payload.as_slice(), None).unwrap();
+            from_value(&value).unwrap()
+        })
+        .collect();
+    check_records(&records, &received, &order);
+}
@@ -0,0 +1,163 @@
+// Экспорт спанов по OTLP: сервис с OTEL_TRACES_EXPORTER=otlp отправляет спаны в локальный фейковый коллектор.
+// Запрос с traceparent дает спан HTTP запроса в трейсе клиента, в нем спан хендлера, в нем спан запроса к базе.
+// Нужна база из DATABASE_URL (make up), запуск: make test-db
+
+use opentelemetry_proto::tonic::{
+    collector::trace::v1::{
+        trace_service_server::{TraceService, TraceServiceServer},
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    },
+    common::v1::any_value::Value,
+    trace::v1::{span::SpanKind, Span},
+};
+use std::{
+    net::TcpListener,
+    process::{Child, Command},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout, Instant},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+const WAIT: Duration = Duration::from_secs(30);
+const SERVICE_NAME: &str = "otlp-export-test";
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+// Коллектор который просто отдает в канал все что ему прислали
+struct Collector(mpsc::UnboundedSender<ExportTraceServiceRequest>);
+
+#[tonic::async_trait]
+impl TraceService for Collector {
+    async fn export(
+        &self,
+        request: Request<ExportTraceServiceRequest>,
+    ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+        let _ = self.0.send(request.into_inner());
+        Ok(Response::new(ExportTraceServiceResponse { partial_success: None }))
+    }
+}
+
+async fn start_collector() -> (String, mpsc::UnboundedReceiver<ExportTraceServiceRequest>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(
+        Server::builder()
+            .add_service(TraceServiceServer::new(Collector(tx)))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    (endpoint, rx)
+}
+
+// Сервис на свободных портах, убивается при выходе из теста
+struct Service {
+    child: Child,
+    address: String,
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+async fn start_service(collector: &str) -> Service {
+    let address = format!("127.0.0.1:{}", free_port());
+    let child = Command::new(env!("CARGO_BIN_EXE_axum_study_project-1"))
+        .env("SERVER_ADDRESS", &address)
+        .env("GRPC_ADDRESS", format!("127.0.0.1:{}", free_port()))
+        .env("API_AUTH", "off")
+        .env("OTEL_TRACES_EXPORTER", "otlp")
+        .env("OTEL_EXPORTER_OTLP_ENDPOINT", collector)
+        .env("OTEL_SERVICE_NAME", SERVICE_NAME)
+        // пачка уходит в коллектор раз в 200мс, а не раз в 5с
+        .env("OTEL_BSP_SCHEDULE_DELAY", "200")
+        .spawn()
+        .expect("failed to start the service");
+    let service = Service { child, address };
+
+    let http = reqwest::Client::new();
+    let deadline = Instant::now() + WAIT;
+    while http.get(format!("http://{}/metrics", service.address)).send().await.is_err() {
+        assert!(Instant::now() < deadline, "service did not start");
+        sleep(Duration::from_millis(200)).await;
+    }
+    service
+}
+
+fn string_attribute<'a>(span: &'a Span, key: &str) -> Option<&'a str> {
+    span.attributes.iter().find(|attribute| attribute.key == key).and_then(|attribute| {
+        match attribute.value.as_ref()?.value.as_ref()? {
+            Value::StringValue(value) => Some(value.as_str()),
+            _ => None,
+        }
+    })
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL, run with make test-db"]
+async fn spans_reach_local_collector() {
+    let (endpoint, mut exports) = start_collector().await;
+    let service = start_service(&endpoint).await;
+
+    // ордера нет ни в кэше ни в базе, значит хендлер сходит в базу
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/order/otlp-test-missing", service.address))
+        .header("traceparent", format!("00-{TRACE_ID}-{PARENT_SPAN_ID}-01"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    // спаны этого трейса, пока не придут все три уровня
+    let mut spans: Vec<Span> = Vec::new();
+    let received = timeout(WAIT, async {
+        while let Some(export) = exports.recv().await {
+            for resource_spans in export.resource_spans {
+                let resource = resource_spans.resource.unwrap_or_default();
+                assert!(
+                    resource.attributes.iter().any(|attribute| attribute.key == "service.name"
+                        && attribute.value.as_ref().and_then(|value| value.value.as_ref())
+                            == Some(&Value::StringValue(SERVICE_NAME.to_string()))),
+                    "service.name is not {SERVICE_NAME}"
+                );
+                spans.extend(
+                    resource_spans
+                        .scope_spans
+                        .into_iter()
+                        .flat_map(|scope| scope.spans)
+                        .filter(|span| hex::encode(&span.trace_id) == TRACE_ID),
+                );
+            }
+            if spans.iter().any(|span| string_attribute(span, "db.system").is_some())
+                && spans.iter().any(|span| span.name == "get_order_by_id")
+                && spans.iter().any(|span| span.name == "GET /order/:order_uid")
+            {
+                return;
+            }
+        }
+    })
+    .await;
+    assert!(received.is_ok(), "spans did not reach the collector, got: {:?}", spans.iter().map(|span| &span.name).collect::<Vec<_>>());
+
+    let request = spans.iter().find(|span| span.name == "GET /order/:order_uid").unwrap();
+    assert_eq!(hex::encode(&request.parent_span_id), PARENT_SPAN_ID, "traceparent is not the parent");
+    assert_eq!(request.kind, SpanKind::Server as i32);
+    assert_eq!(string_attribute(request, "http.route"), Some("/order/:order_uid"));
+    let handler = spans.iter().find(|span| span.name == "get_order_by_id").unwrap();
+    assert_eq!(handler.parent_span_id, request.span_id);
+    assert_eq!(string_attribute(handler, "order_uid"), Some("otlp-test-missing"));
+    let query = spans.iter().find(|span| string_attribute(span, "db.system").is_some()).unwrap();
+    assert_eq!(query.parent_span_id, handler.span_id);
+    assert_eq!(query.kind, SpanKind::Client as i32);
+    assert_eq!(string_attribute(query, "db.system"), Some("postgresql"));
+}
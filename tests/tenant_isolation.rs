@@ -0,0 +1,275 @@
+// Изоляция арендаторов: ключ арендатора B не видит ордера арендатора A ни через HTTP, ни через gRPC, ни через /ws,
+// и не может выбрать чужого арендатора через X-Tenant-ID / x-tenant-id / ?tenant=. Ключи ордеров у арендаторов свои.
+// Запускается сам сервис с API_AUTH по умолчанию (включен), ключи создаются командой keys.
+// Нужна база из DATABASE_URL (make up), запуск: make test-db
+
+use serde_json::{json, Value};
+use std::{
+    net::TcpListener,
+    process::{Child, Command},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+use tonic::{
+    codec::ProstCodec,
+    codegen::http::uri::PathAndQuery,
+    transport::Channel,
+    Code,
+};
+
+#[allow(dead_code, clippy::all, clippy::pedantic)]
+mod proto {
+    tonic::include_proto!("orders");
+}
+
+const WAIT: Duration = Duration::from_secs(30);
+
+// Сервис на свободных портах, убивается при выходе из теста
+struct Service {
+    child: Child,
+    address: String,
+    grpc_address: String,
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn nanos() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+}
+
+async fn start_service() -> Service {
+    let address = format!("127.0.0.1:{}", free_port());
+    let grpc_address = format!("127.0.0.1:{}", free_port());
+    let child = Command::new(env!("CARGO_BIN_EXE_axum_study_project-1"))
+        .env("SERVER_ADDRESS", &address)
+        .env("GRPC_ADDRESS", &grpc_address)
+        .env_remove("API_AUTH")
+        .spawn()
+        .expect("failed to start the service");
+    let service = Service { child, address, grpc_address };
+
+    let http = reqwest::Client::new();
+    let deadline = Instant::now() + WAIT;
+    while http.get(format!("http://{}/metrics", service.address)).send().await.is_err() {
+        assert!(Instant::now() < deadline, "service did not start");
+        sleep(Duration::from_millis(200)).await;
+    }
+    service
+}
+
+// Ключ на чтение и запись ордеров, привязанный к арендатору
+fn create_key(tenant: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_axum_study_project-1"))
+        .args(["keys", "create", "--name", tenant, "--scopes", "orders:read,orders:write", "--tenant", tenant])
+        .output()
+        .expect("failed to run keys create");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().lines().last().unwrap().trim().to_string()
+}
+
+fn order(track_number: &str) -> Value {
+    let nanos = nanos();
+    let order_uid = format!("tenant-test-{nanos}");
+    json!({
+        "order_uid": order_uid,
+        "track_number": track_number,
+        "entry": "WBIL",
+        "delivery": {
+            "name": "Test Testov", "phone": "+9720000000", "zip": "2639809", "city": "Kiryat Mozkin",
+            "address": "Ploshad Mira 15", "region": "Kraiot", "email": "test@gmail.com"
+        },
+        "payment": {
+            "transaction": order_uid, "request_id": "", "currency": "USD", "provider": "wbpay",
+            "amount": 1817, "payment_dt": 1_637_907_727, "bank": "alpha", "delivery_cost": 1500,
+            "goods_total": 317, "custom_fee": 0
+        },
+        "items": [{
+            "chrt_id": i64::try_from(nanos % 1_000_000_000_000).unwrap(), "track_number": track_number,
+            "price": 453, "rid": "ab4219087a764ae0btest4", "name": "Mascaras", "sale": 30, "size": "0",
+            "total_price": 317, "nm_id": 2_389_212, "brand": "Vivienne Sabo", "status": 202
+        }],
+        "delivery_service": "meest",
+        "customer_id": "tenant-test",
+        "shardkey": "7",
+        "sm_id": 99,
+        "date_created": "2021-11-26T06:22:19Z",
+        "oof_shard": "1"
+    })
+}
+
+async fn post_order(service: &Service, key: &str, order: &Value) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/order", service.address))
+        .header("x-api-key", key)
+        .header("content-type", "application/json")
+        .body(order.to_string())
+        .send()
+        .await
+        .unwrap();
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+async fn create_order(service: &Service, key: &str, order: &Value) {
+    let (status, body) = post_order(service, key, order).await;
+    assert_eq!(status, 201, "{body}");
+}
+
+async fn get(service: &Service, path: &str, key: &str, tenant: Option<&str>) -> (u16, Value) {
+    let mut request = reqwest::Client::new()
+        .get(format!("http://{}{path}", service.address))
+        .header("x-api-key", key);
+    if let Some(tenant) = tenant {
+        request = request.header("x-tenant-id", tenant);
+    }
+    let response = request.send().await.unwrap();
+    let status = response.status().as_u16();
+    let body = response.bytes().await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn grpc_get_order(
+    service: &Service,
+    key: &str,
+    tenant: Option<&str>,
+    order_uid: &str,
+) -> Result<proto::Order, tonic::Status> {
+    let channel = Channel::from_shared(format!("http://{}", service.grpc_address))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await.unwrap();
+    let mut request = tonic::Request::new(proto::GetOrderRequest { order_uid: order_uid.to_string() });
+    request.metadata_mut().insert("x-api-key", key.parse().unwrap());
+    if let Some(tenant) = tenant {
+        request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+    }
+    let codec = ProstCodec::<proto::GetOrderRequest, proto::Order>::default();
+    grpc.unary(request, PathAndQuery::from_static("/orders.OrderService/GetOrder"), codec)
+        .await
+        .map(tonic::Response::into_inner)
+}
+
+// Апгрейд до WebSocket руками: статус ответа и соединение, если апгрейд прошел
+async fn ws_connect(service: &Service, key: &str, query: &str) -> (u16, TcpStream) {
+    let mut stream = TcpStream::connect(&service.address).await.unwrap();
+    let request = format!(
+        "GET /ws{query} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nX-API-Key: {key}\r\n\r\n",
+        service.address
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, stream)
+}
+
+// Текстовый кадр от клиента, маска обязательна
+async fn ws_send(stream: &mut TcpStream, text: &str) {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    if payload.len() < 126 {
+        frame.push(0x80 | u8::try_from(payload.len()).unwrap());
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend(u16::try_from(payload.len()).unwrap().to_be_bytes());
+    }
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    frame.extend(mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    stream.write_all(&frame).await.unwrap();
+}
+
+// Следующее текстовое сообщение от сервера, ping и прочие кадры пропускаются
+async fn ws_receive(stream: &mut TcpStream) -> Value {
+    loop {
+        let opcode = stream.read_u8().await.unwrap() & 0x0f;
+        let len = match stream.read_u8().await.unwrap() & 0x7f {
+            126 => u64::from(stream.read_u16().await.unwrap()),
+            127 => stream.read_u64().await.unwrap(),
+            len => u64::from(len),
+        };
+        let mut payload = vec![0; usize::try_from(len).unwrap()];
+        stream.read_exact(&mut payload).await.unwrap();
+        if opcode == 1 {
+            return serde_json::from_slice(&payload).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL, run with make test-db"]
+async fn tenants_do_not_see_each_other() {
+    let service = start_service().await;
+    let (tenant_a, tenant_b) = (format!("iso-a-{}", nanos()), format!("iso-b-{}", nanos()));
+    let (key_a, key_b) = (create_key(&tenant_a), create_key(&tenant_b));
+    let track_number = format!("ISO{}", nanos() % 1_000_000_000_000);
+
+    // /ws: B подписывается на трек номер раньше чем A создаст ордер с ним
+    let (status, _) = ws_connect(&service, &key_b, &format!("?tenant={tenant_a}")).await;
+    assert_eq!(status, 403, "foreign tenant in ?tenant=");
+    let (status, mut ws_b) = ws_connect(&service, &key_b, "").await;
+    assert_eq!(status, 101);
+    ws_send(&mut ws_b, &json!({"type": "subscribe", "track_numbers": [track_number]}).to_string()).await;
+    assert_eq!(ws_receive(&mut ws_b).await["type"], "subscribed");
+
+    let order_a = order(&track_number);
+    let order_uid = order_a["order_uid"].as_str().unwrap();
+    create_order(&service, &key_a, &order_a).await;
+    // свой ордер с тем же трек номером B получает, а ордер A до него нет
+    let order_b = order(&track_number);
+    create_order(&service, &key_b, &order_b).await;
+    let event = timeout(WAIT, ws_receive(&mut ws_b)).await.expect("no event for tenant B");
+    assert_eq!(event["type"], "event");
+    assert_eq!(event["order"]["order_uid"], order_b["order_uid"]);
+
+    // HTTP
+    let (status, body) = get(&service, &format!("/order/{order_uid}"), &key_a, None).await;
+    assert_eq!(status, 200, "{body}");
+    assert_eq!(body["order_uid"], order_uid);
+    let (status, _) = get(&service, &format!("/order/{order_uid}"), &key_b, None).await;
+    assert_eq!(status, 404, "tenant B reads tenant A's order");
+    let (status, _) = get(&service, &format!("/order/{order_uid}"), &key_b, Some(&tenant_a)).await;
+    assert_eq!(status, 403, "foreign tenant in X-Tenant-ID");
+    let (status, body) = get(&service, "/orders?limit=1000", &key_b, None).await;
+    assert_eq!(status, 200, "{body}");
+    assert!(!body.to_string().contains(order_uid), "tenant A's order in tenant B's list");
+
+    // gRPC
+    let order = grpc_get_order(&service, &key_a, None, order_uid).await.unwrap();
+    assert_eq!(order.order_uid, order_uid);
+    let status = grpc_get_order(&service, &key_b, None, order_uid).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let status = grpc_get_order(&service, &key_b, Some(&tenant_a), order_uid).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = grpc_get_order(&service, "wrong-key", None, order_uid).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    // те же order_uid, transaction и chrt_id у другого арендатора - его отдельный ордер, а не 409
+    create_order(&service, &key_b, &order_a).await;
+    let (status, body) = get(&service, &format!("/order/{order_uid}"), &key_b, None).await;
+    assert_eq!(status, 200, "{body}");
+    let (status, body) = post_order(&service, &key_b, &order_a).await;
+    assert_eq!(status, 409, "{body}");
+    // ордер A не изменился
+    let (status, body) = get(&service, &format!("/order/{order_uid}"), &key_a, None).await;
+    assert_eq!(status, 200, "{body}");
+}